@@ -0,0 +1,168 @@
+//! Documents as markdown files with YAML front-matter, for exports and imports
+use crate::{ContentSource, Document, DocumentSpec};
+use anyhow::{Context as _, bail};
+use kube::ResourceExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+/// Annotations that are not exported since they are managed by clients
+const SKIPPED_ANNOTATIONS: &[&str] = &["kubectl.kubernetes.io/last-applied-configuration"];
+
+/// Everything about a Document but its inline content
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct FrontMatter {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    pub title: String,
+    #[serde(default)]
+    pub hide: bool,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_from: Option<ContentSource>,
+}
+
+/// Markdown of a Document; its content preceded by the front-matter
+pub fn to_markdown(doc: &Document) -> anyhow::Result<String> {
+    let mut annotations = doc.annotations().clone();
+    annotations.retain(|k, _| !SKIPPED_ANNOTATIONS.contains(&k.as_str()));
+    let front = FrontMatter {
+        name: doc.name_any(),
+        namespace: doc.namespace(),
+        title: doc.spec.title.clone(),
+        hide: doc.spec.hide,
+        labels: doc.labels().clone(),
+        annotations,
+        content_from: doc.spec.content_from.clone(),
+    };
+    Ok(format!(
+        "---\n{}---\n{}",
+        serde_yaml::to_string(&front)?,
+        doc.spec.content
+    ))
+}
+
+/// The Document described by markdown with front-matter (in the namespace given there, if any)
+pub fn from_markdown(markdown: &str) -> anyhow::Result<Document> {
+    let Some(rest) = markdown.strip_prefix("---\n") else {
+        bail!("missing front-matter; the file has to start with a `---` line");
+    };
+    let (front, content) = match rest.split_once("\n---\n") {
+        Some((front, content)) => (front, content),
+        None => match rest.strip_suffix("\n---") {
+            Some(front) => (front, ""),
+            None => bail!("front-matter is not closed by a `---` line"),
+        },
+    };
+    let front: FrontMatter = serde_yaml::from_str(front).context("invalid front-matter")?;
+    let mut doc = Document::new(&front.name, DocumentSpec {
+        title: front.title,
+        hide: front.hide,
+        content: content.to_string(),
+        content_from: front.content_from,
+    });
+    doc.metadata.namespace = front.namespace;
+    if !front.labels.is_empty() {
+        doc.metadata.labels = Some(front.labels);
+    }
+    if !front.annotations.is_empty() {
+        doc.metadata.annotations = Some(front.annotations);
+    }
+    Ok(doc)
+}
+
+/// A Document with the content of a markdown file without front-matter
+///
+/// The title is taken from its first `# heading` unless given, falling back to the name.
+pub fn from_plain_markdown(name: &str, markdown: &str, title: Option<String>) -> Document {
+    let heading = markdown
+        .lines()
+        .find_map(|line| line.strip_prefix("# "))
+        .map(|heading| heading.trim().to_string());
+    let spec = DocumentSpec {
+        title: title.or(heading).unwrap_or_else(|| name.to_string()),
+        hide: false,
+        content: markdown.to_string(),
+        content_from: None,
+    };
+    Document::new(name, spec)
+}
+
+/// Path of an exported Document; `<namespace>/<name>.md`
+pub fn path(doc: &Document) -> PathBuf {
+    let namespace = doc.namespace().unwrap_or_default();
+    Path::new(&namespace).join(format!("{}.md", doc.name_any()))
+}
+
+/// All markdown files below a directory, sorted
+///
+/// Symlinks are skipped, so that nothing outside the directory is read and link loops end.
+pub fn markdown_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let entries = std::fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        let (path, file_type) = (entry.path(), entry.file_type()?);
+        if file_type.is_dir() {
+            files.extend(markdown_files(&path)?);
+        } else if file_type.is_file() && path.extension().is_some_and(|ext| ext == "md") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{from_markdown, from_plain_markdown, path, to_markdown};
+    use crate::Document;
+    use kube::ResourceExt;
+
+    #[test]
+    fn documents_round_trip_through_markdown() {
+        let mut doc = Document::test().with_content_config_map("notes");
+        doc.spec.title = "Release notes".into();
+        doc.spec.content = "# Changes\n\n---\n\nAll of them\n".into();
+        doc.labels_mut().insert("team".into(), "docs".into());
+        let annotations = doc.annotations_mut();
+        annotations.insert("kube.rs/paused".into(), "true".into());
+        annotations.insert(
+            "kubectl.kubernetes.io/last-applied-configuration".into(),
+            "{}".into(),
+        );
+
+        let markdown = to_markdown(&doc).unwrap();
+        assert!(markdown.starts_with("---\nname: test\nnamespace: default\ntitle: Release notes\n"));
+        assert!(!markdown.contains("last-applied-configuration"));
+        let parsed = from_markdown(&markdown).unwrap();
+        assert_eq!(parsed.namespace().as_deref(), Some("default"));
+        assert_eq!(parsed.spec.content, doc.spec.content);
+        assert_eq!(parsed.labels(), doc.labels());
+        assert_eq!(parsed.annotations().len(), 1);
+        assert!(parsed.is_paused());
+        assert!(parsed.spec.content_from.is_some());
+        assert_eq!(path(&parsed).to_str(), Some("default/test.md"));
+
+        assert!(from_markdown("---\nname: x\ntitle: X\n---").is_ok());
+        assert!(from_markdown("# No front-matter").is_err());
+        assert!(from_markdown("---\nname: x\ntitle: X\nunknown: 1\n---\n").is_err());
+    }
+
+    #[test]
+    fn documents_are_created_from_plain_markdown() {
+        let doc = from_plain_markdown("intro", "Some words\n\n# Introduction\n\nMore words\n", None);
+        assert_eq!(doc.spec.title, "Introduction");
+        assert!(doc.spec.content.starts_with("Some words"));
+        let doc = from_plain_markdown("intro", "no heading", Some("Intro".into()));
+        assert_eq!(doc.spec.title, "Intro");
+        assert_eq!(from_plain_markdown("intro", "", None).spec.title, "intro");
+    }
+}
@@ -0,0 +1,242 @@
+//! Authentication and authorization of the admin HTTP API
+use k8s_openapi::api::{
+    authentication::v1::{TokenReview, TokenReviewSpec},
+    authorization::v1::{ResourceAttributes, SubjectAccessReview, SubjectAccessReviewSpec},
+};
+use kube::{
+    Client,
+    api::{Api, PostParams},
+};
+use serde::{Deserialize, Serialize};
+use tracing::*;
+
+/// How admin requests are authenticated
+#[derive(Clone, Debug, Default)]
+pub enum AuthMode {
+    /// Reject all admin requests
+    #[default]
+    Disabled,
+    /// Compare the bearer token with a shared token (for development)
+    Token(String),
+    /// Review the bearer token with the apiserver, and require access to Documents in all namespaces
+    Kubernetes,
+}
+
+/// Configured authentication of admin requests
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AdminAuth {
+    Disabled,
+    Token,
+    Kubernetes,
+}
+
+/// Admin API settings
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Without an explicit mode, a set token enables token authentication
+    pub auth: Option<AdminAuth>,
+    /// Static token for `token` authentication
+    pub token: Option<String>,
+}
+
+impl AdminConfig {
+    /// The configured authentication; explicit `token` auth fails without a (non-empty) token
+    pub fn mode(&self) -> Result<AuthMode, String> {
+        let token = self.token.clone().filter(|t| !t.is_empty());
+        match (self.auth, token) {
+            (Some(AdminAuth::Kubernetes), _) => Ok(AuthMode::Kubernetes),
+            (Some(AdminAuth::Token), None) => Err("token auth needs a token".into()),
+            (Some(AdminAuth::Token) | None, Some(token)) => Ok(AuthMode::Token(token)),
+            (Some(AdminAuth::Disabled) | None, _) => Ok(AuthMode::Disabled),
+        }
+    }
+}
+
+/// Access needed by a request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// Read Documents and their diagnostics: `list` Documents in all namespaces
+    Read,
+    /// Change the controller: `patch` Documents in all namespaces
+    Admin,
+}
+
+impl Access {
+    pub(crate) fn verb(self) -> &'static str {
+        match self {
+            Access::Read => "list",
+            Access::Admin => "patch",
+        }
+    }
+}
+
+/// Why an admin request was denied
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Denied {
+    /// No valid credentials (401)
+    Unauthenticated(String),
+    /// Valid credentials without access (403)
+    Forbidden(String),
+}
+
+/// Checks bearer tokens of admin requests
+#[derive(Clone)]
+pub struct Authenticator {
+    mode: AuthMode,
+    client: Option<Client>,
+}
+
+impl Authenticator {
+    /// Create an authenticator; the `Kubernetes` mode needs a client for reviews
+    pub fn new(mode: AuthMode, client: Option<Client>) -> Self {
+        Self { mode, client }
+    }
+
+    /// Authenticate a bearer token and authorize it for some access, returning the name of the user
+    ///
+    /// A static token grants all access.
+    pub async fn authorize(&self, bearer: Option<&str>, access: Access) -> Result<String, Denied> {
+        let missing = || Denied::Unauthenticated("missing bearer token".into());
+        match &self.mode {
+            AuthMode::Disabled => Err(Denied::Forbidden("admin endpoints are disabled".into())),
+            AuthMode::Token(expected) => {
+                let given = bearer.ok_or_else(missing)?;
+                // compare in constant time
+                let diff = given
+                    .bytes()
+                    .zip(expected.bytes())
+                    .fold(0, |acc, (a, b)| acc | (a ^ b));
+                if given.len() != expected.len() || diff != 0 {
+                    return Err(Denied::Unauthenticated("invalid token".into()));
+                }
+                Ok("admin".into())
+            }
+            AuthMode::Kubernetes => {
+                let client = self.client.clone().expect("kubernetes auth has a client");
+                self.review(client, bearer.ok_or_else(missing)?, access).await
+            }
+        }
+    }
+
+    async fn review(&self, client: Client, token: &str, access: Access) -> Result<String, Denied> {
+        let unavailable = |e: kube::Error| {
+            warn!("failed to review admin request: {e}");
+            Denied::Forbidden("unable to review request".into())
+        };
+        let review = TokenReview {
+            spec: TokenReviewSpec {
+                token: Some(token.into()),
+                ..TokenReviewSpec::default()
+            },
+            ..TokenReview::default()
+        };
+        let pp = PostParams::default();
+        let reviewed = Api::<TokenReview>::all(client.clone())
+            .create(&pp, &review)
+            .await
+            .map_err(unavailable)?;
+        let status = reviewed.status.unwrap_or_default();
+        let user = status.user.filter(|_| status.authenticated == Some(true));
+        let Some(user) = user else {
+            return Err(Denied::Unauthenticated(
+                status.error.unwrap_or_else(|| "invalid token".into()),
+            ));
+        };
+        let username = user.username.clone().unwrap_or_default();
+        let review = SubjectAccessReview {
+            spec: SubjectAccessReviewSpec {
+                user: user.username,
+                groups: user.groups,
+                uid: user.uid,
+                extra: user.extra,
+                resource_attributes: Some(ResourceAttributes {
+                    group: Some("kube.rs".into()),
+                    resource: Some("documents".into()),
+                    verb: Some(access.verb().into()),
+                    ..ResourceAttributes::default()
+                }),
+                ..SubjectAccessReviewSpec::default()
+            },
+            ..SubjectAccessReview::default()
+        };
+        let reviewed = Api::<SubjectAccessReview>::all(client)
+            .create(&pp, &review)
+            .await
+            .map_err(unavailable)?;
+        match reviewed.status {
+            Some(s) if s.allowed => Ok(username),
+            _ => Err(Denied::Forbidden(format!(
+                "{username} cannot {} documents.kube.rs in all namespaces",
+                access.verb()
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Access, AdminAuth, AdminConfig, AuthMode, Authenticator, Denied};
+    use crate::fixtures::{Scenario, mock_client, timeout_after_1s};
+
+    #[tokio::test]
+    async fn reviewed_tokens_with_access_are_authorized() {
+        let (client, fakeserver) = mock_client();
+        let mocksrv = fakeserver.run(Scenario::AccessReview(Some("jane".into()), Access::Admin, true));
+        let auth = Authenticator::new(AuthMode::Kubernetes, Some(client));
+        assert_eq!(
+            auth.authorize(Some("t0ken"), Access::Admin).await,
+            Ok("jane".into())
+        );
+        timeout_after_1s(mocksrv).await;
+    }
+
+    #[tokio::test]
+    async fn reviewed_tokens_without_access_are_forbidden() {
+        let (client, fakeserver) = mock_client();
+        let mocksrv = fakeserver.run(Scenario::AccessReview(Some("joe".into()), Access::Read, false));
+        let auth = Authenticator::new(AuthMode::Kubernetes, Some(client));
+        let res = auth.authorize(Some("t0ken"), Access::Read).await;
+        assert!(matches!(res, Err(Denied::Forbidden(_))));
+        timeout_after_1s(mocksrv).await;
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_tokens_are_not_authorized() {
+        let (client, fakeserver) = mock_client();
+        let mocksrv = fakeserver.run(Scenario::AccessReview(None, Access::Read, false));
+        let auth = Authenticator::new(AuthMode::Kubernetes, Some(client));
+        let res = auth.authorize(Some("bad"), Access::Read).await;
+        assert!(matches!(res, Err(Denied::Unauthenticated(_))));
+        timeout_after_1s(mocksrv).await;
+        let missing = auth.authorize(None, Access::Read).await;
+        assert!(matches!(missing, Err(Denied::Unauthenticated(_))));
+    }
+
+    #[test]
+    fn explicit_token_auth_needs_a_token() {
+        let config = |auth, token: Option<&str>| AdminConfig {
+            auth,
+            token: token.map(String::from),
+        };
+        let empty = config(Some(AdminAuth::Token), Some(""));
+        assert!(empty.mode().is_err(), "never falls back to disabled");
+        assert!(config(Some(AdminAuth::Token), None).mode().is_err());
+        let set = config(None, Some("s3cr3t")).mode();
+        assert!(matches!(set, Ok(AuthMode::Token(t)) if t == "s3cr3t"));
+        assert!(matches!(config(None, Some("")).mode(), Ok(AuthMode::Disabled)));
+    }
+
+    #[tokio::test]
+    async fn static_tokens_are_compared() {
+        let auth = Authenticator::new(AuthMode::Token("s3cr3t".into()), None);
+        assert!(auth.authorize(Some("s3cr3t"), Access::Admin).await.is_ok());
+        assert!(auth.authorize(Some("s3cr3"), Access::Read).await.is_err());
+        let disabled = Authenticator::new(AuthMode::Disabled, None);
+        assert!(matches!(
+            disabled.authorize(Some("s3cr3t"), Access::Read).await,
+            Err(Denied::Forbidden(_))
+        ));
+    }
+}
@@ -0,0 +1,247 @@
+//! Bounded cache of full Documents for the metadata-only watch mode
+use crate::{Document, Error, Result, metrics::CacheMetrics, search};
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::{
+    Client, ResourceExt,
+    api::{Api, PartialObjectMeta},
+    runtime::reflector::ObjectRef,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+/// How the controller watches Documents
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchMode {
+    /// Watch and keep full Documents in memory
+    #[default]
+    Full,
+    /// Watch metadata only and fetch Documents on demand through a bounded `DocumentCache`
+    Metadata,
+}
+
+/// Watch settings
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct WatchConfig {
+    pub mode: WatchMode,
+    /// Max number of Documents kept by the cache in metadata mode
+    pub cache_capacity: usize,
+    /// Max number of Documents kept by the search index
+    pub search_capacity: usize,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            mode: WatchMode::Full,
+            cache_capacity: 1000,
+            search_capacity: search::DEFAULT_CAPACITY,
+        }
+    }
+}
+
+struct Entry {
+    doc: Arc<Document>,
+    bytes: usize,
+    /// Tick of the last use, the key of the entry in `Inner::order`
+    used: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<ObjectRef<Document>, Entry>,
+    /// Least recently used first
+    order: BTreeMap<u64, ObjectRef<Document>>,
+    ticks: u64,
+    bytes: usize,
+    /// ConfigMap each Document takes its content from, kept after eviction so content changes still reach it
+    config_maps: HashMap<ObjectRef<Document>, String>,
+}
+
+impl Inner {
+    fn touch(&mut self, oref: &ObjectRef<Document>) {
+        let Some(entry) = self.entries.get_mut(oref) else {
+            return;
+        };
+        self.order.remove(&entry.used);
+        self.ticks += 1;
+        entry.used = self.ticks;
+        self.order.insert(self.ticks, oref.clone());
+    }
+
+    fn evict(&mut self, oref: &ObjectRef<Document>) {
+        if let Some(entry) = self.entries.remove(oref) {
+            self.bytes -= entry.bytes;
+            self.order.remove(&entry.used);
+        }
+    }
+}
+
+/// Least recently used cache of Documents, validated against the watched resourceVersion
+#[derive(Clone)]
+pub struct DocumentCache {
+    capacity: usize,
+    inner: Arc<Mutex<Inner>>,
+    metrics: CacheMetrics,
+}
+
+impl DocumentCache {
+    pub fn new(capacity: usize, metrics: CacheMetrics) -> Self {
+        Self {
+            capacity,
+            inner: Arc::default(),
+            metrics,
+        }
+    }
+
+    /// Get the full Document for watched metadata, fetching it when the cached copy is missing or stale
+    ///
+    /// Returns `None` if the Document no longer exists.
+    pub async fn get(
+        &self,
+        client: Client,
+        meta: &PartialObjectMeta<Document>,
+    ) -> Result<Option<Arc<Document>>> {
+        let oref = ObjectRef::new(&meta.name_any()).within(&meta.namespace().unwrap());
+        if let Some(doc) = self.cached(&oref)
+            && doc.resource_version() == meta.resource_version()
+        {
+            self.metrics.hits.inc();
+            return Ok(Some(doc));
+        }
+        self.metrics.misses.inc();
+        let docs: Api<Document> = Api::namespaced(client, &meta.namespace().unwrap());
+        match docs.get_opt(&meta.name_any()).await.map_err(Error::KubeError)? {
+            Some(doc) => Ok(Some(self.insert(doc))),
+            None => {
+                self.remove(&oref);
+                Ok(None)
+            }
+        }
+    }
+
+    /// The cached copy of a Document (if any)
+    pub fn cached(&self, oref: &ObjectRef<Document>) -> Option<Arc<Document>> {
+        let mut inner = self.inner.lock().unwrap();
+        let doc = inner.entries.get(oref).map(|e| e.doc.clone())?;
+        inner.touch(oref);
+        Some(doc)
+    }
+
+    /// All cached Documents
+    pub fn documents(&self) -> Vec<Arc<Document>> {
+        let inner = self.inner.lock().unwrap();
+        inner.entries.values().map(|e| e.doc.clone()).collect()
+    }
+
+    /// Documents referencing a ConfigMap for their content, including ones evicted from the cache
+    ///
+    /// Reconciles of evicted Documents fetch them again through `get`.
+    pub fn referencing(&self, cm: &ConfigMap) -> Vec<ObjectRef<Document>> {
+        let (name, ns) = (cm.name_any(), cm.namespace());
+        let inner = self.inner.lock().unwrap();
+        inner
+            .config_maps
+            .iter()
+            .filter(|(oref, cm)| oref.namespace == ns && **cm == name)
+            .map(|(oref, _)| oref.clone())
+            .collect()
+    }
+
+    pub fn insert(&self, doc: Document) -> Arc<Document> {
+        let oref = ObjectRef::from_obj(&doc);
+        let bytes = serde_json::to_vec(&doc).map(|v| v.len()).unwrap_or_default();
+        let config_map = doc.content_config_map().map(String::from);
+        let doc = Arc::new(doc);
+        let mut inner = self.inner.lock().unwrap();
+        inner.evict(&oref);
+        inner.entries.insert(oref.clone(), Entry {
+            doc: doc.clone(),
+            bytes,
+            used: 0,
+        });
+        inner.bytes += bytes;
+        inner.touch(&oref);
+        match config_map {
+            Some(cm) => inner.config_maps.insert(oref, cm),
+            None => inner.config_maps.remove(&oref),
+        };
+        while inner.entries.len() > self.capacity {
+            let Some(lru) = inner.order.values().next().cloned() else {
+                break;
+            };
+            inner.evict(&lru);
+        }
+        self.update_metrics(&inner);
+        doc
+    }
+
+    /// Forget a deleted Document
+    pub fn remove(&self, oref: &ObjectRef<Document>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.evict(oref);
+        inner.config_maps.remove(oref);
+        self.update_metrics(&inner);
+    }
+
+    fn update_metrics(&self, inner: &Inner) {
+        self.metrics.objects.set(inner.entries.len() as i64);
+        self.metrics.bytes.set(inner.bytes as i64);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DocumentCache;
+    use crate::{Document, Metrics};
+    use k8s_openapi::api::core::v1::ConfigMap;
+    use kube::{Resource, runtime::reflector::ObjectRef};
+
+    fn named(name: &str) -> Document {
+        let mut doc = Document::test();
+        doc.meta_mut().name = Some(name.into());
+        doc
+    }
+
+    #[test]
+    fn cache_evicts_least_recently_used_documents() {
+        let metrics = Metrics::default();
+        let cache = DocumentCache::new(2, metrics.cache.clone());
+        cache.insert(named("a"));
+        cache.insert(named("b"));
+        // use a so that b is the least recently used
+        assert!(cache.cached(&ObjectRef::from_obj(&named("a"))).is_some());
+        cache.insert(named("c"));
+        assert!(cache.cached(&ObjectRef::from_obj(&named("b"))).is_none());
+        assert_eq!(cache.documents().len(), 2);
+        assert_eq!(metrics.cache.objects.get(), 2);
+        assert!(metrics.cache.bytes.get() > 0);
+
+        cache.remove(&ObjectRef::from_obj(&named("a")));
+        assert_eq!(metrics.cache.objects.get(), 1);
+    }
+
+    #[test]
+    fn evicted_documents_still_follow_their_config_map() {
+        let cache = DocumentCache::new(1, Metrics::default().cache);
+        cache.insert(named("a").with_content_config_map("shared"));
+        cache.insert(named("b").with_content_config_map("shared"));
+        cache.insert(named("c"));
+        assert_eq!(cache.documents().len(), 1);
+
+        let mut cm = ConfigMap::default();
+        cm.meta_mut().name = Some("shared".into());
+        cm.meta_mut().namespace = Some("default".into());
+        let mut referencing = cache.referencing(&cm);
+        referencing.sort_by(|a, b| a.name.cmp(&b.name));
+        let expected = ["a", "b"].map(|n| ObjectRef::from_obj(&named(n)));
+        assert_eq!(referencing, expected);
+
+        cache.remove(&ObjectRef::from_obj(&named("a")));
+        assert_eq!(cache.referencing(&cm), [ObjectRef::from_obj(&named("b"))]);
+    }
+}
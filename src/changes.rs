@@ -0,0 +1,466 @@
+//! Feed of Document changes seen by the watch, for Server-Sent Event streams
+use futures::{StreamExt, stream::BoxStream};
+use kube::{Resource, ResourceExt, runtime::watcher};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::sync::broadcast;
+
+/// Changes kept for clients resuming from a sequence number
+const HISTORY: usize = 1000;
+/// Changes buffered per subscriber before it lags
+const BUFFER: usize = 256;
+/// Comment sent to idle streams so proxies keep them open
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// What happened to a Document
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ChangeKind {
+    Created,
+    /// The spec changed (including unhiding)
+    Updated,
+    Hidden,
+    Deleted,
+}
+
+impl ChangeKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ChangeKind::Created => "created",
+            ChangeKind::Updated => "updated",
+            ChangeKind::Hidden => "hidden",
+            ChangeKind::Deleted => "deleted",
+        }
+    }
+}
+
+/// A change to a Document, numbered in the order it was seen
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Change {
+    /// `<epoch>-<seq>`, to resume from
+    pub id: String,
+    #[serde(skip)]
+    pub seq: u64,
+    pub kind: ChangeKind,
+    /// Cluster of the Document when several clusters are reconciled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cluster: Option<String>,
+    pub namespace: String,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resource_version: Option<String>,
+    /// Whether the Document is hidden (unknown when only metadata is watched)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hidden: Option<bool>,
+}
+
+impl Change {
+    /// The change as a Server-Sent Event
+    pub fn to_event(&self) -> String {
+        let data = serde_json::to_string(self).unwrap_or_default();
+        format!("id: {}\nevent: {}\ndata: {data}\n\n", self.id, self.kind.as_str())
+    }
+}
+
+/// A client asked to resume from a change that is no longer (or not yet) known
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Expired {
+    #[error(
+        "changes after {since} are not available (oldest is {oldest}, latest is {latest}); list Documents again"
+    )]
+    Evicted {
+        since: String,
+        oldest: String,
+        latest: String,
+    },
+    /// Ids of another replica, or from before a restart, number other changes
+    #[error("{since} is not an id of this feed (epoch {epoch}); list Documents again")]
+    OtherEpoch { since: String, epoch: String },
+}
+
+/// What the feed knows about a Document
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Known {
+    generation: Option<i64>,
+    hidden: Option<bool>,
+}
+
+/// Cluster, namespace and name of a Document
+type Key = (Option<String>, String, String);
+
+#[derive(Default)]
+struct Inner {
+    seq: u64,
+    history: VecDeque<Change>,
+    known: HashMap<Key, Known>,
+    /// Documents seen during a relist per cluster, to find the ones deleted meanwhile
+    relisted: HashMap<Option<String>, HashSet<Key>>,
+}
+
+/// Broadcast of the changes to Documents, with a bounded history to resume from
+///
+/// Sequence numbers restart with every process, so change ids are prefixed with an epoch picked at random when
+/// the feed is created. Ids of other epochs (another replica, or before a restart) are not resumed from.
+#[derive(Clone)]
+pub struct ChangeFeed {
+    inner: Arc<Mutex<Inner>>,
+    sender: broadcast::Sender<Change>,
+    epoch: Arc<str>,
+    /// Cluster of the observed watch events
+    cluster: Option<String>,
+}
+
+impl Default for ChangeFeed {
+    fn default() -> Self {
+        let epoch = format!(
+            "{:016x}",
+            getrandom::u64().expect("the OS provides random numbers")
+        );
+        Self {
+            inner: Arc::default(),
+            sender: broadcast::channel(BUFFER).0,
+            epoch: epoch.into(),
+            cluster: None,
+        }
+    }
+}
+
+impl ChangeFeed {
+    /// The same feed, observing the watch events of a cluster
+    pub fn for_cluster(&self, name: &str) -> Self {
+        Self {
+            cluster: Some(name.into()),
+            ..self.clone()
+        }
+    }
+
+    fn key<K: Resource>(&self, obj: &K) -> Key {
+        (
+            self.cluster.clone(),
+            obj.namespace().unwrap_or_default(),
+            obj.name_any(),
+        )
+    }
+
+    /// Record a watch event, publishing the changes it implies
+    ///
+    /// Only changes to the spec (tracked by the generation) are published, not status or metadata writes.
+    /// `hidden` tells whether an object is hidden, if that is known. Metadata-only watches cannot tell, so
+    /// hiding is published as an update there, without `hidden`.
+    pub fn observe<K: Resource>(&self, event: &watcher::Event<K>, hidden: impl Fn(&K) -> Option<bool>) {
+        let mut inner = self.inner.lock().unwrap();
+        match event {
+            watcher::Event::Init => {
+                inner.relisted.insert(self.cluster.clone(), HashSet::new());
+            }
+            watcher::Event::InitApply(obj) | watcher::Event::Apply(obj) => {
+                let key = self.key(obj);
+                if let Some(relisted) = inner.relisted.get_mut(&self.cluster) {
+                    relisted.insert(key.clone());
+                }
+                let known = Known {
+                    generation: obj.meta().generation,
+                    hidden: hidden(obj),
+                };
+                let kind = match inner.known.insert(key, known) {
+                    None => ChangeKind::Created,
+                    Some(old) if old == known => return,
+                    Some(old) if known.hidden == Some(true) && old.hidden != Some(true) => ChangeKind::Hidden,
+                    Some(_) => ChangeKind::Updated,
+                };
+                self.publish(&mut inner, kind, obj, known.hidden);
+            }
+            watcher::Event::Delete(obj) => {
+                inner.known.remove(&self.key(obj));
+                self.publish(&mut inner, ChangeKind::Deleted, obj, hidden(obj));
+            }
+            watcher::Event::InitDone => {
+                let Some(relisted) = inner.relisted.remove(&self.cluster) else {
+                    return;
+                };
+                let mut gone: Vec<_> = inner
+                    .known
+                    .keys()
+                    .filter(|key| key.0 == self.cluster && !relisted.contains(*key))
+                    .cloned()
+                    .collect();
+                gone.sort();
+                for key in gone {
+                    let known = inner.known.remove(&key);
+                    let (cluster, namespace, name) = key;
+                    self.push(&mut inner, Change {
+                        id: String::new(),
+                        seq: 0,
+                        kind: ChangeKind::Deleted,
+                        cluster,
+                        namespace,
+                        name,
+                        resource_version: None,
+                        hidden: known.and_then(|k| k.hidden),
+                    });
+                }
+            }
+        }
+    }
+
+    fn publish<K: Resource>(&self, inner: &mut Inner, kind: ChangeKind, obj: &K, hidden: Option<bool>) {
+        self.push(inner, Change {
+            id: String::new(),
+            seq: 0,
+            kind,
+            cluster: self.cluster.clone(),
+            namespace: obj.namespace().unwrap_or_default(),
+            name: obj.name_any(),
+            resource_version: obj.resource_version(),
+            hidden,
+        });
+    }
+
+    fn push(&self, inner: &mut Inner, mut change: Change) {
+        inner.seq += 1;
+        change.seq = inner.seq;
+        change.id = self.id(inner.seq);
+        if inner.history.len() == HISTORY {
+            inner.history.pop_front();
+        }
+        inner.history.push_back(change.clone());
+        // without subscribers there is nobody to tell
+        let _ = self.sender.send(change);
+    }
+
+    fn id(&self, seq: u64) -> String {
+        format!("{}-{seq}", self.epoch)
+    }
+
+    /// Id of the latest change
+    pub fn latest(&self) -> String {
+        self.id(self.inner.lock().unwrap().seq)
+    }
+
+    /// Server-Sent Events for the changes in the namespaces (all when empty)
+    ///
+    /// Starts with the changes after the id `since` when given, and ends with a `lagged` event when the client
+    /// falls too far behind; it can then resume from the last `id` it received.
+    pub fn stream(
+        &self,
+        namespaces: Vec<String>,
+        since: Option<&str>,
+    ) -> Result<BoxStream<'static, String>, Expired> {
+        let since = match since {
+            None => None,
+            Some(id) => {
+                let seq = id
+                    .strip_prefix(&*self.epoch)
+                    .and_then(|rest| rest.strip_prefix('-'))
+                    .and_then(|seq| seq.parse::<u64>().ok());
+                let Some(seq) = seq else {
+                    return Err(Expired::OtherEpoch {
+                        since: id.into(),
+                        epoch: self.epoch.to_string(),
+                    });
+                };
+                Some((id, seq))
+            }
+        };
+        // subscribe while holding the lock, so no change is missed or sent twice
+        let inner = self.inner.lock().unwrap();
+        let backlog: Vec<_> = match since {
+            None => vec![],
+            Some((id, since)) => {
+                let oldest = inner.history.front().map_or(inner.seq + 1, |c| c.seq);
+                if since > inner.seq || since + 1 < oldest {
+                    return Err(Expired::Evicted {
+                        since: id.into(),
+                        oldest: self.id(oldest),
+                        latest: self.id(inner.seq),
+                    });
+                }
+                inner.history.iter().filter(|c| c.seq > since).cloned().collect()
+            }
+        };
+        let receiver = self.sender.subscribe();
+        drop(inner);
+
+        let matches = move |c: &Change| namespaces.is_empty() || namespaces.contains(&c.namespace);
+        let backlog: Vec<_> = backlog
+            .into_iter()
+            .filter(&matches)
+            .map(|c| c.to_event())
+            .collect();
+        let live = futures::stream::unfold(Some(receiver), move |receiver| {
+            let matches = matches.clone();
+            async move {
+                let mut receiver = receiver?;
+                loop {
+                    match tokio::time::timeout(KEEP_ALIVE, receiver.recv()).await {
+                        Err(_) => return Some((": keep-alive\n\n".to_string(), Some(receiver))),
+                        Ok(Ok(change)) if matches(&change) => {
+                            return Some((change.to_event(), Some(receiver)));
+                        }
+                        Ok(Ok(_)) => continue,
+                        Ok(Err(broadcast::error::RecvError::Lagged(skipped))) => {
+                            let event = format!("event: lagged\ndata: {{\"skipped\":{skipped}}}\n\n");
+                            return Some((event, None));
+                        }
+                        Ok(Err(broadcast::error::RecvError::Closed)) => return None,
+                    }
+                }
+            }
+        });
+        Ok(futures::stream::iter(backlog).chain(live).boxed())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ChangeFeed, ChangeKind, Expired};
+    use crate::Document;
+    use futures::StreamExt;
+    use kube::{ResourceExt, core::PartialObjectMetaExt, runtime::watcher::Event};
+
+    fn doc(namespace: &str, name: &str, generation: i64) -> Document {
+        let mut doc = Document::test();
+        doc.metadata.name = Some(name.into());
+        doc.metadata.namespace = Some(namespace.into());
+        doc.metadata.generation = Some(generation);
+        doc
+    }
+
+    fn observe(feed: &ChangeFeed, event: Event<Document>) {
+        feed.observe(&event, |d| Some(d.spec.hide));
+    }
+
+    fn kinds(feed: &ChangeFeed) -> Vec<(ChangeKind, String)> {
+        let inner = feed.inner.lock().unwrap();
+        inner.history.iter().map(|c| (c.kind, c.name.clone())).collect()
+    }
+
+    #[test]
+    fn watch_events_become_changes() {
+        let feed = ChangeFeed::default();
+        observe(&feed, Event::Init);
+        observe(&feed, Event::InitApply(doc("default", "a", 1)));
+        observe(&feed, Event::InitApply(doc("default", "b", 1)));
+        observe(&feed, Event::InitDone);
+        // status writes keep the generation
+        observe(&feed, Event::Apply(doc("default", "a", 1)));
+        observe(&feed, Event::Apply(doc("default", "a", 2)));
+        let mut hidden = doc("default", "a", 3);
+        hidden.spec.hide = true;
+        observe(&feed, Event::Apply(hidden));
+        observe(&feed, Event::Delete(doc("default", "a", 3)));
+        // a relist after the watch was down finds b deleted
+        observe(&feed, Event::Init);
+        observe(&feed, Event::InitDone);
+
+        use ChangeKind::*;
+        let expected = [
+            (Created, "a"),
+            (Created, "b"),
+            (Updated, "a"),
+            (Hidden, "a"),
+            (Deleted, "a"),
+            (Deleted, "b"),
+        ];
+        let expected: Vec<_> = expected.into_iter().map(|(k, n)| (k, n.to_string())).collect();
+        assert_eq!(kinds(&feed), expected);
+        assert_eq!(feed.latest(), format!("{}-6", feed.epoch));
+    }
+
+    #[test]
+    fn metadata_watches_publish_hiding_as_updates() {
+        let feed = ChangeFeed::default();
+        let meta = |generation| {
+            doc("default", "a", generation)
+                .metadata
+                .into_response_partial::<Document>()
+        };
+        feed.observe(&Event::Apply(meta(1)), |_| None);
+        // spec.hide is not part of the metadata
+        feed.observe(&Event::Apply(meta(2)), |_| None);
+        assert_eq!(kinds(&feed), [
+            (ChangeKind::Created, "a".to_string()),
+            (ChangeKind::Updated, "a".to_string())
+        ]);
+        let update = feed.inner.lock().unwrap().history[1].clone();
+        assert_eq!(update.hidden, None);
+        assert!(!update.to_event().contains("hidden"));
+    }
+
+    #[test]
+    fn relists_only_delete_documents_of_their_cluster() {
+        let feed = ChangeFeed::default();
+        let (east, west) = (feed.for_cluster("east"), feed.for_cluster("west"));
+        east.observe(&Event::Apply(doc("default", "a", 1)), |_| None);
+        west.observe(&Event::Apply(doc("default", "a", 1)), |_| None);
+        west.observe(&Event::<Document>::Init, |_| None);
+        west.observe(&Event::<Document>::InitDone, |_| None);
+
+        let inner = feed.inner.lock().unwrap();
+        let changes: Vec<_> = inner
+            .history
+            .iter()
+            .map(|c| (c.kind, c.cluster.as_deref()))
+            .collect();
+        assert_eq!(changes, [
+            (ChangeKind::Created, Some("east")),
+            (ChangeKind::Created, Some("west")),
+            (ChangeKind::Deleted, Some("west")),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn streams_resume_and_filter_namespaces() {
+        let feed = ChangeFeed::default();
+        observe(&feed, Event::Apply(doc("default", "a", 1)));
+        observe(&feed, Event::Apply(doc("other", "b", 1)));
+        observe(&feed, Event::Apply(doc("default", "c", 1)));
+
+        let id = |seq: u64| format!("{}-{seq}", feed.epoch);
+        let mut events = feed.stream(vec!["default".into()], Some(&id(1))).unwrap();
+        let first = events.next().await.unwrap();
+        assert!(first.starts_with(&format!(
+            "id: {}\nevent: created\ndata: {{\"id\":\"{}\",",
+            id(3),
+            id(3)
+        )));
+        assert!(first.contains("\"name\":\"c\""));
+        let deleted = doc("default", "c", 1);
+        observe(&feed, Event::Apply(doc("other", "d", 1)));
+        observe(&feed, Event::Delete(deleted.clone()));
+        let next = events.next().await.unwrap();
+        assert!(next.starts_with(&format!("id: {}\nevent: deleted\n", id(5))));
+        assert!(next.contains(&format!("\"name\":\"{}\"", deleted.name_any())));
+
+        assert!(feed.stream(vec![], Some(&id(0))).is_ok());
+        let err = feed.stream(vec![], Some(&id(9))).err().unwrap();
+        assert_eq!(err, Expired::Evicted {
+            since: id(9),
+            oldest: id(1),
+            latest: id(5)
+        });
+    }
+
+    #[test]
+    fn ids_of_other_epochs_are_not_resumed_from() {
+        let feed = ChangeFeed::default();
+        observe(&feed, Event::Apply(doc("default", "a", 1)));
+        // another replica, or this one before a restart, numbers its changes from 1 as well
+        let other = ChangeFeed::default();
+        assert_ne!(feed.epoch, other.epoch);
+        for since in [other.latest(), "1".into(), format!("{}-x", feed.epoch)] {
+            let err = feed.stream(vec![], Some(&since)).err().unwrap();
+            assert_eq!(err, Expired::OtherEpoch {
+                since,
+                epoch: feed.epoch.to_string()
+            });
+        }
+        assert!(feed.stream(vec![], Some(&feed.latest())).is_ok());
+    }
+}
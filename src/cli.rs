@@ -0,0 +1,682 @@
+//! Commands of the `docctl` command line tool
+use crate::{
+    Document, archive, crds,
+    source::SOURCE_LABEL,
+    sweep::{FinalizerConfig, Sweeper},
+};
+use anyhow::{Context as _, bail};
+use clap::{Parser, Subcommand};
+use jiff::Timestamp;
+use k8s_openapi::api::events::v1::Event;
+use kube::{
+    Client, Config, ResourceExt,
+    api::{Api, ListParams, Patch, PatchParams, PostParams},
+    config::KubeConfigOptions,
+};
+use serde_json::json;
+use std::{
+    fmt::Write as _,
+    path::{Path, PathBuf},
+};
+
+/// Field manager of changes made by docctl
+pub const DOCCTL_FIELD_MANAGER: &str = "docctl";
+
+/// Number of events shown by `describe`
+const RECENT_EVENTS: usize = 10;
+
+/// Manage Documents from the command line
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct Cli {
+    /// Namespace of the Documents (defaults to the namespace of the kubeconfig context)
+    #[arg(short, long, global = true)]
+    pub namespace: Option<String>,
+    /// Kubeconfig context to use (defaults to the current context)
+    #[arg(long, global = true)]
+    pub context: Option<String>,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Create a Document from a markdown file
+    ///
+    /// The title is taken from the first `# heading` of the file unless given.
+    Create {
+        file: PathBuf,
+        /// Name of the Document (defaults to the file name)
+        #[arg(long)]
+        name: Option<String>,
+        #[arg(long)]
+        title: Option<String>,
+        /// Create the Document hidden
+        #[arg(long)]
+        hide: bool,
+    },
+    /// List Documents with their hidden status
+    List {
+        /// List Documents in all namespaces
+        #[arg(short = 'A', long)]
+        all_namespaces: bool,
+    },
+    /// Hide a Document
+    Hide { name: String },
+    /// Stop hiding a Document
+    Unhide { name: String },
+    /// Print the title and content of a Document (following `contentFrom`)
+    Show { name: String },
+    /// Show the status and recent events of a Document
+    Describe { name: String },
+    /// Export Documents to `<dir>/<namespace>/<name>.md` files with YAML front-matter
+    Export {
+        dir: PathBuf,
+        /// Export Documents in all namespaces
+        #[arg(short = 'A', long)]
+        all_namespaces: bool,
+    },
+    /// Import exported Documents with server-side apply
+    ///
+    /// Documents without a namespace in their front-matter are imported into the selected namespace.
+    Import {
+        dir: PathBuf,
+        /// Show the changes an import would make without applying them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Remove the finalizer of the controller from Documents, before uninstalling it
+    ///
+    /// Scale the controller down to zero replicas first, as running replicas add the finalizer back.
+    UninstallFinalizers {
+        /// Remove the finalizer in all namespaces
+        #[arg(short = 'A', long)]
+        all_namespaces: bool,
+        /// Comma separated former names of the finalizer to remove as well
+        #[arg(long = "legacy-finalizers", value_delimiter = ',', value_name = "NAMES")]
+        legacy: Vec<String>,
+        /// Show the Documents that would lose the finalizer without changing them
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+impl Cli {
+    /// Run the command against the cluster of the local kubeconfig
+    pub async fn run(self) -> anyhow::Result<()> {
+        let options = KubeConfigOptions {
+            context: self.context.clone(),
+            ..Default::default()
+        };
+        let config = Config::from_kubeconfig(&options)
+            .await
+            .context("failed to load kubeconfig")?;
+        let client = Client::try_from(config)?;
+        let namespace = self
+            .namespace
+            .clone()
+            .unwrap_or_else(|| client.default_namespace().to_string());
+        let output = Docctl::new(client, namespace).run(self.command).await?;
+        print!("{output}");
+        Ok(())
+    }
+}
+
+/// Documents of a namespace, managed through a client
+pub struct Docctl {
+    client: Client,
+    namespace: String,
+}
+
+impl Docctl {
+    pub fn new(client: Client, namespace: String) -> Self {
+        Self { client, namespace }
+    }
+
+    fn api(&self) -> Api<Document> {
+        Api::namespaced(self.client.clone(), &self.namespace)
+    }
+
+    /// Run a command, returning its output
+    pub async fn run(&self, command: Command) -> anyhow::Result<String> {
+        match command {
+            Command::Create {
+                file,
+                name,
+                title,
+                hide,
+            } => {
+                let markdown = std::fs::read_to_string(&file)
+                    .with_context(|| format!("failed to read {}", file.display()))?;
+                let name = match name {
+                    Some(name) => name,
+                    None => name_from_path(&file)?,
+                };
+                let mut doc = archive::from_plain_markdown(&name, &markdown, title);
+                doc.spec.hide = hide;
+                let doc = self.create(doc).await?;
+                Ok(format!("document/{} created\n", doc.name_any()))
+            }
+            Command::List { all_namespaces } => {
+                let docs = self.list(all_namespaces).await?;
+                Ok(format_list(&docs, all_namespaces))
+            }
+            Command::Hide { name } => {
+                self.set_hidden(&name, true).await?;
+                Ok(format!("document/{name} hidden\n"))
+            }
+            Command::Unhide { name } => {
+                self.set_hidden(&name, false).await?;
+                Ok(format!("document/{name} unhidden\n"))
+            }
+            Command::Show { name } => self.show(&name).await,
+            Command::Describe { name } => self.describe(&name).await,
+            Command::Export { dir, all_namespaces } => self.export(&dir, all_namespaces).await,
+            Command::Import { dir, dry_run } => self.import(&dir, dry_run).await,
+            Command::UninstallFinalizers {
+                all_namespaces,
+                legacy,
+                dry_run,
+            } => self.uninstall_finalizers(all_namespaces, legacy, dry_run).await,
+        }
+    }
+
+    pub async fn create(&self, doc: Document) -> anyhow::Result<Document> {
+        let pp = PostParams {
+            field_manager: Some(DOCCTL_FIELD_MANAGER.into()),
+            ..Default::default()
+        };
+        let name = doc.name_any();
+        match self.api().create(&pp, &doc).await {
+            Err(kube::Error::Api(s)) if s.is_already_exists() => {
+                bail!("document/{name} already exists in {}", self.namespace)
+            }
+            res => Ok(res?),
+        }
+    }
+
+    pub async fn list(&self, all_namespaces: bool) -> anyhow::Result<Vec<Document>> {
+        let api = match all_namespaces {
+            true => Api::all(self.client.clone()),
+            false => self.api(),
+        };
+        Ok(api.list(&ListParams::default()).await?.items)
+    }
+
+    pub async fn set_hidden(&self, name: &str, hide: bool) -> anyhow::Result<Document> {
+        let patch = Patch::Merge(json!({ "spec": { "hide": hide } }));
+        let pp = PatchParams {
+            field_manager: Some(DOCCTL_FIELD_MANAGER.into()),
+            ..Default::default()
+        };
+        Ok(self.api().patch(name, &pp, &patch).await?)
+    }
+
+    pub async fn show(&self, name: &str) -> anyhow::Result<String> {
+        let doc = self.api().get(name).await?;
+        let resolved = doc.resolve_content(self.client.clone(), None).await?;
+        let mut content = format!("# {}\n\n{}", doc.spec.title, resolved.content);
+        if !content.ends_with('\n') {
+            content.push('\n');
+        }
+        Ok(content)
+    }
+
+    pub async fn describe(&self, name: &str) -> anyhow::Result<String> {
+        let doc = self.api().get(name).await?;
+        let events: Api<Event> = Api::namespaced(self.client.clone(), &self.namespace);
+        let selector = format!("regarding.kind=Document,regarding.name={name}");
+        let mut events = events.list(&ListParams::default().fields(&selector)).await?.items;
+        events.sort_by_key(event_time);
+        let recent = events.len().saturating_sub(RECENT_EVENTS);
+        Ok(format_describe(&doc, &events[recent..], Timestamp::now()))
+    }
+
+    pub async fn export(&self, dir: &Path, all_namespaces: bool) -> anyhow::Result<String> {
+        let mut out = String::new();
+        for doc in self.list(all_namespaces).await? {
+            // synced Documents are recreated by their DocumentSource, which owns them
+            if let Some(source) = doc.labels().get(SOURCE_LABEL) {
+                writeln!(
+                    out,
+                    "document/{} skipped, synced from documentsource/{source}",
+                    doc.name_any()
+                )?;
+                continue;
+            }
+            let path = dir.join(archive::path(&doc));
+            let parent = path.parent().unwrap_or(dir);
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+            std::fs::write(&path, archive::to_markdown(&doc)?)
+                .with_context(|| format!("failed to write {}", path.display()))?;
+            writeln!(out, "document/{} exported to {}", doc.name_any(), path.display())?;
+        }
+        Ok(out)
+    }
+
+    pub async fn import(&self, dir: &Path, dry_run: bool) -> anyhow::Result<String> {
+        let mut out = String::new();
+        let mut conflicts = 0;
+        for file in archive::markdown_files(dir)? {
+            let markdown = std::fs::read_to_string(&file)
+                .with_context(|| format!("failed to read {}", file.display()))?;
+            let mut doc = archive::from_markdown(&markdown)
+                .with_context(|| format!("invalid document in {}", file.display()))?;
+            let namespace = doc.namespace().unwrap_or_else(|| self.namespace.clone());
+            doc.metadata.namespace = Some(namespace.clone());
+            let name = doc.name_any();
+            if let Some(source) = doc.labels().get(SOURCE_LABEL) {
+                writeln!(
+                    out,
+                    "document/{name} skipped, synced from documentsource/{source}"
+                )?;
+                continue;
+            }
+            let api: Api<Document> = Api::namespaced(self.client.clone(), &namespace);
+            // not forced, so fields set by the controller or other clients are reported instead of taken over
+            let pp = PatchParams::apply(DOCCTL_FIELD_MANAGER);
+            let existing = match dry_run {
+                true => api.get_opt(&name).await?,
+                false => None,
+            };
+            if dry_run && existing.is_none() {
+                writeln!(out, "document/{name} would be created in {namespace}")?;
+                continue;
+            }
+            let pp = if dry_run { pp.dry_run() } else { pp };
+            let applied = match api.patch(&name, &pp, &Patch::Apply(&doc)).await {
+                Ok(applied) => applied,
+                Err(kube::Error::Api(s)) if s.code == 409 => {
+                    writeln!(out, "document/{name} in {namespace} conflicts: {}", s.message)?;
+                    conflicts += 1;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let Some(existing) = existing else {
+                writeln!(out, "document/{name} applied in {namespace}")?;
+                continue;
+            };
+            let diff = crds::diff(
+                &archive::to_markdown(&existing)?,
+                &archive::to_markdown(&applied)?,
+            );
+            if diff.is_empty() {
+                writeln!(out, "document/{name} in {namespace} is unchanged")?;
+            } else {
+                writeln!(out, "document/{name} in {namespace} would change:")?;
+                out.push_str(&indent(&diff.join("\n")));
+            }
+        }
+        if conflicts > 0 {
+            bail!("{out}{conflicts} Documents have fields managed by others and were not imported");
+        }
+        Ok(out)
+    }
+
+    /// Remove the current and legacy finalizer names of the controller, keeping finalizers of others
+    ///
+    /// Documents changed while this runs are not updated and reported as failed.
+    pub async fn uninstall_finalizers(
+        &self,
+        all_namespaces: bool,
+        legacy: Vec<String>,
+        dry_run: bool,
+    ) -> anyhow::Result<String> {
+        let namespaces = match all_namespaces {
+            true => vec![],
+            false => vec![self.namespace.clone()],
+        };
+        let config = FinalizerConfig {
+            legacy,
+            ..FinalizerConfig::default()
+        };
+        let sweeper = Sweeper::new(self.client.clone(), config).with_namespaces(namespaces);
+        let summary = sweeper.sweep(true, dry_run).await?;
+        let mut out = String::new();
+        for key in &summary.removed {
+            let (namespace, name) = key.split_once('/').unwrap_or_default();
+            match dry_run {
+                true => writeln!(out, "document/{name} in {namespace} would lose the finalizer")?,
+                false => writeln!(out, "document/{name} in {namespace} finalizer removed")?,
+            }
+        }
+        for key in &summary.unmanaged {
+            let (namespace, name) = key.split_once('/').unwrap_or_default();
+            writeln!(
+                out,
+                "document/{name} in {namespace} keeps the finalizer (not selected)"
+            )?;
+        }
+        for (key, error) in &summary.failed {
+            let (namespace, name) = key.split_once('/').unwrap_or_default();
+            writeln!(out, "document/{name} in {namespace} failed: {error}")?;
+        }
+        if !summary.failed.is_empty() {
+            bail!("{out}{} Documents still have the finalizer", summary.failed.len());
+        }
+        Ok(out)
+    }
+}
+
+/// A valid object name from the stem of a file name
+fn name_from_path(path: &std::path::Path) -> anyhow::Result<String> {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+    let name: String = stem
+        .to_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let name = name
+        .trim_matches(|c: char| !c.is_ascii_alphanumeric())
+        .to_string();
+    if name.is_empty() {
+        bail!("no name can be derived from {}; use --name", path.display());
+    }
+    Ok(name)
+}
+
+pub fn format_list(docs: &[Document], all_namespaces: bool) -> String {
+    let mut rows = vec![];
+    if all_namespaces {
+        rows.push(header(&["NAMESPACE", "NAME", "TITLE", "HIDE", "HIDDEN", "READY"]));
+    } else {
+        rows.push(header(&["NAME", "TITLE", "HIDE", "HIDDEN", "READY"]));
+    }
+    for doc in docs {
+        let status = doc.status.clone().unwrap_or_default();
+        let ready = status.conditions.iter().find(|c| c.type_ == "Ready");
+        let mut row = vec![
+            doc.name_any(),
+            doc.spec.title.clone(),
+            doc.spec.hide.to_string(),
+            status.hidden.to_string(),
+            ready
+                .map(|c| c.status.clone())
+                .unwrap_or_else(|| "Unknown".into()),
+        ];
+        if all_namespaces {
+            row.insert(0, doc.namespace().unwrap_or_default());
+        }
+        rows.push(row);
+    }
+    table(&rows)
+}
+
+pub fn format_describe(doc: &Document, events: &[Event], now: Timestamp) -> String {
+    let status = doc.status.clone().unwrap_or_default();
+    let content = match (&doc.spec.content_from, &status.content_source) {
+        (None, _) => format!("{} bytes inline", doc.spec.content.len()),
+        (Some(_), Some(source)) if source.hash.is_empty() => format!("from {} {}", source.kind, source.name),
+        (Some(_), Some(source)) => format!("from {} {} (sha256 {})", source.kind, source.name, source.hash),
+        (Some(_), None) => "from an external source (not resolved yet)".into(),
+    };
+    let mut out = String::new();
+    let fields = [
+        ("Name", doc.name_any()),
+        ("Namespace", doc.namespace().unwrap_or_default()),
+        ("Title", doc.spec.title.clone()),
+        ("Hide", doc.spec.hide.to_string()),
+        ("Hidden", status.hidden.to_string()),
+        ("Paused", doc.is_paused().to_string()),
+        ("Content", content),
+    ];
+    for (field, value) in fields {
+        writeln!(out, "{:<11}{value}", format!("{field}:")).unwrap();
+    }
+
+    out.push_str("Conditions:\n");
+    if status.conditions.is_empty() {
+        out.push_str("  <none>\n");
+    } else {
+        let mut rows = vec![header(&["Type", "Status", "Reason", "Age", "Message"])];
+        for c in &status.conditions {
+            let age = age(c.last_transition_time.0, now);
+            rows.push(vec![
+                c.type_.clone(),
+                c.status.clone(),
+                c.reason.clone(),
+                age,
+                c.message.clone(),
+            ]);
+        }
+        out.push_str(&indent(&table(&rows)));
+    }
+
+    out.push_str("Events:\n");
+    if events.is_empty() {
+        out.push_str("  <none>\n");
+    } else {
+        let mut rows = vec![header(&["Type", "Reason", "Age", "From", "Message"])];
+        for e in events {
+            rows.push(vec![
+                e.type_.clone().unwrap_or_default(),
+                e.reason.clone().unwrap_or_default(),
+                event_time(e)
+                    .map(|t| age(t, now))
+                    .unwrap_or_else(|| "<unknown>".into()),
+                e.reporting_controller.clone().unwrap_or_default(),
+                e.note.clone().unwrap_or_default(),
+            ]);
+        }
+        out.push_str(&indent(&table(&rows)));
+    }
+    out
+}
+
+/// When an event last happened
+fn event_time(e: &Event) -> Option<Timestamp> {
+    let series = e.series.as_ref().map(|s| s.last_observed_time.0);
+    series
+        .or(e.event_time.as_ref().map(|t| t.0))
+        .or(e.deprecated_last_timestamp.as_ref().map(|t| t.0))
+        .or(e.metadata.creation_timestamp.as_ref().map(|t| t.0))
+}
+
+/// Short age like kubectl prints it (`45s`, `12m`, `3h`, `5d`)
+fn age(since: Timestamp, now: Timestamp) -> String {
+    let secs = now.duration_since(since).as_secs().max(0);
+    match secs {
+        s if s < 120 => format!("{s}s"),
+        s if s < 2 * 3600 => format!("{}m", s / 60),
+        s if s < 2 * 86400 => format!("{}h", s / 3600),
+        s => format!("{}d", s / 86400),
+    }
+}
+
+/// Left aligned columns separated by three spaces
+fn table(rows: &[Vec<String>]) -> String {
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let widths: Vec<_> = (0..columns)
+        .map(|i| {
+            rows.iter()
+                .map(|r| r.get(i).map_or(0, |c| c.chars().count()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let mut out = String::new();
+    for row in rows {
+        let cells: Vec<_> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        writeln!(out, "{}", cells.join("   ").trim_end()).unwrap();
+    }
+    out
+}
+
+fn header(columns: &[&str]) -> Vec<String> {
+    columns.iter().map(|c| c.to_string()).collect()
+}
+
+fn indent(text: &str) -> String {
+    text.lines().map(|line| format!("  {line}\n")).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cli, Command, Docctl, format_describe, format_list};
+    use crate::{
+        Document, archive,
+        fixtures::{Scenario, mock_client, timeout_after_1s},
+        source::SOURCE_LABEL,
+    };
+    use clap::CommandFactory;
+    use jiff::{SignedDuration, Timestamp};
+    use k8s_openapi::{api::events::v1::Event, apimachinery::pkg::apis::meta::v1::MicroTime};
+
+    #[test]
+    fn args_are_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[tokio::test]
+    async fn create_posts_the_document() {
+        let (client, fakeserver) = mock_client();
+        let path = std::env::temp_dir().join(format!("Release Notes-{}.md", std::process::id()));
+        std::fs::write(&path, "# Release notes\n\nAll the changes\n").unwrap();
+        let expected = Document::test();
+        let mocksrv = fakeserver.run(Scenario::DocumentCreation(expected.clone()));
+        let create = Command::Create {
+            file: path.clone(),
+            name: Some(expected.metadata.name.clone().unwrap()),
+            title: None,
+            hide: true,
+        };
+        let output = Docctl::new(client, "default".into()).run(create).await.unwrap();
+        assert_eq!(output, "document/test created\n");
+        timeout_after_1s(mocksrv).await;
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn hide_patches_the_spec() {
+        let (client, fakeserver) = mock_client();
+        let doc = Document::test().needs_hide();
+        let mocksrv = fakeserver.run(Scenario::HidePatch(doc));
+        let hide = Command::Hide { name: "test".into() };
+        let output = Docctl::new(client, "default".into()).run(hide).await.unwrap();
+        assert_eq!(output, "document/test hidden\n");
+        timeout_after_1s(mocksrv).await;
+    }
+
+    #[tokio::test]
+    async fn describe_shows_status_and_events() {
+        let (client, fakeserver) = mock_client();
+        let doc = Document::test().finalized().reconciled();
+        let mocksrv = fakeserver.run(Scenario::DocumentDescribe(doc, "HideRequested".into()));
+        let describe = Command::Describe { name: "test".into() };
+        let output = Docctl::new(client, "default".into()).run(describe).await.unwrap();
+        timeout_after_1s(mocksrv).await;
+        assert!(output.contains("Name:      test\n"), "{output}");
+        assert!(output.contains("Ready"), "{output}");
+        assert!(output.contains("HideRequested"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn export_writes_markdown_files() {
+        let (client, fakeserver) = mock_client();
+        let dir = std::env::temp_dir().join(format!("doc-ctrl-export-{}", std::process::id()));
+        let mut synced = Document::test();
+        synced.metadata.name = Some("guides-intro".into());
+        synced.metadata.labels = Some([(SOURCE_LABEL.to_string(), "guides".to_string())].into());
+        let mocksrv = fakeserver.run(Scenario::DocumentList(vec![
+            Document::test(),
+            Document::illegal(),
+            synced,
+        ]));
+        let export = Command::Export {
+            dir: dir.clone(),
+            all_namespaces: false,
+        };
+        let output = Docctl::new(client, "default".into()).run(export).await.unwrap();
+        timeout_after_1s(mocksrv).await;
+        assert_eq!(output.lines().count(), 3);
+        assert!(output.ends_with("document/guides-intro skipped, synced from documentsource/guides\n"));
+        assert!(!dir.join("default/guides-intro.md").exists());
+        let markdown = std::fs::read_to_string(dir.join("default/illegal.md")).unwrap();
+        assert!(markdown.starts_with("---\nname: illegal\nnamespace: default\n"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn import_dry_run_shows_changes() {
+        let (client, fakeserver) = mock_client();
+        let dir = std::env::temp_dir().join(format!("doc-ctrl-import-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let existing = Document::test();
+        let mut imported = existing.clone();
+        imported.spec.title = "Imported".into();
+        std::fs::write(dir.join("test.md"), archive::to_markdown(&imported).unwrap()).unwrap();
+
+        let mocksrv = fakeserver.run(Scenario::ImportDryRun(existing));
+        let import = Command::Import {
+            dir: dir.clone(),
+            dry_run: true,
+        };
+        let output = Docctl::new(client, "default".into()).run(import).await.unwrap();
+        timeout_after_1s(mocksrv).await;
+        assert_eq!(
+            output,
+            "document/test in default would change:\n  -title: ''\n  +title: Imported\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn uninstall_finalizers_removes_them_in_the_namespace() {
+        let (client, fakeserver) = mock_client();
+        let mut doc = Document::test();
+        doc.metadata.finalizers = Some(vec!["kube.rs/documents".into(), "other.io/keep".into()]);
+        let mut elsewhere = doc.clone();
+        elsewhere.metadata.namespace = Some("other".into());
+        let mut removed = doc.clone();
+        removed.metadata.finalizers = Some(vec!["other.io/keep".into()]);
+        let scenario = Scenario::FinalizerSweep(Some(vec![doc.clone(), elsewhere]), vec![doc], removed);
+        let mocksrv = fakeserver.run(scenario);
+        let uninstall = Command::UninstallFinalizers {
+            all_namespaces: false,
+            legacy: vec!["kube.rs/documents".into()],
+            dry_run: false,
+        };
+        let output = Docctl::new(client, "default".into())
+            .run(uninstall)
+            .await
+            .unwrap();
+        timeout_after_1s(mocksrv).await;
+        assert_eq!(
+            output,
+            "document/test in default finalizer removed\n\
+             document/test in other keeps the finalizer (not selected)\n"
+        );
+    }
+
+    #[test]
+    fn output_is_formatted_as_tables() {
+        let mut doc = Document::test().reconciled();
+        doc.spec.title = "Test".into();
+        let list = format_list(&[doc.clone()], true);
+        let lines: Vec<_> = list.lines().collect();
+        assert_eq!(lines[0], "NAMESPACE   NAME   TITLE   HIDE    HIDDEN   READY");
+        assert_eq!(lines[1], "default     test   Test    false   false    True");
+
+        let now = Timestamp::now();
+        let event = Event {
+            reason: Some("HiddenDoc".into()),
+            event_time: Some(MicroTime(now - SignedDuration::from_mins(5))),
+            ..Event::default()
+        };
+        let describe = format_describe(&doc, &[event], now);
+        assert!(describe.contains("Paused:    false\n"), "{describe}");
+        assert!(describe.contains("HiddenDoc   5m"), "{describe}");
+    }
+}
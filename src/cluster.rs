@@ -0,0 +1,61 @@
+//! Reconciling Documents in several clusters from one controller
+use anyhow::Context as _;
+use kube::{Client, Config, config::KubeConfigOptions};
+use serde::{Deserialize, Serialize, Serializer};
+use thiserror::Error;
+
+/// A cluster reconciled by the controller, reached through a kubeconfig context
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ClusterConfig {
+    /// Name in the `cluster` label of metrics and in diagnostics
+    pub name: String,
+    /// Context in the kubeconfig; defaults to the name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+}
+
+impl ClusterConfig {
+    pub fn context(&self) -> &str {
+        self.context.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Client for the cluster from the kubeconfig (`KUBECONFIG` or `~/.kube/config`)
+    pub async fn client(&self) -> anyhow::Result<Client> {
+        let options = KubeConfigOptions {
+            context: Some(self.context().to_string()),
+            ..Default::default()
+        };
+        let config = Config::from_kubeconfig(&options)
+            .await
+            .with_context(|| format!("failed to load context {:?}", self.context()))?;
+        Client::try_from(config).with_context(|| format!("failed to create a client for {:?}", self.name))
+    }
+}
+
+/// Serialize kubeconfig contexts given as flags into clusters named after them
+pub(crate) fn serialize_contexts<S: Serializer>(
+    contexts: &Option<Vec<String>>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    let clusters = contexts.as_ref().map(|contexts| {
+        contexts
+            .iter()
+            .map(|context| ClusterConfig {
+                name: context.clone(),
+                context: None,
+            })
+            .collect::<Vec<_>>()
+    });
+    clusters.serialize(s)
+}
+
+/// Why a request could not be scoped to a cluster
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ClusterError {
+    #[error("the cluster parameter is required when reconciling several clusters")]
+    Required,
+
+    #[error("unknown cluster {0:?}")]
+    Unknown(String),
+}
@@ -0,0 +1,789 @@
+//! Configuration of the controller binary
+//!
+//! Values are layered with later sources taking precedence: built-in defaults, the YAML file given by `--config`,
+//! environment variables, then command line flags.
+use crate::{
+    auth::{AdminAuth, AdminConfig},
+    cache::{WatchConfig, WatchMode},
+    cluster::ClusterConfig,
+    controller::ControllerConfig,
+    notify::NotifierConfig,
+    predicates::PredicateConfig,
+    shard::ShardConfig,
+    source::SourceConfig,
+    sweep::FinalizerConfig,
+    telemetry::TelemetryConfig,
+    tls::TlsConfig,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("failed to read {}: {source}", path.display())]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("failed to parse {}: {source}", path.display())]
+    Parse {
+        path: PathBuf,
+        #[source]
+        source: serde_yaml::Error,
+    },
+
+    #[error("invalid configuration: {0}")]
+    Deserialize(#[source] serde_json::Error),
+
+    #[error("invalid configuration:{}", .0.iter().map(|p| format!("\n  - {p}")).collect::<String>())]
+    Invalid(Vec<String>),
+}
+
+/// Settings of the controller binary
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    /// Serve the admin endpoints with TLS
+    pub tls: Option<TlsConfig>,
+    pub controller: ControllerConfig,
+    /// Clusters to reconcile; only the cluster of the default client when empty
+    pub clusters: Vec<ClusterConfig>,
+    pub telemetry: TelemetryConfig,
+    pub watch: WatchConfig,
+    pub predicates: PredicateConfig,
+    pub sharding: ShardConfig,
+    pub notifications: NotifierConfig,
+    pub finalizers: FinalizerConfig,
+    pub sources: SourceConfig,
+    pub admin: AdminConfig,
+}
+
+/// Bind addresses of the web servers
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct ServerConfig {
+    /// Probes and read-only diagnostics
+    pub http_bind: SocketAddr,
+    pub metrics_bind: SocketAddr,
+    /// Admin endpoints (with TLS when configured)
+    pub admin_bind: SocketAddr,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            http_bind: ([0, 0, 0, 0], 8080).into(),
+            metrics_bind: ([0, 0, 0, 0], 9090).into(),
+            admin_bind: ([0, 0, 0, 0], 8443).into(),
+        }
+    }
+}
+
+impl Config {
+    /// Layer the file from `--config` (if any) under the environment and flags, and validate the result
+    pub fn load(args: &Args) -> Result<Self, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => {
+                let path = path.clone();
+                let text = match std::fs::read_to_string(&path) {
+                    Ok(text) => text,
+                    Err(source) => return Err(ConfigError::Read { path, source }),
+                };
+                serde_yaml::from_str(&text).map_err(|source| ConfigError::Parse { path, source })?
+            }
+            None => Value::Null,
+        };
+        if config.is_null() {
+            config = Value::Object(Map::new());
+        }
+        let overrides = serde_json::to_value(args).expect("args are serializable");
+        if let Some(overrides) = prune(overrides) {
+            merge(&mut config, overrides);
+        }
+        let config: Config = serde_json::from_value(config).map_err(ConfigError::Deserialize)?;
+        match config.validate() {
+            problems if problems.is_empty() => Ok(config),
+            problems => Err(ConfigError::Invalid(problems)),
+        }
+    }
+
+    /// Problems with the configuration that would otherwise surface after startup
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let mut problem = |ok: bool, msg: String| {
+            if !ok {
+                problems.push(msg);
+            }
+        };
+
+        let server = &self.server;
+        let binds = [server.http_bind, server.metrics_bind, server.admin_bind];
+        for (i, addr) in binds.iter().enumerate() {
+            problem(
+                !binds[..i].contains(addr),
+                format!("server: {addr} is bound by more than one server"),
+            );
+        }
+
+        if let Some(tls) = &self.tls {
+            for file in [&tls.cert_file, &tls.key_file] {
+                problem(file.is_file(), format!("tls: {} is not a file", file.display()));
+            }
+            problem(
+                !tls.reload_interval.is_zero(),
+                "tls: reloadInterval must be positive".into(),
+            );
+        }
+
+        let controller = &self.controller;
+        for ns in &controller.namespaces {
+            problem(is_dns_label(ns), format!("controller: invalid namespace {ns:?}"));
+        }
+        for (name, interval) in [
+            ("requeueInterval", controller.requeue_interval),
+            ("retryInterval", controller.retry_interval),
+            ("pausedRequeueInterval", controller.paused_requeue_interval),
+        ] {
+            problem(
+                !interval.is_zero(),
+                format!("controller: {name} must be positive"),
+            );
+        }
+        problem(
+            !controller.reporter.is_empty(),
+            "controller: reporter must not be empty".into(),
+        );
+        if let Some(root) = &controller.content_root {
+            problem(
+                root.is_dir(),
+                format!("controller: contentRoot {} is not a directory", root.display()),
+            );
+        }
+
+        for (i, cluster) in self.clusters.iter().enumerate() {
+            problem(
+                !cluster.name.is_empty(),
+                "clusters: name must not be empty".into(),
+            );
+            problem(
+                !self.clusters[..i].iter().any(|c| c.name == cluster.name),
+                format!("clusters: {:?} is configured more than once", cluster.name),
+            );
+        }
+
+        let telemetry = &self.telemetry;
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&telemetry.log_filter) {
+            problem(false, format!("telemetry: invalid logFilter: {e}"));
+        }
+        #[cfg(feature = "telemetry")]
+        problem(
+            telemetry.otlp_endpoint.is_some(),
+            "telemetry: otlpEndpoint is required for traces".into(),
+        );
+
+        problem(
+            self.watch.cache_capacity > 0 || self.watch.mode == WatchMode::Full,
+            "watch: cacheCapacity must be positive in metadata mode".into(),
+        );
+        problem(
+            self.watch.search_capacity > 0,
+            "watch: searchCapacity must be positive".into(),
+        );
+
+        let sharding = &self.sharding;
+        if sharding.enabled {
+            problem(
+                !sharding.identity.is_empty(),
+                "sharding: identity must not be empty".into(),
+            );
+            problem(sharding.vnodes > 0, "sharding: vnodes must be positive".into());
+            problem(
+                sharding.renew_interval < sharding.lease_duration,
+                "sharding: renewInterval must be shorter than leaseDuration".into(),
+            );
+        }
+
+        for url in &self.notifications.endpoints {
+            let valid = reqwest::Url::parse(url).is_ok_and(|u| matches!(u.scheme(), "http" | "https"));
+            problem(valid, format!("notifications: invalid endpoint {url:?}"));
+        }
+
+        for name in &self.finalizers.legacy {
+            problem(
+                !name.is_empty() && name != crate::DOCUMENT_FINALIZER,
+                format!("finalizers: invalid legacy finalizer {name:?}"),
+            );
+        }
+
+        problem(
+            !self.sources.git_timeout.is_zero(),
+            "sources: gitTimeout must be positive".into(),
+        );
+        if let Some(dir) = &self.sources.checkout_dir {
+            problem(
+                dir.is_dir(),
+                format!("sources: checkoutDir {} is not a directory", dir.display()),
+            );
+        }
+        for host in &self.sources.allowed_hosts {
+            let domain = host.strip_prefix("*.").unwrap_or(host);
+            problem(
+                !domain.is_empty() && !domain.contains(['/', ':', '*', '@']),
+                format!("sources: invalid allowed host {host:?}"),
+            );
+        }
+        if let Err(e) = self.admin.mode() {
+            problem(false, format!("admin: {e}"));
+        }
+        problems
+    }
+
+    /// The configuration with secrets replaced, for display
+    pub fn redacted(&self) -> Self {
+        let redact = |s: &Option<String>| s.as_ref().map(|_| "<redacted>".to_string());
+        let mut config = self.clone();
+        config.notifications.secret = redact(&config.notifications.secret);
+        config.admin.token = redact(&config.admin.token);
+        config
+    }
+}
+
+/// Settings that can be replaced while running, shared between their readers
+#[derive(Debug, Default)]
+pub struct Reloadable<T>(Arc<RwLock<Arc<T>>>);
+
+impl<T> Clone for Reloadable<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> From<T> for Reloadable<T> {
+    fn from(value: T) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(value))))
+    }
+}
+
+impl<T> Reloadable<T> {
+    /// The current value (later replacements do not affect the returned value)
+    pub fn get(&self) -> Arc<T> {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Replace the value for all readers
+    pub fn set(&self, value: T) {
+        *self.0.write().unwrap() = Arc::new(value);
+    }
+}
+
+fn is_dns_label(s: &str) -> bool {
+    let valid_chars = s
+        .bytes()
+        .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-');
+    valid_chars && (1..=63).contains(&s.len()) && !s.starts_with('-') && !s.ends_with('-')
+}
+
+/// Drop unset values (and sections left empty) so they do not override the file
+fn prune(value: Value) -> Option<Value> {
+    match value {
+        Value::Null => None,
+        Value::Object(map) => {
+            let map: Map<_, _> = map
+                .into_iter()
+                .filter_map(|(k, v)| Some((k, prune(v)?)))
+                .collect();
+            (!map.is_empty()).then_some(Value::Object(map))
+        }
+        value => Some(value),
+    }
+}
+
+fn merge(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                merge(base.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (base, value) => *base = value,
+    }
+}
+
+/// Serde helpers for durations written like `30s`, `5m` or `1h 30m` (ISO 8601 is also accepted)
+pub mod duration {
+    use jiff::SignedDuration;
+    use serde::{Deserialize, Deserializer, Serializer, de::Error as _, ser::Error as _};
+    use std::time::Duration;
+
+    /// Parse a non-negative duration
+    pub fn parse(s: &str) -> Result<Duration, String> {
+        let duration: SignedDuration = s.parse().map_err(|e| format!("{e}"))?;
+        Duration::try_from(duration).map_err(|_| format!("{s} is negative"))
+    }
+
+    pub fn serialize<S: Serializer>(duration: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        let duration = SignedDuration::try_from(*duration).map_err(S::Error::custom)?;
+        s.collect_str(&format_args!("{duration:#}"))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        parse(&String::deserialize(d)?).map_err(D::Error::custom)
+    }
+
+    /// For optional durations
+    pub mod option {
+        use serde::Serializer;
+        use std::time::Duration;
+
+        pub fn serialize<S: Serializer>(duration: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+            match duration {
+                Some(d) => super::serialize(d, s),
+                None => s.serialize_none(),
+            }
+        }
+    }
+}
+
+/// Kubernetes controller for Documents
+///
+/// Every flag can also be set through the environment variable shown with it, or in the `--config` file under the
+/// section of its heading (e.g. `controller.requeueInterval` for `--requeue-interval`).
+#[derive(clap::Parser, Clone, Debug, Default, Serialize)]
+#[command(version)]
+pub struct Args {
+    /// YAML configuration file; environment variables and flags override its values
+    #[arg(long, short, env = "CONFIG_FILE", value_name = "FILE")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+
+    #[command(flatten, next_help_heading = "Server")]
+    server: ServerArgs,
+
+    #[command(flatten, next_help_heading = "TLS")]
+    tls: TlsArgs,
+
+    #[command(flatten, next_help_heading = "Controller")]
+    controller: ControllerArgs,
+
+    /// Comma separated kubeconfig contexts of clusters to reconcile [default: the cluster of the default client]
+    #[arg(
+        long,
+        env = "KUBE_CONTEXTS",
+        value_delimiter = ',',
+        value_name = "CONTEXTS",
+        help_heading = "Clusters"
+    )]
+    #[serde(rename = "clusters", serialize_with = "crate::cluster::serialize_contexts")]
+    contexts: Option<Vec<String>>,
+
+    #[command(flatten, next_help_heading = "Telemetry")]
+    telemetry: TelemetryArgs,
+
+    #[command(flatten, next_help_heading = "Watch")]
+    watch: WatchArgs,
+
+    #[command(flatten, next_help_heading = "Predicates")]
+    predicates: PredicateArgs,
+
+    #[command(flatten, next_help_heading = "Sharding")]
+    sharding: ShardArgs,
+
+    #[command(flatten, next_help_heading = "Notifications")]
+    notifications: NotifierArgs,
+
+    #[command(flatten, next_help_heading = "Finalizers")]
+    finalizers: FinalizerArgs,
+
+    #[command(flatten, next_help_heading = "Sources")]
+    sources: SourceArgs,
+
+    #[command(flatten, next_help_heading = "Admin")]
+    admin: AdminArgs,
+}
+
+#[derive(clap::Args, Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ServerArgs {
+    /// Address of probes and read-only diagnostics [default: 0.0.0.0:8080]
+    #[arg(long, env = "HTTP_BIND", value_name = "ADDR")]
+    http_bind: Option<SocketAddr>,
+
+    /// Address of metrics [default: 0.0.0.0:9090]
+    #[arg(long, env = "METRICS_BIND", value_name = "ADDR")]
+    metrics_bind: Option<SocketAddr>,
+
+    /// Address of admin endpoints [default: 0.0.0.0:8443]
+    #[arg(long, env = "ADMIN_BIND", value_name = "ADDR")]
+    admin_bind: Option<SocketAddr>,
+}
+
+#[derive(clap::Args, Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TlsArgs {
+    /// PEM certificate chain for the admin endpoints (needs --tls-key-file)
+    #[arg(id = "tls-cert-file", long, env = "TLS_CERT_FILE", value_name = "FILE")]
+    cert_file: Option<PathBuf>,
+
+    /// PEM private key for the admin endpoints (needs --tls-cert-file)
+    #[arg(id = "tls-key-file", long, env = "TLS_KEY_FILE", value_name = "FILE")]
+    key_file: Option<PathBuf>,
+
+    /// How often certificate files are checked for changes [default: 30s]
+    #[arg(id = "tls-reload-interval", long, env = "TLS_RELOAD_INTERVAL", value_name = "DURATION", value_parser = duration::parse)]
+    #[serde(serialize_with = "duration::option::serialize")]
+    reload_interval: Option<Duration>,
+}
+
+#[derive(clap::Args, Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ControllerArgs {
+    /// Comma separated namespaces to watch Documents in [default: all namespaces]
+    #[arg(
+        long,
+        env = "WATCH_NAMESPACES",
+        value_delimiter = ',',
+        value_name = "NAMESPACES"
+    )]
+    namespaces: Option<Vec<String>>,
+
+    /// Max reconciles running at once, 0 for unbounded [default: 0]
+    #[arg(long, env = "RECONCILE_CONCURRENCY", value_name = "N")]
+    concurrency: Option<u16>,
+
+    /// How often Documents are reconciled without changes [default: 5m]
+    #[arg(long, env = "REQUEUE_INTERVAL", value_name = "DURATION", value_parser = duration::parse)]
+    #[serde(serialize_with = "duration::option::serialize")]
+    requeue_interval: Option<Duration>,
+
+    /// How soon Documents are retried after retryable errors [default: 15s]
+    #[arg(long, env = "RETRY_INTERVAL", value_name = "DURATION", value_parser = duration::parse)]
+    #[serde(serialize_with = "duration::option::serialize")]
+    retry_interval: Option<Duration>,
+
+    /// How often globally paused Documents are checked for a resume [default: 30s]
+    #[arg(long, env = "PAUSED_REQUEUE_INTERVAL", value_name = "DURATION", value_parser = duration::parse)]
+    #[serde(serialize_with = "duration::option::serialize")]
+    paused_requeue_interval: Option<Duration>,
+
+    /// Controller name on published events [default: doc-controller]
+    #[arg(long, env = "REPORTER", value_name = "NAME")]
+    reporter: Option<String>,
+
+    /// Directory file:// content urls are read from [default: file urls are rejected]
+    #[arg(long, env = "CONTENT_ROOT", value_name = "DIR")]
+    content_root: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TelemetryArgs {
+    /// Log filter directives [default: info]
+    #[arg(long, env = "RUST_LOG", value_name = "FILTER")]
+    log_filter: Option<String>,
+
+    /// OTLP gRPC collector receiving traces (with the telemetry feature)
+    #[arg(long, env = "OPENTELEMETRY_ENDPOINT_URL", value_name = "URL")]
+    otlp_endpoint: Option<String>,
+}
+
+#[derive(clap::Args, Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WatchArgs {
+    /// Keep full Documents in memory, or only their metadata [default: full]
+    #[arg(id = "watch-mode", long, env = "WATCH_MODE", value_name = "MODE")]
+    mode: Option<WatchMode>,
+
+    /// Max Documents held by the cache in metadata mode [default: 1000]
+    #[arg(
+        id = "document-cache-capacity",
+        long,
+        env = "DOCUMENT_CACHE_CAPACITY",
+        value_name = "N"
+    )]
+    cache_capacity: Option<usize>,
+
+    /// Max Documents held by the search index [default: 10000]
+    #[arg(long, env = "SEARCH_CAPACITY", value_name = "N")]
+    search_capacity: Option<usize>,
+}
+
+#[derive(clap::Args, Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PredicateArgs {
+    /// Only reconcile on relevant changes [default: true]
+    #[arg(
+        id = "predicate-filter",
+        long,
+        env = "PREDICATE_FILTER_ENABLED",
+        value_name = "BOOL"
+    )]
+    enabled: Option<bool>,
+
+    /// Comma separated prefixes of labels relevant to the reconciler
+    #[arg(
+        id = "predicate-label-prefixes",
+        long,
+        env = "PREDICATE_LABEL_PREFIXES",
+        value_delimiter = ',',
+        value_name = "PREFIXES"
+    )]
+    label_prefixes: Option<Vec<String>>,
+
+    /// Comma separated prefixes of annotations relevant to the reconciler [default: kube.rs/]
+    #[arg(
+        id = "predicate-annotation-prefixes",
+        long,
+        env = "PREDICATE_ANNOTATION_PREFIXES",
+        value_delimiter = ',',
+        value_name = "PREFIXES"
+    )]
+    annotation_prefixes: Option<Vec<String>>,
+}
+
+#[derive(clap::Args, Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ShardArgs {
+    /// Split Documents between replicas [default: false]
+    #[arg(id = "sharding", long, env = "SHARDING_ENABLED", value_name = "BOOL")]
+    enabled: Option<bool>,
+
+    /// Unique name of this replica [default: doc-controller]
+    #[arg(id = "shard-identity", long, env = "POD_NAME", value_name = "NAME")]
+    identity: Option<String>,
+
+    /// Namespace of the membership Leases [default: default]
+    #[arg(id = "shard-namespace", long, env = "POD_NAMESPACE", value_name = "NAMESPACE")]
+    namespace: Option<String>,
+
+    /// Replicas without a Lease renewal for this long are considered gone [default: 15s]
+    #[arg(id = "shard-lease-duration", long, env = "SHARD_LEASE_DURATION", value_name = "DURATION", value_parser = duration::parse)]
+    #[serde(serialize_with = "duration::option::serialize")]
+    lease_duration: Option<Duration>,
+
+    /// How often the Lease is renewed [default: 5s]
+    #[arg(id = "shard-renew-interval", long, env = "SHARD_RENEW_INTERVAL", value_name = "DURATION", value_parser = duration::parse)]
+    #[serde(serialize_with = "duration::option::serialize")]
+    renew_interval: Option<Duration>,
+}
+
+#[derive(clap::Args, Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NotifierArgs {
+    /// Comma separated webhooks receiving lifecycle transitions
+    #[arg(
+        id = "notify-webhook-urls",
+        long,
+        env = "NOTIFY_WEBHOOK_URLS",
+        value_delimiter = ',',
+        value_name = "URLS"
+    )]
+    endpoints: Option<Vec<String>>,
+
+    /// Secret signing webhook payloads
+    #[arg(
+        id = "notify-webhook-secret",
+        long,
+        env = "NOTIFY_WEBHOOK_SECRET",
+        hide_env_values = true,
+        value_name = "SECRET"
+    )]
+    secret: Option<String>,
+}
+
+#[derive(clap::Args, Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FinalizerArgs {
+    /// Comma separated former names of the Document finalizer, migrated to the current one
+    #[arg(
+        id = "legacy-finalizers",
+        long,
+        env = "LEGACY_FINALIZERS",
+        value_delimiter = ',',
+        value_name = "NAMES"
+    )]
+    legacy: Option<Vec<String>>,
+
+    /// Migrate and report finalizers at startup [default: true]
+    #[arg(
+        id = "finalizer-sweep",
+        long,
+        env = "FINALIZER_SWEEP_ON_STARTUP",
+        value_name = "BOOL"
+    )]
+    sweep_on_startup: Option<bool>,
+}
+
+#[derive(clap::Args, Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SourceArgs {
+    /// How long a git command of a DocumentSource sync may run [default: 1m]
+    #[arg(id = "source-git-timeout", long, env = "SOURCE_GIT_TIMEOUT", value_name = "DURATION", value_parser = duration::parse)]
+    #[serde(serialize_with = "duration::option::serialize")]
+    git_timeout: Option<Duration>,
+
+    /// Writable directory repositories are cloned into [default: the temporary directory]
+    #[arg(
+        id = "source-checkout-dir",
+        long,
+        env = "SOURCE_CHECKOUT_DIR",
+        value_name = "DIR"
+    )]
+    checkout_dir: Option<PathBuf>,
+
+    /// Comma separated hosts repositories may be cloned from, `*.` matching subdomains [default: github.com,gitlab.com]
+    #[arg(
+        id = "source-allowed-hosts",
+        long,
+        env = "SOURCE_ALLOWED_HOSTS",
+        value_delimiter = ',',
+        value_name = "HOSTS"
+    )]
+    allowed_hosts: Option<Vec<String>>,
+}
+
+#[derive(clap::Args, Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AdminArgs {
+    /// Authentication of admin requests [default: token when a token is set, otherwise disabled]
+    #[arg(id = "admin-auth", long, env = "ADMIN_AUTH", value_name = "MODE")]
+    auth: Option<AdminAuth>,
+
+    /// Static bearer token for token authentication
+    #[arg(
+        id = "admin-token",
+        long,
+        env = "ADMIN_TOKEN",
+        hide_env_values = true,
+        value_name = "TOKEN"
+    )]
+    token: Option<String>,
+}
+
+#[cfg(test)]
+impl Args {
+    /// Parse flags with the given environment variables instead of those of the test process
+    pub(crate) fn parse_with_env<'a>(
+        argv: &[&str],
+        env: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Self {
+        use clap::{CommandFactory, FromArgMatches};
+        let env: std::collections::HashMap<_, _> = env.into_iter().collect();
+        let mut flags: Vec<String> = argv.iter().map(|a| a.to_string()).collect();
+        let command = Args::command().mut_args(|arg| {
+            let var = arg.get_env().and_then(|var| env.get(var.to_str()?));
+            if let Some(value) = var {
+                flags.push(format!(
+                    "--{}={value}",
+                    arg.get_long().expect("env args have flags")
+                ));
+            }
+            arg.env(None)
+        });
+        let matches = command.try_get_matches_from(flags).expect("valid flags");
+        Args::from_arg_matches(&matches).expect("valid flags")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Args, Config, ConfigError};
+    use crate::cache::WatchMode;
+    use clap::CommandFactory;
+    use std::time::Duration;
+
+    #[test]
+    fn args_are_consistent() {
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn flags_override_the_config_file() {
+        let path = std::env::temp_dir().join(format!("doc-ctrl-config-{}.yaml", std::process::id()));
+        let file = "
+controller:
+  concurrency: 4
+  requeueInterval: 10m
+  namespaces: [docs]
+watch:
+  mode: metadata
+  cacheCapacity: 50
+";
+        std::fs::write(&path, file).unwrap();
+        let args = Args::parse_with_env(
+            &[
+                "controller",
+                "--config",
+                path.to_str().unwrap(),
+                "--concurrency=8",
+                "--http-bind=127.0.0.1:8000",
+            ],
+            [("DOCUMENT_CACHE_CAPACITY", "100"), ("RUST_LOG", "debug")],
+        );
+        let config = Config::load(&args).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.controller.concurrency, 8);
+        assert_eq!(config.controller.requeue_interval, Duration::from_secs(600));
+        assert_eq!(config.controller.namespaces, vec!["docs".to_string()]);
+        assert_eq!(config.watch.mode, WatchMode::Metadata);
+        assert_eq!(config.watch.cache_capacity, 100);
+        assert_eq!(config.server.http_bind.to_string(), "127.0.0.1:8000");
+        assert_eq!(config.telemetry.log_filter, "debug");
+        assert!(config.clusters.is_empty());
+        // untouched sections keep their defaults
+        assert_eq!(config.server.metrics_bind.port(), 9090);
+        assert!(config.predicates.enabled);
+    }
+
+    #[test]
+    fn contexts_become_clusters() {
+        let args = Args::parse_with_env(&["controller"], [("KUBE_CONTEXTS", "east,west")]);
+        let config = Config::load(&args).unwrap();
+        let names: Vec<_> = config.clusters.iter().map(|c| c.context()).collect();
+        assert_eq!(names, ["east", "west"]);
+        assert_eq!(config.clusters[1].name, "west");
+
+        let args = Args::parse_with_env(&["controller", "--contexts=east,east"], []);
+        assert!(matches!(Config::load(&args), Err(ConfigError::Invalid(_))));
+        let clusters = serde_json::json!({"clusters": [{"name": "prod", "context": "admin@prod"}]});
+        let config: Config = serde_json::from_value(clusters).unwrap();
+        assert_eq!(config.clusters[0].context(), "admin@prod");
+    }
+
+    #[test]
+    fn invalid_configuration_is_rejected() {
+        let args = Args::parse_with_env(
+            &[
+                "controller",
+                "--metrics-bind=0.0.0.0:8080",
+                "--requeue-interval=0s",
+                "--namespaces=Docs",
+                "--admin-auth=token",
+                "--legacy-finalizers=documents.kube.rs",
+            ],
+            [("ADMIN_TOKEN", "")],
+        );
+        let Err(ConfigError::Invalid(problems)) = Config::load(&args) else {
+            panic!("expected validation errors");
+        };
+        assert_eq!(problems.len(), 5, "{problems:?}");
+
+        assert!(
+            Args::command()
+                .try_get_matches_from(["controller", "--retry-interval=soon"])
+                .is_err()
+        );
+        let unknown = serde_json::from_value::<Config>(serde_json::json!({"controler": {}}));
+        assert!(unknown.is_err());
+    }
+}
@@ -0,0 +1,302 @@
+//! Resolution of Document content stored outside the Document object
+use crate::{ContentSource, ContentSourceStatus, Document, Error, KeyRef, Result};
+use k8s_openapi::api::core::v1::{ConfigMap, Secret};
+use kube::{Client, ResourceExt, api::Api, runtime::reflector::ObjectRef};
+use sha2::{Digest, Sha256};
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+/// The content of a Document after following any `contentFrom` reference
+pub struct ResolvedContent {
+    pub content: String,
+    /// Set when the content came from an external source
+    pub source: Option<ContentSourceStatus>,
+}
+
+impl ResolvedContent {
+    /// Content that may be indexed for search; none for content resolved from a Secret
+    pub fn searchable(&self) -> &str {
+        match &self.source {
+            Some(source) if source.kind == "Secret" => "",
+            _ => &self.content,
+        }
+    }
+}
+
+impl Document {
+    /// Resolve the content of the Document, fetching it from its external source if necessary
+    ///
+    /// `file://` urls are only read below `file_root`, and rejected without one.
+    pub async fn resolve_content(&self, client: Client, file_root: Option<&Path>) -> Result<ResolvedContent> {
+        let Some(source) = &self.spec.content_from else {
+            return Ok(ResolvedContent {
+                content: self.spec.content.clone(),
+                source: None,
+            });
+        };
+        let ns = self.namespace().unwrap(); // doc is namespace scoped
+        let (content, mut status) = source.fetch(client, &ns, file_root).await?;
+        // a digest of low-entropy secrets in status could be reversed by anyone reading Documents
+        if status.kind != "Secret" {
+            status.hash = hash(&content);
+        }
+        Ok(ResolvedContent {
+            content,
+            source: Some(status),
+        })
+    }
+
+    /// Name of the ConfigMap holding the content of the Document (if any)
+    pub(crate) fn content_config_map(&self) -> Option<&str> {
+        let source = self.spec.content_from.as_ref()?;
+        source.config_map_key_ref.as_ref().map(|r| r.name.as_str())
+    }
+}
+
+impl ContentSource {
+    async fn fetch(
+        &self,
+        client: Client,
+        ns: &str,
+        file_root: Option<&Path>,
+    ) -> Result<(String, ContentSourceStatus)> {
+        match (&self.config_map_key_ref, &self.secret_key_ref, &self.url) {
+            (Some(KeyRef { name, key }), None, None) => {
+                let cms: Api<ConfigMap> = Api::namespaced(client, ns);
+                let cm = cms.get_opt(name).await.map_err(Error::KubeError)?;
+                let cm = cm.ok_or_else(|| missing(format!("ConfigMap {ns}/{name} not found")))?;
+                let content = cm.data.as_ref().and_then(|d| d.get(key)).cloned();
+                let content = content.ok_or_else(|| missing(format!("key {key} not in ConfigMap {name}")))?;
+                Ok((content, ContentSourceStatus {
+                    kind: "ConfigMap".into(),
+                    name: name.clone(),
+                    resource_version: cm.resource_version(),
+                    hash: String::new(),
+                }))
+            }
+            (None, Some(KeyRef { name, key }), None) => {
+                let secrets: Api<Secret> = Api::namespaced(client, ns);
+                let secret = secrets.get_opt(name).await.map_err(Error::KubeError)?;
+                let secret = secret.ok_or_else(|| missing(format!("Secret {ns}/{name} not found")))?;
+                let bytes = secret.data.as_ref().and_then(|d| d.get(key)).cloned();
+                let bytes = bytes.ok_or_else(|| missing(format!("key {key} not in Secret {name}")))?;
+                let content = String::from_utf8(bytes.0)
+                    .map_err(|_| invalid(format!("key {key} in Secret {name} is not utf-8")))?;
+                Ok((content, ContentSourceStatus {
+                    kind: "Secret".into(),
+                    name: name.clone(),
+                    resource_version: secret.resource_version(),
+                    hash: String::new(),
+                }))
+            }
+            (None, None, Some(url)) => {
+                let path = url
+                    .strip_prefix("file://")
+                    .ok_or_else(|| invalid(format!("unsupported url {url}; only file:// is supported")))?;
+                let root =
+                    file_root.ok_or_else(|| invalid("file urls are disabled (no content root)".into()))?;
+                let real = confine(root, path)?;
+                let content = tokio::fs::read_to_string(&real)
+                    .await
+                    .map_err(|e| invalid(format!("failed to read {path}: {e}")))?;
+                Ok((content, ContentSourceStatus {
+                    kind: "Url".into(),
+                    name: url.clone(),
+                    resource_version: None,
+                    hash: String::new(),
+                }))
+            }
+            _ => Err(invalid(
+                "exactly one of configMapKeyRef, secretKeyRef or url must be set".into(),
+            )),
+        }
+    }
+}
+
+/// The real path of a file below the root, rejecting `..` and symlinks leading out of the root
+///
+/// Paths outside the root are rejected before touching the filesystem, so whether they exist is not revealed.
+fn confine(root: &Path, path: &str) -> Result<PathBuf> {
+    let path = Path::new(path);
+    if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+        return Err(invalid(format!(
+            "file url path {} must be absolute without ..",
+            path.display()
+        )));
+    }
+    let canonical = root
+        .canonicalize()
+        .map_err(|e| invalid(format!("content root {}: {e}", root.display())))?;
+    if !path.starts_with(root) && !path.starts_with(&canonical) {
+        return Err(outside(path));
+    }
+    let root = canonical;
+    let real = path.canonicalize().map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => missing(format!("file {} not found", path.display())),
+        _ => invalid(format!("failed to resolve {}: {e}", path.display())),
+    })?;
+    if !real.starts_with(&root) {
+        return Err(outside(path));
+    }
+    Ok(real)
+}
+
+fn outside(path: &Path) -> Error {
+    invalid(format!("file {} is outside the content root", path.display()))
+}
+
+fn missing(msg: String) -> Error {
+    Error::MissingContentReference(msg)
+}
+
+fn invalid(msg: String) -> Error {
+    Error::InvalidContentSource(msg)
+}
+
+/// Sha256 hex digest of content
+pub fn hash(content: &str) -> String {
+    format!("{:x}", Sha256::digest(content.as_bytes()))
+}
+
+/// Find the Documents referencing a ConfigMap for their content
+///
+/// Used as the mapper for the ConfigMap watch so that content updates trigger reconciles.
+pub fn referencing_documents(
+    docs: impl IntoIterator<Item = Arc<Document>>,
+    cm: &ConfigMap,
+) -> Vec<ObjectRef<Document>> {
+    let (name, ns) = (cm.name_any(), cm.namespace());
+    docs.into_iter()
+        .filter(|doc| doc.namespace() == ns && doc.content_config_map() == Some(name.as_str()))
+        .map(|doc| ObjectRef::from_obj(&*doc))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ResolvedContent, hash};
+    use crate::{
+        ContentSource, ContentSourceStatus, Document, Error, KeyRef,
+        fixtures::{Scenario, timeout_after_1s},
+    };
+
+    fn url_doc(path: &std::path::Path) -> Document {
+        Document::test().with_content_from(ContentSource {
+            url: Some(format!("file://{}", path.display())),
+            ..ContentSource::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn url_content_is_read_and_hashed() {
+        let root = std::env::temp_dir().join(format!("doc-controller-content-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let path = root.join("url-content.md");
+        std::fs::write(&path, "# hello").unwrap();
+        let (client, _) = crate::fixtures::mock_client();
+        let resolved = url_doc(&path).resolve_content(client, Some(&root)).await.unwrap();
+        assert_eq!(resolved.content, "# hello");
+        assert_eq!(resolved.searchable(), "# hello");
+        let source = resolved.source.unwrap();
+        assert_eq!(source.kind, "Url");
+        assert_eq!(source.hash, hash("# hello"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn url_content_is_confined_to_the_content_root() {
+        let dir = std::env::temp_dir().join(format!("doc-controller-confined-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        let outside = dir.join("secret.md");
+        std::fs::write(&outside, "secret").unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("link.md")).unwrap();
+        let (client, _) = crate::fixtures::mock_client();
+
+        // disabled without a root
+        let err = url_doc(&outside)
+            .resolve_content(client.clone(), None)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::InvalidContentSource(_)), "{err}");
+        // outside the root, existing and absent files are rejected alike
+        let absent = dir.join("absent.md");
+        for path in [
+            outside.clone(),
+            absent,
+            root.join("link.md"),
+            root.join("../secret.md"),
+        ] {
+            let err = url_doc(&path)
+                .resolve_content(client.clone(), Some(&root))
+                .await
+                .err();
+            assert!(
+                matches!(err, Some(Error::InvalidContentSource(_))),
+                "{path:?}: {err:?}"
+            );
+        }
+        let err = url_doc(&root.join("missing.md"))
+            .resolve_content(client, Some(&root))
+            .await
+            .err();
+        assert!(matches!(err, Some(Error::MissingContentReference(_))), "{err:?}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn secret_content_is_not_hashed_into_status() {
+        let (client, fakeserver) = crate::fixtures::mock_client();
+        let doc = Document::test().with_content_from(ContentSource {
+            secret_key_ref: Some(KeyRef {
+                name: "creds".into(),
+                key: "doc".into(),
+            }),
+            ..ContentSource::default()
+        });
+        let mocksrv = fakeserver.run(Scenario::SecretFetch(
+            "creds".into(),
+            "doc".into(),
+            "password".into(),
+        ));
+        let resolved = doc.resolve_content(client, None).await.unwrap();
+        timeout_after_1s(mocksrv).await;
+        assert_eq!(resolved.content, "password");
+        let source = resolved.source.unwrap();
+        assert_eq!(source.kind, "Secret");
+        assert_eq!(source.hash, "");
+        assert_eq!(source.resource_version.as_deref(), Some("7"));
+    }
+
+    #[test]
+    fn secret_content_is_not_searchable() {
+        let resolved = ResolvedContent {
+            content: "password".into(),
+            source: Some(ContentSourceStatus {
+                kind: "Secret".into(),
+                name: "creds".into(),
+                resource_version: None,
+                hash: String::new(),
+            }),
+        };
+        assert_eq!(resolved.searchable(), "");
+    }
+
+    #[tokio::test]
+    async fn ambiguous_or_unsupported_sources_are_invalid() {
+        let (client, _) = crate::fixtures::mock_client();
+        let doc = Document::test().with_content_from(ContentSource::default());
+        let err = doc.resolve_content(client.clone(), None).await.err().unwrap();
+        assert!(matches!(err, Error::InvalidContentSource(_)));
+
+        let doc = Document::test().with_content_from(ContentSource {
+            url: Some("https://example.com/doc.md".into()),
+            ..ContentSource::default()
+        });
+        let err = doc.resolve_content(client, None).await.err().unwrap();
+        assert!(matches!(err, Error::InvalidContentSource(_)));
+    }
+}
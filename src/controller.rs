@@ -1,26 +1,165 @@
-use crate::{Error, Metrics, Result, telemetry};
-use futures::StreamExt;
+use crate::{
+    Error, ErrorCategory, Metrics, Result,
+    cache::{DocumentCache, WatchConfig, WatchMode},
+    changes::ChangeFeed,
+    cluster::{ClusterConfig, ClusterError},
+    config::{Config as Settings, Reloadable},
+    content,
+    diagnostics::{DocumentDiagnostics, DocumentDiagnosticsMap, DocumentsSummary, ReconcileResult},
+    events::{self, EventConfig, EventPublisher},
+    notify::{Notifier, NotifierConfig, Transition},
+    pod::PodInfo,
+    predicates::{self, PredicateConfig},
+    search::{Hit, MAX_LIMIT, SearchIndex},
+    shard::{ShardConfig, ShardInfo, Sharder},
+    source,
+    sweep::Sweeper,
+    telemetry,
+    trigger::Triggers,
+};
+use futures::{StreamExt, TryStreamExt, channel::mpsc};
 use jiff::Timestamp;
+use k8s_openapi::{
+    NamespaceResourceScope,
+    api::core::v1::ConfigMap,
+    apimachinery::pkg::apis::meta::v1::{Condition, Time},
+};
 use kube::{
     CustomResource, Resource,
-    api::{Api, ListParams, Patch, PatchParams, ResourceExt},
+    api::{Api, ListParams, PartialObjectMeta, Patch, PatchParams, ResourceExt},
     client::Client,
     runtime::{
+        WatchStreamExt,
         controller::{Action, Controller},
         events::{Event, EventType, Recorder, Reporter},
         finalizer::{Event as Finalizer, finalizer},
+        metadata_watcher,
+        reflector::{self, ObjectRef},
+        watcher,
         watcher::Config,
     },
 };
+use opentelemetry::trace::TraceId;
 use schemars::JsonSchema;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use serde_json::json;
-use std::sync::Arc;
-use tokio::{sync::RwLock, time::Duration};
+use std::{
+    path::PathBuf,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+use tokio::{
+    sync::{OwnedSemaphorePermit, RwLock, Semaphore},
+    time::{Duration, Instant},
+};
 use tracing::*;
 
 pub static DOCUMENT_FINALIZER: &str = "documents.kube.rs";
 
+/// Field manager of server-side applies made by the crate
+pub static FIELD_MANAGER: &str = "cntrlr";
+
+/// Annotation that pauses reconciliation of a Document when set to `"true"`
+pub static PAUSED_ANNOTATION: &str = "kube.rs/paused";
+
+/// Reconciler settings
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct ControllerConfig {
+    /// Namespaces to watch Documents in; all namespaces when empty
+    pub namespaces: Vec<String>,
+    /// Max number of reconciles running at once; unbounded when 0
+    pub concurrency: u16,
+    /// Pause all reconciliation (also toggled through the admin API)
+    pub paused: bool,
+    /// How often Documents are reconciled without changes (and retried after permanent errors)
+    #[serde(with = "crate::config::duration")]
+    pub requeue_interval: Duration,
+    /// How soon Documents are retried after retryable errors
+    #[serde(with = "crate::config::duration")]
+    pub retry_interval: Duration,
+    /// How often globally paused Documents are checked for a resume
+    #[serde(with = "crate::config::duration")]
+    pub paused_requeue_interval: Duration,
+    /// Name of the controller on published events
+    pub reporter: String,
+    /// Rules Documents have to follow
+    pub validation: ValidationPolicy,
+    /// Directory `file://` content urls have to point into; file urls are rejected when unset
+    pub content_root: Option<PathBuf>,
+}
+
+impl Default for ControllerConfig {
+    fn default() -> Self {
+        Self {
+            namespaces: vec![],
+            concurrency: 0,
+            paused: false,
+            requeue_interval: Duration::from_secs(5 * 60),
+            retry_interval: Duration::from_secs(15),
+            paused_requeue_interval: Duration::from_secs(30),
+            reporter: "doc-controller".into(),
+            validation: ValidationPolicy::default(),
+            content_root: None,
+        }
+    }
+}
+
+/// Rules Documents have to follow to be reconciled
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct ValidationPolicy {
+    /// Names of Documents rejected as illegal
+    pub denied_names: Vec<String>,
+    /// Max size of the resolved content in bytes; unlimited when unset
+    pub max_content_bytes: Option<usize>,
+}
+
+impl Default for ValidationPolicy {
+    fn default() -> Self {
+        Self {
+            denied_names: vec!["illegal".into()],
+            max_content_bytes: None,
+        }
+    }
+}
+
+impl ValidationPolicy {
+    fn check(&self, doc: &Document, content: &str) -> Result<()> {
+        if self.denied_names.contains(&doc.name_any()) {
+            return Err(Error::IllegalDocument); // error names show up in metrics
+        }
+        match self.max_content_bytes {
+            Some(max) if content.len() > max => Err(Error::ContentTooLarge(content.len())),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Bounds the number of reconciles running at once; the bound can change at runtime
+///
+/// Reconciles that already hold a permit finish under the bound they started with.
+#[derive(Clone, Default)]
+pub struct ConcurrencyLimit(Arc<std::sync::RwLock<(u16, Option<Arc<Semaphore>>)>>);
+
+impl ConcurrencyLimit {
+    /// Change the bound (0 for unbounded)
+    pub fn set(&self, limit: u16) {
+        let mut current = self.0.write().unwrap();
+        if current.0 != limit {
+            *current = (limit, (limit > 0).then(|| Arc::new(Semaphore::new(limit.into()))));
+        }
+    }
+
+    /// Wait for a permit to reconcile (none is needed when unbounded)
+    pub async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        let semaphore = self.0.read().unwrap().1.clone()?;
+        semaphore.acquire_owned().await.ok()
+    }
+}
+
 /// Generate the Kubernetes wrapper struct `Document` from our Spec and Status struct
 ///
 /// This provides a hook for generating the CRD yaml (in crdgen.rs)
@@ -30,21 +169,190 @@ pub static DOCUMENT_FINALIZER: &str = "documents.kube.rs";
 #[cfg_attr(test, derive(Default))]
 #[kube(kind = "Document", group = "kube.rs", version = "v1", namespaced)]
 #[kube(status = "DocumentStatus", shortname = "doc")]
+#[serde(rename_all = "camelCase")]
 pub struct DocumentSpec {
     pub title: String,
     pub hide: bool,
+    /// Inline markdown content (ignored when `contentFrom` is set)
+    #[serde(default)]
     pub content: String,
+    /// Reference to content stored outside the Document
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_from: Option<ContentSource>,
 }
-/// The status object of `Document`
+
+/// External location of a Document's content
+///
+/// Exactly one of the fields must be set.
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ContentSource {
+    /// A key in a ConfigMap in the Document's namespace
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_map_key_ref: Option<KeyRef>,
+    /// A key in a Secret in the Document's namespace
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_key_ref: Option<KeyRef>,
+    /// A `file://` url readable by the controller
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+/// Selects a key from a ConfigMap or Secret
 #[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+pub struct KeyRef {
+    pub name: String,
+    pub key: String,
+}
+
+/// The status object of `Document`
+#[derive(Deserialize, Serialize, Clone, Default, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct DocumentStatus {
+    #[serde(default)]
     pub hidden: bool,
+    /// The generation of the Document last reconciled successfully
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
+    /// The resolved external content source (when `contentFrom` is used)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_source: Option<ContentSourceStatus>,
+    /// Conditions of the Document; `Ready`, and `Paused` while paused by annotation
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<Condition>,
+}
+
+/// Version information about the last resolved content source
+#[derive(Deserialize, Serialize, Clone, Default, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ContentSourceStatus {
+    /// Kind of source; ConfigMap, Secret or Url
+    pub kind: String,
+    /// Name of the object or the url
+    pub name: String,
+    /// The resourceVersion of the ConfigMap or Secret
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resource_version: Option<String>,
+    /// Sha256 hash of the resolved content, unset for Secrets (tracked by their resourceVersion)
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub hash: String,
 }
 
 impl Document {
     fn was_hidden(&self) -> bool {
         self.status.as_ref().map(|s| s.hidden).unwrap_or(false)
     }
+
+    /// Lifecycle transitions a successful reconcile of this Document announces
+    ///
+    /// Failure, pause and deletion conditions also write the status, so creation is detected through the
+    /// `observedGeneration` only a successful reconcile sets.
+    fn transitions(&self) -> Vec<Transition> {
+        let mut transitions = vec![];
+        if self.status.as_ref().and_then(|s| s.observed_generation).is_none() {
+            transitions.push(Transition::Created);
+        }
+        match (self.was_hidden(), self.spec.hide) {
+            (true, false) => transitions.push(Transition::Unhidden),
+            (false, true) => transitions.push(Transition::Hidden),
+            _ => {}
+        }
+        transitions
+    }
+
+    /// The Ready condition, keeping the previous transition time if readiness did not change
+    fn ready_condition(&self, ready: bool, reason: &str, message: String) -> Condition {
+        self.condition("Ready", ready, reason, message)
+    }
+
+    /// Whether reconciliation is paused through the `kube.rs/paused` annotation
+    pub fn is_paused(&self) -> bool {
+        self.annotations()
+            .get(PAUSED_ANNOTATION)
+            .is_some_and(|v| v == "true")
+    }
+
+    /// Record a pause through the Paused condition (unless already recorded)
+    ///
+    /// The condition is dropped by the next status write after the annotation is removed.
+    async fn record_paused(&self, client: Client) -> Result<()> {
+        let message = format!("reconciliation paused by the {PAUSED_ANNOTATION} annotation");
+        let Some(patch) = self.condition_patch("Paused", true, "PauseAnnotation", message) else {
+            return Ok(());
+        };
+        let docs: Api<Document> = Api::namespaced(client, &self.namespace().unwrap());
+        docs.patch_status(&self.name_any(), &PatchParams::default(), &patch)
+            .await
+            .map_err(Error::KubeError)?;
+        Ok(())
+    }
+}
+
+/// The conditions with one of them replaced, or `None` when it is already set with the same status, reason and message
+fn set_condition(conditions: &[Condition], condition: Condition) -> Option<Vec<Condition>> {
+    let unchanged = conditions.iter().any(|c| {
+        c.type_ == condition.type_
+            && c.status == condition.status
+            && c.reason == condition.reason
+            && c.message == condition.message
+    });
+    if unchanged {
+        return None;
+    }
+    let mut conditions: Vec<Condition> = conditions
+        .iter()
+        .filter(|c| c.type_ != condition.type_)
+        .cloned()
+        .collect();
+    conditions.push(condition);
+    Some(conditions)
+}
+
+impl HasConditions for Document {
+    fn conditions(&self) -> &[Condition] {
+        self.status
+            .as_ref()
+            .map(|s| s.conditions.as_slice())
+            .unwrap_or_default()
+    }
+}
+
+/// Resources reporting their state through status conditions
+pub(crate) trait HasConditions: Resource {
+    fn conditions(&self) -> &[Condition];
+
+    /// A condition, keeping the previous transition time if its status did not change
+    fn condition(&self, type_: &str, value: bool, reason: &str, message: String) -> Condition {
+        let status = if value { "True" } else { "False" };
+        let previous = self.conditions().iter().find(|c| c.type_ == type_);
+        let last_transition_time = match previous {
+            Some(c) if c.status == status => c.last_transition_time.clone(),
+            _ => Time(Timestamp::now()),
+        };
+        Condition {
+            type_: type_.into(),
+            status: status.into(),
+            reason: reason.into(),
+            message,
+            last_transition_time,
+            observed_generation: self.meta().generation,
+        }
+    }
+
+    /// A merge patch setting one condition, or `None` when it is already set with the same status, reason and message
+    ///
+    /// Merge patches leave the remaining status owned by the reconcilers' apply patches.
+    fn condition_patch(
+        &self,
+        type_: &str,
+        value: bool,
+        reason: &str,
+        message: String,
+    ) -> Option<Patch<serde_json::Value>> {
+        let condition = self.condition(type_, value, reason, message);
+        let conditions = set_condition(self.conditions(), condition)?;
+        Some(Patch::Merge(json!({ "status": { "conditions": conditions } })))
+    }
 }
 
 // Context for our reconciler
@@ -52,55 +360,228 @@ impl Document {
 pub struct Context {
     /// Kubernetes client
     pub client: Client,
-    /// Event recorder
-    pub recorder: Recorder,
+    /// Event publisher
+    pub events: EventPublisher,
     /// Diagnostics read by the web server
     pub diagnostics: Arc<RwLock<Diagnostics>>,
     /// Prometheus metrics
     pub metrics: Arc<Metrics>,
+    /// Webhook notifier for lifecycle transitions
+    pub notifier: Notifier,
+    /// Full Documents fetched for metadata-only watches
+    pub cache: DocumentCache,
+    /// Which Documents this replica reconciles
+    pub sharder: Sharder,
+    /// Global pause of all reconciliation
+    pub paused: Arc<AtomicBool>,
+    /// Outcomes of the latest reconciles per Document
+    pub documents: DocumentDiagnosticsMap,
+    /// Full-text index of non-hidden Documents
+    pub search: SearchIndex,
+    /// Reconciler settings
+    pub config: Reloadable<ControllerConfig>,
+    /// Bound on concurrent reconciles
+    pub concurrency: ConcurrencyLimit,
+    /// Requested reconciles, told when reconciles start
+    pub triggers: Triggers,
+}
+
+impl Context {
+    /// Report a failed reconcile through a Warning event and the Ready condition in the background
+    ///
+    /// Repeated events are deduplicated and rate limited by the `EventPublisher`.
+    pub(crate) fn report_failure<K>(&self, obj: &K, error: &Error, action: &str)
+    where
+        K: HasConditions<DynamicType = (), Scope = NamespaceResourceScope>,
+        K: Clone + DeserializeOwned + std::fmt::Debug + Send + 'static,
+    {
+        let note = events::truncate_note(error.to_string());
+        let ev = Event {
+            type_: EventType::Warning,
+            reason: error.event_reason().into(),
+            note: Some(note.clone()),
+            action: action.into(),
+            secondary: None,
+        };
+        let message = format!("{note} ({})", error.category().as_str());
+        // unchanged conditions are not written again, e.g. on every retry after a conflict
+        let patch = obj.condition_patch("Ready", false, error.event_reason(), message);
+        let oref = obj.object_ref(&());
+        let api: Api<K> = Api::namespaced(self.client.clone(), &obj.namespace().unwrap());
+        let (events, name) = (self.events.clone(), obj.name_any());
+        tokio::spawn(async move {
+            events.publish(&ev, &oref).await;
+            let Some(patch) = patch else { return };
+            if let Err(e) = api.patch_status(&name, &PatchParams::default(), &patch).await {
+                warn!("failed to set Ready condition on {name}: {e}");
+            }
+        });
+    }
 }
 
 #[instrument(skip(ctx, doc), fields(trace_id))]
 async fn reconcile(doc: Arc<Document>, ctx: Arc<Context>) -> Result<Action> {
+    let oref = ObjectRef::from_obj(&*doc);
+    ctx.triggers.start(&oref);
+    if !ctx.sharder.owns(&*doc) {
+        ctx.metrics.shard.skipped.inc();
+        // ownership moved away or never was ours; the owner indexes it
+        ctx.search.remove(&*doc);
+        return Ok(Action::await_change()); // reconciled again on rebalance
+    }
     let trace_id = telemetry::get_trace_id();
-    if trace_id != opentelemetry::trace::TraceId::INVALID {
+    if trace_id != TraceId::INVALID {
         Span::current().record("trace_id", field::display(&trace_id));
     }
+    let _permit = ctx.concurrency.acquire().await;
+    let start = Instant::now();
+    let paused = ctx.paused.load(Ordering::Relaxed) || doc.is_paused();
+    if paused {
+        ctx.triggers.pause(&oref);
+    }
+    let deleted = !paused && doc.meta().deletion_timestamp.is_some();
+    let res = if paused {
+        reconcile_paused(&doc, &ctx).await
+    } else {
+        reconcile_finalized(doc, ctx.clone(), trace_id).await
+    };
+    let result = res.as_ref().map(|_| match paused {
+        true => ReconcileResult::Paused,
+        false => ReconcileResult::Success,
+    });
+    if res.is_ok() && deleted {
+        // the finalizer is removed, so nothing is recorded for the deleted Document
+        ctx.documents.remove(&oref);
+    } else {
+        let next_requeue = res.as_ref().ok().and_then(|r| r.delay);
+        ctx.documents
+            .record(oref, start.elapsed(), result, next_requeue, trace_id);
+    }
+    res.map(|r| r.action)
+}
+
+/// An `Action` with its requeue delay, which `Action` does not expose
+pub(crate) struct Requeue {
+    action: Action,
+    delay: Option<Duration>,
+}
+
+impl Requeue {
+    fn after(delay: Duration) -> Self {
+        Self {
+            action: Action::requeue(delay),
+            delay: Some(delay),
+        }
+    }
+
+    fn await_change() -> Self {
+        Self {
+            action: Action::await_change(),
+            delay: None,
+        }
+    }
+}
+
+async fn reconcile_paused(doc: &Document, ctx: &Context) -> Result<Requeue> {
+    if ctx.paused.load(Ordering::Relaxed) {
+        ctx.metrics.reconcile.set_paused_skip("global");
+        return Ok(Requeue::after(ctx.config.get().paused_requeue_interval));
+    }
+    ctx.metrics.reconcile.set_paused_skip("document");
+    doc.record_paused(ctx.client.clone()).await?;
+    Ok(Requeue::await_change()) // removing the annotation triggers a reconcile
+}
+
+async fn reconcile_finalized(doc: Arc<Document>, ctx: Arc<Context>, trace_id: TraceId) -> Result<Requeue> {
     let _timer = ctx.metrics.reconcile.count_and_measure(&trace_id);
     ctx.diagnostics.write().await.last_event = Timestamp::now();
     let ns = doc.namespace().unwrap(); // doc is namespace scoped
     let docs: Api<Document> = Api::namespaced(ctx.client.clone(), &ns);
 
     info!("Reconciling Document \"{}\" in {}", doc.name_any(), ns);
-    finalizer(&docs, DOCUMENT_FINALIZER, doc, |event| async {
-        match event {
-            Finalizer::Apply(doc) => doc.reconcile(ctx.clone()).await,
-            Finalizer::Cleanup(doc) => doc.cleanup(ctx.clone()).await,
+    // the finalizer only passes on the `Action`, and returns its own when adding the finalizer
+    let delay = OnceLock::new();
+    let action = finalizer(&docs, DOCUMENT_FINALIZER, doc, |event| async {
+        let requeue = match event {
+            Finalizer::Apply(doc) => doc.reconcile(ctx.clone()).await?,
+            Finalizer::Cleanup(doc) => doc.cleanup(ctx.clone()).await?,
+        };
+        if let Some(d) = requeue.delay {
+            let _ = delay.set(d);
         }
+        Ok::<_, Error>(requeue.action)
     })
     .await
-    .map_err(|e| Error::FinalizerError(Box::new(e)))
+    .map_err(|e| Error::FinalizerError(Box::new(e)))?;
+    Ok(Requeue {
+        action,
+        delay: delay.into_inner(),
+    })
 }
 
 fn error_policy(doc: Arc<Document>, error: &Error, ctx: Arc<Context>) -> Action {
     warn!("reconcile failed: {:?}", error);
-    ctx.metrics.reconcile.set_failure(&doc, error);
-    Action::requeue(Duration::from_secs(5 * 60))
+    ctx.metrics.reconcile.set_failure(&*doc, error);
+    ctx.report_failure(&*doc, error, "Reconciling");
+    let requeue = failure_requeue(error, &ctx.config.get());
+    ctx.documents.record_retry(&ObjectRef::from_obj(&*doc), requeue);
+    Action::requeue(requeue)
+}
+
+fn failure_requeue(error: &Error, config: &ControllerConfig) -> Duration {
+    match error.category() {
+        ErrorCategory::Conflict => Duration::from_secs(1),
+        ErrorCategory::Retryable => config.retry_interval,
+        ErrorCategory::Permanent => config.requeue_interval,
+    }
+}
+
+/// Reconcile a Document watched through its metadata, fetching the full object through the cache
+async fn reconcile_metadata(meta: Arc<PartialObjectMeta<Document>>, ctx: Arc<Context>) -> Result<Action> {
+    ctx.triggers
+        .start(&ObjectRef::new(&meta.name_any()).within(&meta.namespace().unwrap()));
+    if !ctx.sharder.owns(&*meta) {
+        ctx.metrics.shard.skipped.inc();
+        ctx.search.remove(&*meta);
+        return Ok(Action::await_change());
+    }
+    match ctx.cache.get(ctx.client.clone(), &meta).await? {
+        Some(doc) => reconcile(doc, ctx).await,
+        None => Ok(Action::await_change()), // deleted since the watch event
+    }
+}
+
+fn error_policy_metadata(meta: Arc<PartialObjectMeta<Document>>, error: &Error, ctx: Arc<Context>) -> Action {
+    warn!("reconcile failed: {:?}", error);
+    ctx.metrics.reconcile.set_failure(&*meta, error);
+    let oref = ObjectRef::new(&meta.name_any()).within(&meta.namespace().unwrap());
+    // failures to fetch the Document leave nothing to report on
+    if let Some(doc) = ctx.cache.cached(&oref) {
+        ctx.report_failure(&*doc, error, "Reconciling");
+    }
+    let requeue = failure_requeue(error, &ctx.config.get());
+    ctx.documents.record_retry(&oref, requeue);
+    Action::requeue(requeue)
 }
 
 impl Document {
     // Reconcile (for non-finalizer related changes)
-    async fn reconcile(&self, ctx: Arc<Context>) -> Result<Action> {
+    async fn reconcile(&self, ctx: Arc<Context>) -> Result<Requeue> {
         let client = ctx.client.clone();
         let oref = self.object_ref(&());
         let ns = self.namespace().unwrap();
         let name = self.name_any();
-        let docs: Api<Document> = Api::namespaced(client, &ns);
+        let docs: Api<Document> = Api::namespaced(client.clone(), &ns);
 
+        let config = ctx.config.get();
+        let content = self
+            .resolve_content(client, config.content_root.as_deref())
+            .await?;
         let should_hide = self.spec.hide;
-        if !self.was_hidden() && should_hide {
+        let transitions = self.transitions();
+        if transitions.contains(&Transition::Hidden) {
             // send an event once per hide
-            ctx.recorder
+            ctx.events
                 .publish(
                     &Event {
                         type_: EventType::Normal,
@@ -111,48 +592,75 @@ impl Document {
                     },
                     &oref,
                 )
+                .await;
+        }
+        if let Err(e) = config.validation.check(self, &content.content) {
+            ctx.search.remove(self);
+            return Err(e);
+        }
+        let desired = DocumentStatus {
+            hidden: should_hide,
+            observed_generation: self.metadata.generation,
+            content_source: content.source.clone(),
+            conditions: vec![self.ready_condition(true, "Reconciled", String::new())],
+        };
+        // only write the status when it differs from what we observed
+        if self.status.as_ref() == Some(&desired) {
+            ctx.metrics.reconcile.set_status_write("skipped");
+        } else {
+            let new_status = Patch::Apply(json!({
+                "apiVersion": "kube.rs/v1",
+                "kind": "Document",
+                "status": desired
+            }));
+            let ps = PatchParams::apply(FIELD_MANAGER).force();
+            let _o = docs
+                .patch_status(&name, &ps, &new_status)
                 .await
                 .map_err(Error::KubeError)?;
+            ctx.metrics.reconcile.set_status_write("applied");
         }
-        if name == "illegal" {
-            return Err(Error::IllegalDocument); // error names show up in metrics
+        ctx.search.upsert(self, content.searchable());
+        // notify once the transition has been recorded in the status
+        for transition in transitions {
+            ctx.notifier.notify(self, transition);
         }
-        // always overwrite status object with what we saw
-        let new_status = Patch::Apply(json!({
-            "apiVersion": "kube.rs/v1",
-            "kind": "Document",
-            "status": DocumentStatus {
-                hidden: should_hide,
-            }
-        }));
-        let ps = PatchParams::apply("cntrlr").force();
-        let _o = docs
-            .patch_status(&name, &ps, &new_status)
-            .await
-            .map_err(Error::KubeError)?;
 
-        // If no events were received, check back every 5 minutes
-        Ok(Action::requeue(Duration::from_secs(5 * 60)))
+        // If no events were received, check back after the requeue interval
+        Ok(Requeue::after(config.requeue_interval))
     }
 
     // Finalizer cleanup (the object was deleted, ensure nothing is orphaned)
-    async fn cleanup(&self, ctx: Arc<Context>) -> Result<Action> {
+    //
+    // Every step is idempotent so that a failed cleanup can be retried from the start.
+    // The Deleting condition reports progress while the deletion timestamp is set,
+    // and ensures the deletion is only announced once across retries.
+    pub(crate) async fn cleanup(&self, ctx: Arc<Context>) -> Result<Requeue> {
         let oref = self.object_ref(&());
-        // Document doesn't have any real cleanup, so we just publish an event
-        ctx.recorder
-            .publish(
-                &Event {
-                    type_: EventType::Normal,
-                    reason: "DeleteRequested".into(),
-                    note: Some(format!("Delete `{}`", self.name_any())),
-                    action: "Deleting".into(),
-                    secondary: None,
-                },
-                &oref,
-            )
-            .await
-            .map_err(Error::KubeError)?;
-        Ok(Action::await_change())
+        let message = "removing the search index entry and cached copy".to_string();
+        if let Some(patch) = self.condition_patch("Deleting", true, "CleaningUp", message) {
+            let docs: Api<Document> = Api::namespaced(ctx.client.clone(), &self.namespace().unwrap());
+            docs.patch_status(&self.name_any(), &PatchParams::default(), &patch)
+                .await
+                .map_err(Error::KubeError)?;
+            ctx.events
+                .publish(
+                    &Event {
+                        type_: EventType::Normal,
+                        reason: "DeleteRequested".into(),
+                        note: Some(format!("Delete `{}`", self.name_any())),
+                        action: "Deleting".into(),
+                        secondary: None,
+                    },
+                    &oref,
+                )
+                .await;
+            ctx.notifier.notify(self, Transition::Deleted);
+        }
+        // the controller creates no objects for Documents, only in-memory artifacts
+        ctx.search.remove(self);
+        ctx.cache.remove(&ObjectRef::from_obj(self));
+        Ok(Requeue::await_change())
     }
 }
 
@@ -163,12 +671,30 @@ pub struct Diagnostics {
     pub last_event: Timestamp,
     #[serde(skip)]
     pub reporter: Reporter,
+    /// Whether all reconciliation is paused
+    pub paused: bool,
+    /// Documents with recorded reconciles
+    pub documents: DocumentsSummary,
+    /// Shard of this replica (when sharding is enabled)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shard: Option<ShardInfo>,
+    /// Name of the cluster when several clusters are reconciled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cluster: Option<String>,
+    /// Diagnostics of each cluster when several clusters are reconciled
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub clusters: Vec<Diagnostics>,
 }
 impl Default for Diagnostics {
     fn default() -> Self {
         Self {
             last_event: Timestamp::now(),
             reporter: "doc-controller".into(),
+            paused: false,
+            documents: DocumentsSummary::default(),
+            shard: None,
+            cluster: None,
+            clusters: vec![],
         }
     }
 }
@@ -179,16 +705,276 @@ impl Diagnostics {
 }
 
 /// State shared between the controller and the web server
-#[derive(Clone, Default)]
+#[derive(Clone)]
 pub struct State {
     /// Diagnostics populated by the reconciler
     diagnostics: Arc<RwLock<Diagnostics>>,
     /// Metrics
     metrics: Arc<Metrics>,
+    /// Active configuration
+    config: Reloadable<Settings>,
+    /// Reconciler settings
+    controller: Reloadable<ControllerConfig>,
+    /// Bound on concurrent reconciles
+    concurrency: ConcurrencyLimit,
+    /// Webhook notification settings
+    notifications: Reloadable<NotifierConfig>,
+    /// Which watch events cause reconciles
+    predicates: PredicateConfig,
+    /// How Documents are watched and cached
+    watch: WatchConfig,
+    /// Which Documents this replica reconciles
+    sharder: Sharder,
+    /// Global pause of all reconciliation
+    paused: Arc<AtomicBool>,
+    /// Reconciles requested through the web server
+    triggers: Triggers,
+    /// Outcomes of the latest reconciles per Document
+    documents: DocumentDiagnosticsMap,
+    /// Full-text index of non-hidden Documents
+    search: SearchIndex,
+    /// Changes to Documents seen by the watch
+    changes: ChangeFeed,
+    /// Cluster reconciled with this state, when several clusters are reconciled
+    cluster: Option<ClusterConfig>,
+    /// States of the reconciled clusters, when there are several
+    clusters: Vec<State>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        let metrics = Arc::<Metrics>::default();
+        Self {
+            search: SearchIndex::new(WatchConfig::default().search_capacity, metrics.search.clone()),
+            metrics,
+            diagnostics: Arc::default(),
+            config: Reloadable::default(),
+            controller: Reloadable::default(),
+            concurrency: ConcurrencyLimit::default(),
+            notifications: Reloadable::default(),
+            predicates: PredicateConfig::default(),
+            watch: WatchConfig::default(),
+            sharder: Sharder::default(),
+            paused: Arc::default(),
+            triggers: Triggers::default(),
+            documents: DocumentDiagnosticsMap::default(),
+            changes: ChangeFeed::default(),
+            cluster: None,
+            clusters: vec![],
+        }
+    }
 }
 
 /// State wrapper around the controller outputs for the web server
 impl State {
+    /// Configure all parts of the controller
+    pub fn with_config(self, config: Settings) -> Self {
+        let state = self
+            .with_controller(config.controller.clone())
+            .with_notifications(config.notifications.clone())
+            .with_predicates(config.predicates.clone())
+            .with_watch(config.watch.clone())
+            .with_sharding(config.sharding.clone())
+            .with_clusters(config.clusters.clone());
+        state.config.set(config);
+        state
+    }
+
+    /// Configure the reconciler
+    pub fn with_controller(mut self, config: ControllerConfig) -> Self {
+        let diagnostics = Diagnostics {
+            reporter: config.reporter.as_str().into(),
+            ..Diagnostics::default()
+        };
+        self.diagnostics = Arc::new(RwLock::new(diagnostics));
+        self.concurrency.set(config.concurrency);
+        self.set_paused(config.paused);
+        self.controller = config.into();
+        self
+    }
+
+    /// Configure webhook notifications for lifecycle transitions
+    pub fn with_notifications(mut self, config: NotifierConfig) -> Self {
+        self.notifications = config.into();
+        self
+    }
+
+    /// Apply a changed configuration at runtime
+    ///
+    /// Only the reconciler settings (except its namespaces and reporter) and notifications take effect; the remaining
+    /// settings are recorded as given but need a restart. A change of `controller.paused` overrides the pause
+    /// state set through the admin API.
+    pub fn apply(&self, config: Settings) {
+        if self.config.get().controller.paused != config.controller.paused {
+            self.set_paused(config.controller.paused);
+        }
+        self.concurrency.set(config.controller.concurrency);
+        self.controller.set(config.controller.clone());
+        self.notifications.set(config.notifications.clone());
+        self.config.set(config);
+    }
+
+    /// Active configuration getter
+    pub fn config(&self) -> Arc<Settings> {
+        self.config.get()
+    }
+
+    /// Configure which changes to Documents cause reconciles
+    pub fn with_predicates(mut self, config: PredicateConfig) -> Self {
+        self.predicates = config;
+        self
+    }
+
+    /// Configure how Documents are watched
+    pub fn with_watch(mut self, config: WatchConfig) -> Self {
+        self.search = SearchIndex::new(config.search_capacity, self.metrics.search.clone());
+        self.watch = config;
+        self
+    }
+
+    /// Split Documents between replicas
+    pub fn with_sharding(mut self, config: ShardConfig) -> Self {
+        self.sharder = Sharder::new(config, self.metrics.shard.clone());
+        self
+    }
+
+    /// Reconcile several clusters, each with its own client, metrics, diagnostics and requests
+    ///
+    /// Every cluster gets a `State` sharing the configuration, pause state and change feed, and its metrics are
+    /// labelled with its name. The web server reads the aggregate through this `State`.
+    pub fn with_clusters(mut self, clusters: Vec<ClusterConfig>) -> Self {
+        let names: Vec<_> = clusters.iter().map(|c| c.name.clone()).collect();
+        let states: Vec<_> = clusters
+            .into_iter()
+            .zip(Metrics::for_clusters(&names))
+            .map(|(cluster, metrics)| {
+                let metrics = Arc::new(metrics);
+                metrics
+                    .reconcile
+                    .paused
+                    .set(self.paused.load(Ordering::Relaxed).into());
+                let diagnostics = Diagnostics {
+                    reporter: self.controller.get().reporter.as_str().into(),
+                    ..Diagnostics::default()
+                };
+                State {
+                    diagnostics: Arc::new(RwLock::new(diagnostics)),
+                    sharder: self.sharder.with_metrics(metrics.shard.clone()),
+                    triggers: Triggers::default(),
+                    documents: DocumentDiagnosticsMap::default(),
+                    search: SearchIndex::new(self.watch.search_capacity, metrics.search.clone()),
+                    changes: self.changes.for_cluster(&cluster.name),
+                    cluster: Some(cluster),
+                    clusters: vec![],
+                    metrics,
+                    ..self.clone()
+                }
+            })
+            .collect();
+        // the registry of the clusters is served from here
+        if let Some(first) = states.first() {
+            self.metrics = first.metrics.clone();
+        }
+        self.clusters = states;
+        self
+    }
+
+    /// States of the reconciled clusters when there are several
+    pub fn clusters(&self) -> &[State] {
+        &self.clusters
+    }
+
+    /// Name of the cluster reconciled with this state, when several clusters are reconciled
+    pub fn cluster_name(&self) -> Option<String> {
+        self.cluster.as_ref().map(|c| c.name.clone())
+    }
+
+    /// The state of a named cluster, or this state when there is only one cluster
+    pub fn cluster(&self, name: Option<&str>) -> Result<&State, ClusterError> {
+        match name {
+            None if self.clusters.is_empty() => Ok(self),
+            None => Err(ClusterError::Required),
+            Some(name) => self
+                .clusters
+                .iter()
+                .find(|c| c.cluster.as_ref().is_some_and(|c| c.name == name))
+                .ok_or_else(|| ClusterError::Unknown(name.into())),
+        }
+    }
+
+    /// Client of the cluster reconciled with this state (the default client without several clusters)
+    pub async fn client(&self) -> anyhow::Result<Client> {
+        match &self.cluster {
+            Some(cluster) => cluster.client().await,
+            None => Ok(Client::try_default().await?),
+        }
+    }
+
+    /// Sweeper of the finalizers of the Documents in this state's cluster
+    pub async fn sweeper(&self, client: Client) -> Sweeper {
+        Sweeper::new(client.clone(), self.config.get().finalizers.clone())
+            .with_namespaces(self.controller.get().namespaces.clone())
+            .with_cluster(self.cluster_name())
+            .with_context(self.to_context(client).await)
+    }
+
+    /// Whether all reconciliation is paused
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Pause or resume all reconciliation
+    ///
+    /// Paused Documents are picked up again within the paused requeue interval of resuming.
+    pub fn set_paused(&self, paused: bool) {
+        if self.paused.swap(paused, Ordering::Relaxed) != paused {
+            info!("reconciliation {}", if paused { "paused" } else { "resumed" });
+        }
+        self.metrics.reconcile.paused.set(paused.into());
+        for cluster in &self.clusters {
+            cluster.metrics.reconcile.paused.set(paused.into());
+        }
+    }
+
+    /// Diagnostics of the latest reconcile of a Document
+    pub fn document_diagnostics(&self, namespace: &str, name: &str) -> Option<DocumentDiagnostics> {
+        self.documents.get(&ObjectRef::new(name).within(namespace))
+    }
+
+    /// Non-hidden Documents matching a query, best first (across all clusters)
+    pub fn search(&self, query: &str, namespace: Option<&str>, limit: usize) -> Vec<Hit> {
+        if self.clusters.is_empty() {
+            let mut hits = self.search.search(query, namespace, limit);
+            for hit in &mut hits {
+                hit.cluster = self.cluster_name();
+            }
+            return hits;
+        }
+        let mut hits: Vec<_> = self
+            .clusters
+            .iter()
+            .flat_map(|c| c.search(query, namespace, limit))
+            .collect();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+        hits.truncate(limit.min(MAX_LIMIT));
+        hits
+    }
+
+    /// Shard identity and members when sharding is enabled
+    pub fn shard(&self) -> Option<ShardInfo> {
+        self.sharder.info()
+    }
+
+    /// Document changes getter
+    pub fn changes(&self) -> &ChangeFeed {
+        &self.changes
+    }
+
+    /// Reconcile requests getter
+    pub fn triggers(&self) -> &Triggers {
+        &self.triggers
+    }
+
     /// Metrics getter
     pub fn metrics(&self) -> String {
         let mut buffer = String::new();
@@ -197,53 +983,271 @@ impl State {
         buffer
     }
 
-    /// State getter
+    /// State getter, summing up the clusters when there are several
     pub async fn diagnostics(&self) -> Diagnostics {
-        self.diagnostics.read().await.clone()
+        if self.clusters.is_empty() {
+            return self.cluster_diagnostics().await;
+        }
+        let mut diagnostics = self.diagnostics.read().await.clone();
+        diagnostics.paused = self.paused.load(Ordering::Relaxed);
+        for cluster in &self.clusters {
+            let d = cluster.cluster_diagnostics().await;
+            let name = d.cluster.clone().unwrap_or_default();
+            diagnostics.last_event = diagnostics.last_event.max(d.last_event);
+            diagnostics.documents.tracked += d.documents.tracked;
+            let failing = d.documents.failing.iter().map(|doc| format!("{name}/{doc}"));
+            diagnostics.documents.failing.extend(failing);
+            diagnostics.clusters.push(d);
+        }
+        diagnostics.documents.failing.sort();
+        diagnostics
+    }
+
+    async fn cluster_diagnostics(&self) -> Diagnostics {
+        let mut diagnostics = self.diagnostics.read().await.clone();
+        diagnostics.paused = self.paused.load(Ordering::Relaxed);
+        diagnostics.documents = self.documents.summary();
+        diagnostics.shard = self.sharder.info();
+        diagnostics.cluster = self.cluster_name();
+        diagnostics
     }
 
     // Create a Controller Context that can update State
     pub async fn to_context(&self, client: Client) -> Arc<Context> {
         Arc::new(Context {
             client: client.clone(),
-            recorder: self.diagnostics.read().await.recorder(client),
+            events: EventPublisher::new(
+                self.diagnostics.read().await.recorder(client),
+                EventConfig::default(),
+                self.metrics.events.clone(),
+            ),
             metrics: self.metrics.clone(),
             diagnostics: self.diagnostics.clone(),
+            notifier: Notifier::new(self.notifications.clone(), self.metrics.notify.clone()),
+            cache: DocumentCache::new(self.watch.cache_capacity, self.metrics.cache.clone()),
+            sharder: self.sharder.clone(),
+            paused: self.paused.clone(),
+            documents: self.documents.clone(),
+            search: self.search.clone(),
+            config: self.controller.clone(),
+            concurrency: self.concurrency.clone(),
+            triggers: self.triggers.clone(),
         })
     }
 }
 
+/// Api for namespaced resources in the watched namespaces
+///
+/// Watching several (but not all) namespaces needs a cluster wide watch filtered by `predicates::in_namespaces`.
+pub(crate) fn scoped_api<K>(client: Client, namespaces: &[String]) -> Api<K>
+where
+    K: Resource<Scope = NamespaceResourceScope>,
+    K::DynamicType: Default,
+{
+    match namespaces {
+        [ns] => Api::namespaced(client, ns),
+        _ => Api::all(client),
+    }
+}
+
 /// Initialize the controller and shared state (given the crd is installed)
+///
+/// Runs a `Controller` per cluster when several clusters are configured.
 pub async fn run(state: State) {
-    let client = Client::try_default().await.expect("failed to create kube Client");
-    let docs = Api::<Document>::all(client.clone());
+    if state.clusters.is_empty() {
+        let client = Client::try_default().await.expect("failed to create kube Client");
+        return run_cluster(state, client).await;
+    }
+    let clusters = state.clusters.iter().map(|cluster| {
+        let config = cluster.cluster.clone().expect("clusters are named");
+        let span = info_span!("cluster", name = %config.name);
+        async move {
+            let client = match config.client().await {
+                Ok(client) => client,
+                Err(e) => {
+                    error!("failed to connect to cluster {}: {e:#}", config.name);
+                    std::process::exit(1);
+                }
+            };
+            run_cluster(cluster.clone(), client).await
+        }
+        .instrument(span)
+    });
+    futures::future::join_all(clusters).await;
+}
+
+/// Reconcile the Documents (and DocumentSources) of one cluster
+async fn run_cluster(state: State, client: Client) {
+    let namespaces = state.controller.get().namespaces.clone();
+    let docs = scoped_api::<Document>(client.clone(), &namespaces);
     if let Err(e) = docs.list(&ListParams::default().limit(1)).await {
         error!("CRD is not queryable; {e:?}. Is the CRD installed?");
         info!("Installation: cargo run --bin crdgen | kubectl apply -f -");
         std::process::exit(1);
     }
-    Controller::new(docs, Config::default().any_semantic())
+    // migrate renamed finalizers and report the ones no reconcile removes
+    if state.config.get().finalizers.sweep_on_startup {
+        let sweeper = state.sweeper(client.clone()).await;
+        tokio::spawn(
+            async move {
+                if let Err(e) = sweeper.run(false).await {
+                    warn!("finalizer sweep failed: {e}");
+                }
+            }
+            .in_current_span(),
+        );
+    }
+    // reconcile everything again when shard ownership moves
+    let (rebalance, rebalances) = mpsc::unbounded();
+    // the Pod only exists in the cluster of the default client
+    let owner = match state.cluster {
+        None => PodInfo::from_env().and_then(|pod| pod.owner_reference()),
+        Some(_) => None,
+    };
+    let membership = state.sharder.clone().spawn(client.clone(), owner, rebalance);
+    let release = client.clone();
+    let sources = source::run(client.clone(), state.clone(), namespaces.clone());
+    let documents = async {
+        match state.watch.mode {
+            WatchMode::Full => run_full(docs, client, state.clone(), namespaces, rebalances).await,
+            WatchMode::Metadata => run_metadata(docs, client, state.clone(), namespaces, rebalances).await,
+        }
+    };
+    futures::join!(documents, sources);
+    if let Some(membership) = membership {
+        membership.abort();
+        state.sharder.release(release).await;
+    }
+}
+
+/// Watch and store full Documents
+async fn run_full(
+    docs: Api<Document>,
+    client: Client,
+    state: State,
+    namespaces: Vec<String>,
+    rebalances: mpsc::UnboundedReceiver<()>,
+) {
+    // only reconcile on changes relevant to us (not e.g. our own status writes)
+    let (reader, writer) = reflector::store();
+    let in_namespaces = predicates::in_namespaces(namespaces.clone());
+    let (changes, search) = (state.changes.clone(), state.search.clone());
+    let stream = watcher(docs, Config::default().any_semantic())
+        .default_backoff()
+        .try_filter(move |e| futures::future::ready(in_namespaces(e)))
+        .inspect_ok(move |e| changes.observe(e, |d| Some(d.spec.hide)))
+        .inspect_ok(move |e| search.observe(e))
+        .reflect(writer);
+    let stream = predicates::filter_changes(stream, state.predicates.clone(), state.metrics.watch.clone());
+    let controller = Controller::for_stream(stream, reader);
+    let store = controller.store();
+    state.triggers.set_lister(move |ns| {
+        let docs = store.state().into_iter();
+        docs.filter(|d| d.namespace().as_deref() == Some(ns))
+            .map(|d| ObjectRef::from_obj(&*d))
+            .collect()
+    });
+    // reconcile documents when the ConfigMaps holding their content change
+    let store = controller.store();
+    let cms = scoped_api::<ConfigMap>(client.clone(), &namespaces);
+    let (triggers, sharder) = (state.triggers.clone(), state.sharder.clone());
+    controller
+        .watches(cms, Config::default(), move |cm| {
+            content::referencing_documents(store.state(), &cm)
+        })
+        .reconcile_all_on(rebalances)
+        .reconcile_on(state.triggers.stream())
         .shutdown_on_signal()
         .run(reconcile, error_policy, state.to_context(client).await)
-        .filter_map(|x| async move { std::result::Result::ok(x) })
-        .for_each(|_| futures::future::ready(()))
+        .for_each(|res| {
+            triggers.complete(&res, &sharder);
+            futures::future::ready(())
+        })
+        .await;
+}
+
+/// Watch Document metadata only, fetching full Documents on demand through a bounded cache
+async fn run_metadata(
+    docs: Api<Document>,
+    client: Client,
+    state: State,
+    namespaces: Vec<String>,
+    rebalances: mpsc::UnboundedReceiver<()>,
+) {
+    let (reader, writer) = reflector::store();
+    let in_namespaces = predicates::in_namespaces(namespaces.clone());
+    let (changes, search) = (state.changes.clone(), state.search.clone());
+    let stream = metadata_watcher(docs, Config::default().any_semantic())
+        .default_backoff()
+        .try_filter(move |e| futures::future::ready(in_namespaces(e)))
+        // whether Documents are hidden is not known from their metadata
+        .inspect_ok(move |e| changes.observe(e, |_| None))
+        .inspect_ok(move |e| search.observe(e))
+        .reflect(writer);
+    let stream = predicates::filter_changes(stream, state.predicates.clone(), state.metrics.watch.clone());
+    let ctx = state.to_context(client.clone()).await;
+    // Documents are known to reference ConfigMaps once reconciled, even after leaving the cache
+    let cache = ctx.cache.clone();
+    let cms = scoped_api::<ConfigMap>(client, &namespaces);
+    let store = reader.clone();
+    state.triggers.set_lister(move |ns| {
+        let metas = store.state().into_iter();
+        metas
+            .filter(|m| m.namespace().as_deref() == Some(ns))
+            .map(|m| ObjectRef::new(&m.name_any()).within(ns))
+            .collect()
+    });
+    let requested = state
+        .triggers
+        .stream()
+        .map(|o| ObjectRef::new(&o.name).within(&o.namespace.unwrap()));
+    let (triggers, sharder) = (state.triggers.clone(), state.sharder.clone());
+    Controller::for_stream(stream, reader)
+        .watches(cms, Config::default(), move |cm| {
+            cache
+                .referencing(&cm)
+                .into_iter()
+                .map(|o| ObjectRef::new(&o.name).within(o.namespace.as_deref().unwrap_or_default()))
+                .collect::<Vec<_>>()
+        })
+        .reconcile_all_on(rebalances)
+        .reconcile_on(requested)
+        .shutdown_on_signal()
+        .run(reconcile_metadata, error_policy_metadata, ctx)
+        .for_each(|res| {
+            triggers.complete(&res, &sharder);
+            futures::future::ready(())
+        })
         .await;
 }
 
 // Mock tests relying on fixtures.rs and its primitive apiserver mocks
 #[cfg(test)]
 mod test {
-    use super::{Context, Document, error_policy, reconcile};
+    use super::{
+        Context, Document, DocumentStatus, HasConditions, State, error_policy, reconcile, reconcile_metadata,
+    };
     use crate::{
-        fixtures::{Scenario, timeout_after_1s},
-        metrics::ErrorLabels,
+        Error, ErrorCategory,
+        cluster::{ClusterConfig, ClusterError},
+        diagnostics::ReconcileResult,
+        fixtures::{Scenario, mock_client, timeout_after_1s},
+        metrics::{ErrorLabels, PauseLabels, StatusWriteLabels},
+        notify::Transition,
+        shard::{ShardConfig, Sharder},
     };
     use envtest::Environment;
     use kube::{
-        CustomResourceExt,
+        CustomResourceExt, Resource,
         api::{Api, ListParams, Patch, PatchParams},
+        core::PartialObjectMetaExt,
+        runtime::{controller::Action, reflector::ObjectRef},
+    };
+    use opentelemetry::trace::TraceId;
+    use std::{
+        sync::{Arc, atomic::Ordering},
+        time::Duration,
     };
-    use std::sync::Arc;
 
     #[tokio::test]
     async fn documents_without_finalizer_gets_a_finalizer() {
@@ -263,23 +1267,192 @@ mod test {
         timeout_after_1s(mocksrv).await;
     }
 
+    #[tokio::test]
+    async fn reconciled_doc_skips_status_patch() {
+        let (testctx, fakeserver) = Context::test();
+        let doc = Document::test().finalized().reconciled();
+        let mocksrv = fakeserver.run(Scenario::StatusUnchanged);
+        reconcile(Arc::new(doc), testctx.clone())
+            .await
+            .expect("reconciler");
+        timeout_after_1s(mocksrv).await;
+        let writes = &testctx.metrics.reconcile.status_writes;
+        let skipped = writes.get_or_create(&StatusWriteLabels {
+            result: "skipped".into(),
+        });
+        assert_eq!(skipped.get(), 1);
+        assert_eq!(testctx.search.len(), 1);
+    }
+
+    fn clusters(names: &[&str]) -> Vec<ClusterConfig> {
+        let cluster = |name: &&str| ClusterConfig {
+            name: name.to_string(),
+            context: None,
+        };
+        names.iter().map(cluster).collect()
+    }
+
+    #[tokio::test]
+    async fn clusters_are_reconciled_with_their_own_clients_and_labels() {
+        let state = State::default().with_clusters(clusters(&["east", "west"]));
+        for cluster in state.clusters() {
+            let (client, fakeserver) = mock_client();
+            let mut doc = Document::test().finalized().reconciled();
+            doc.spec.title = "Runbook".into();
+            let mocksrv = fakeserver.run(Scenario::StatusUnchanged);
+            let ctx = cluster.to_context(client).await;
+            reconcile(Arc::new(doc), ctx).await.expect("reconciler");
+            timeout_after_1s(mocksrv).await;
+        }
+
+        let metrics = state.metrics();
+        for cluster in ["east", "west"] {
+            let runs = format!("doc_ctrl_reconcile_runs_total{{cluster=\"{cluster}\"}} 1");
+            assert!(metrics.contains(&runs), "{metrics}");
+        }
+        let diagnostics = state.diagnostics().await;
+        assert_eq!(diagnostics.documents.tracked, 2);
+        assert_eq!(diagnostics.clusters[1].cluster.as_deref(), Some("west"));
+        let hits = state.search("runbook", None, 10);
+        let clusters: Vec<_> = hits.iter().map(|h| h.cluster.as_deref()).collect();
+        assert_eq!(clusters.len(), 2);
+        assert!(clusters.contains(&Some("east")));
+
+        let west = state.cluster(Some("west")).unwrap();
+        assert!(west.document_diagnostics("default", "test").is_some());
+        assert_eq!(state.cluster(None).err(), Some(ClusterError::Required));
+        assert!(state.cluster(Some("north")).is_err());
+        let single = State::default();
+        assert!(single.cluster(None).is_ok());
+        assert!(!single.metrics().contains("cluster="));
+    }
+
+    #[tokio::test]
+    async fn metadata_watched_doc_is_fetched_once_then_cached() {
+        let (testctx, fakeserver) = Context::test();
+        let mut doc = Document::test().finalized().reconciled();
+        doc.meta_mut().resource_version = Some("1".into());
+        let meta = Arc::new(doc.metadata.clone().into_response_partial::<Document>());
+        let mocksrv = fakeserver.run(Scenario::DocumentFetch(doc));
+        for _ in 0..2 {
+            reconcile_metadata(meta.clone(), testctx.clone())
+                .await
+                .expect("reconciler");
+        }
+        timeout_after_1s(mocksrv).await;
+        assert_eq!(testctx.metrics.cache.misses.get(), 1);
+        assert_eq!(testctx.metrics.cache.hits.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn docs_owned_by_other_shards_are_skipped() {
+        let (testctx, fakeserver) = Context::test();
+        let mut ctx = (*testctx).clone();
+        let config = ShardConfig {
+            enabled: true,
+            identity: "replica-0".into(),
+            ..ShardConfig::default()
+        };
+        ctx.sharder = Sharder::new(config, ctx.metrics.shard.clone());
+        assert!(ctx.sharder.set_members(vec!["replica-1".into()]));
+        let mocksrv = fakeserver.run(Scenario::RadioSilence);
+        let res = reconcile(Arc::new(Document::test()), Arc::new(ctx.clone())).await;
+        assert_eq!(res.unwrap(), Action::await_change());
+        timeout_after_1s(mocksrv).await;
+        assert_eq!(ctx.metrics.shard.skipped.get(), 1);
+        assert_eq!(ctx.metrics.reconcile.runs.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn paused_doc_only_records_paused_condition() {
+        let (testctx, fakeserver) = Context::test();
+        let doc = Document::test().finalized().reconciled().paused();
+        let mocksrv = fakeserver.run(Scenario::PausedConditionPatch(doc.clone()));
+        let res = reconcile(Arc::new(doc), testctx.clone()).await;
+        assert_eq!(res.unwrap(), Action::await_change());
+        timeout_after_1s(mocksrv).await;
+        let skips = testctx
+            .metrics
+            .reconcile
+            .paused_skips
+            .get_or_create(&PauseLabels {
+                scope: "document".into(),
+            });
+        assert_eq!(skips.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn globally_paused_reconciles_are_noops() {
+        let (testctx, fakeserver) = Context::test();
+        testctx.paused.store(true, Ordering::Relaxed);
+        let mocksrv = fakeserver.run(Scenario::RadioSilence);
+        let res = reconcile(Arc::new(Document::test()), testctx.clone()).await;
+        assert_eq!(
+            res.unwrap(),
+            Action::requeue(testctx.config.get().paused_requeue_interval)
+        );
+        timeout_after_1s(mocksrv).await;
+        assert_eq!(testctx.metrics.reconcile.runs.get(), 0);
+        let oref = ObjectRef::from_obj(&Document::test());
+        assert!(testctx.documents.get(&oref).unwrap().next_requeue.is_some());
+    }
+
     #[tokio::test]
     async fn finalized_doc_with_hide_causes_event_and_hide_patch() {
         let (testctx, fakeserver) = Context::test();
         let doc = Document::test().finalized().needs_hide();
         let scenario = Scenario::EventPublishThenStatusPatch("HideRequested".into(), doc.clone());
         let mocksrv = fakeserver.run(scenario);
-        reconcile(Arc::new(doc), testctx).await.expect("reconciler");
+        let oref = ObjectRef::from_obj(&doc);
+        reconcile(Arc::new(doc), testctx.clone())
+            .await
+            .expect("reconciler");
         timeout_after_1s(mocksrv).await;
+        let diagnostics = testctx.documents.get(&oref).unwrap();
+        assert!(diagnostics.next_requeue.is_some());
     }
 
     #[tokio::test]
     async fn finalized_doc_with_delete_timestamp_causes_delete() {
         let (testctx, fakeserver) = Context::test();
         let doc = Document::test().finalized().needs_delete();
+        testctx.search.upsert(&doc, "content");
+        testctx.cache.insert(doc.clone());
+        let oref = ObjectRef::from_obj(&doc);
+        testctx.documents.record(
+            oref.clone(),
+            Duration::ZERO,
+            Ok(ReconcileResult::Success),
+            None,
+            TraceId::INVALID,
+        );
         let mocksrv = fakeserver.run(Scenario::Cleanup("DeleteRequested".into(), doc.clone()));
-        reconcile(Arc::new(doc), testctx).await.expect("reconciler");
+        reconcile(Arc::new(doc), testctx.clone())
+            .await
+            .expect("reconciler");
+        timeout_after_1s(mocksrv).await;
+        assert!(testctx.search.is_empty());
+        assert!(testctx.cache.cached(&oref).is_none());
+        assert!(testctx.documents.get(&oref).is_none());
+    }
+
+    #[tokio::test]
+    async fn retried_cleanup_only_removes_the_finalizer() {
+        let (testctx, fakeserver) = Context::test();
+        let doc = Document::test().finalized().needs_delete();
+        let message = "removing the search index entry and cached copy".into();
+        let deleting = doc.condition("Deleting", true, "CleaningUp", message);
+        let doc = doc.with_status(DocumentStatus {
+            conditions: vec![deleting],
+            ..DocumentStatus::default()
+        });
+        testctx.search.upsert(&doc, "content");
+        let mocksrv = fakeserver.run(Scenario::CleanupRetry(doc.clone()));
+        reconcile(Arc::new(doc), testctx.clone())
+            .await
+            .expect("reconciler");
         timeout_after_1s(mocksrv).await;
+        assert!(testctx.search.is_empty());
     }
 
     #[tokio::test]
@@ -296,13 +1469,119 @@ mod test {
         error_policy(doc.clone(), &err, testctx.clone());
         let err_labels = ErrorLabels {
             instance: "illegal".into(),
-            error: "finalizererror(applyfailed(illegaldocument))".into(),
+            error: "illegal_document".into(),
+            category: "permanent".into(),
         };
         let metrics = &testctx.metrics.reconcile;
         let failures = metrics.failures.get_or_create(&err_labels).get();
         assert_eq!(failures, 1);
     }
 
+    #[tokio::test]
+    async fn failed_reconcile_publishes_warning_event_and_condition() {
+        let (testctx, fakeserver) = Context::test();
+        let doc = Arc::new(Document::illegal().finalized());
+        let scenario = Scenario::FailureReported("IllegalDocument".into(), (*doc).clone());
+        let mocksrv = fakeserver.run(scenario);
+        let err = reconcile(doc.clone(), testctx.clone()).await.unwrap_err();
+        error_policy(doc, &err, testctx);
+        timeout_after_1s(mocksrv).await;
+    }
+
+    #[tokio::test]
+    async fn failed_reconcile_is_recorded_in_document_diagnostics() {
+        let (testctx, fakeserver) = Context::test();
+        let doc = Arc::new(Document::illegal().finalized());
+        let scenario = Scenario::FailureReported("IllegalDocument".into(), (*doc).clone());
+        let mocksrv = fakeserver.run(scenario);
+        let err = reconcile(doc.clone(), testctx.clone()).await.unwrap_err();
+        error_policy(doc.clone(), &err, testctx.clone());
+        timeout_after_1s(mocksrv).await;
+        let diagnostics = testctx.documents.get(&ObjectRef::from_obj(&*doc)).unwrap();
+        assert_eq!(diagnostics.result, ReconcileResult::Error);
+        assert_eq!(diagnostics.error, Some(err.to_string()));
+        assert_eq!(diagnostics.retries, 1);
+        assert!(diagnostics.next_requeue.is_some());
+        assert_eq!(testctx.documents.summary().failing, vec![
+            "default/illegal".to_string()
+        ]);
+    }
+
+    #[tokio::test]
+    async fn conflicts_are_requeued_sooner_than_permanent_errors() {
+        let (testctx, _fakeserver) = Context::test();
+        let doc = Arc::new(Document::test().finalized());
+        let conflict = Error::KubeError(kube::Error::Api(Box::new(
+            kube::core::Status::failure("the object has been modified", "Conflict").with_code(409),
+        )));
+        assert_eq!(conflict.code(), "conflict");
+        assert_eq!(conflict.category(), ErrorCategory::Conflict);
+        let conflict_action = error_policy(doc.clone(), &conflict, testctx.clone());
+        assert_eq!(conflict_action, Action::requeue(Duration::from_secs(1)));
+        let permanent_action = error_policy(doc, &Error::IllegalDocument, testctx);
+        assert_eq!(permanent_action, Action::requeue(Duration::from_secs(5 * 60)));
+    }
+
+    #[test]
+    fn condition_patches_are_valid_without_hidden() {
+        // failures are reported with a merge patch of the conditions alone
+        let crd = serde_json::to_value(Document::crd()).unwrap();
+        let status = &crd["spec"]["versions"][0]["schema"]["openAPIV3Schema"]["properties"]["status"];
+        assert!(status.get("required").is_none(), "{status}");
+        let patched = serde_json::json!({ "conditions": [] });
+        let status: DocumentStatus = serde_json::from_value(patched).unwrap();
+        assert!(!status.hidden);
+    }
+
+    #[test]
+    fn unchanged_conditions_are_not_patched_again() {
+        let doc = Document::test().reconciled().paused();
+        let paused = doc.condition("Paused", true, "PauseAnnotation", "paused".into());
+        let failed = doc.ready_condition(false, "Failed", "boom".into());
+        let doc = doc.with_status(DocumentStatus {
+            conditions: vec![paused, failed],
+            ..DocumentStatus::default()
+        });
+        assert!(
+            doc.condition_patch("Ready", false, "Failed", "boom".into())
+                .is_none()
+        );
+        let Some(Patch::Merge(patch)) = doc.condition_patch("Ready", false, "Failed", "bang".into()) else {
+            panic!("changed message is patched");
+        };
+        let conditions = patch["status"]["conditions"].as_array().unwrap();
+        assert_eq!(conditions.len(), 2, "other conditions are kept");
+        assert_eq!(conditions[0]["type"], "Paused");
+        assert_eq!(conditions[1]["message"], "bang");
+    }
+
+    #[test]
+    fn created_is_announced_until_a_reconcile_succeeds() {
+        assert_eq!(Document::test().transitions(), [Transition::Created]);
+        // a failure status written before the first successful reconcile
+        let failed = Document::test().with_status(DocumentStatus {
+            conditions: vec![Document::test().ready_condition(false, "Failed", "boom".into())],
+            ..DocumentStatus::default()
+        });
+        assert_eq!(failed.transitions(), [Transition::Created]);
+        assert!(Document::test().reconciled().transitions().is_empty());
+        let hide = Document::test().reconciled().needs_hide();
+        assert_eq!(hide.transitions(), [Transition::Hidden]);
+    }
+
+    #[tokio::test]
+    async fn doc_with_missing_content_config_map_fails() {
+        let (testctx, fakeserver) = Context::test();
+        let doc = Document::test().finalized().with_content_config_map("missing");
+        let mocksrv = fakeserver.run(Scenario::ContentConfigMapMissing("missing".into()));
+        let res = reconcile(Arc::new(doc), testctx).await;
+        timeout_after_1s(mocksrv).await;
+        let err = res.unwrap_err();
+        assert!(err.to_string().contains("MissingContentReference"), "{err}");
+        assert_eq!(err.code(), "missing_content_reference");
+        assert_eq!(err.category(), ErrorCategory::Retryable);
+    }
+
     // Integration test without mocks
     #[tokio::test]
     async fn integration_reconcile_should_set_status_and_send_event() {
@@ -332,10 +1611,34 @@ mod test {
             .await
             .unwrap()
             .into_iter()
-            .filter(|e| e.reason.as_deref() == Some("HideRequested"))
-            .last()
+            .rfind(|e| e.reason.as_deref() == Some("HideRequested"))
             .unwrap();
         dbg!("got ev: {:?}", &event);
         assert_eq!(event.action.as_deref(), Some("Hiding"));
     }
+
+    // Integration test of several clusters without mocks
+    #[tokio::test]
+    async fn integration_clusters_are_reconciled_independently() {
+        let state = State::default().with_clusters(clusters(&["east", "west"]));
+        let mut servers = vec![];
+        for cluster in state.clusters() {
+            let env = Environment::default().with_crds(vec![Document::crd()]).unwrap();
+            let server = env.create().await.unwrap();
+            let client = server.client().unwrap();
+            let docs: Api<Document> = Api::namespaced(client.clone(), "default");
+            let doc = Document::test().finalized();
+            let patch = Patch::Apply(doc.clone());
+            docs.patch("test", &PatchParams::apply("ctrltest"), &patch)
+                .await
+                .unwrap();
+            let ctx = cluster.to_context(client).await;
+            reconcile(Arc::new(doc), ctx).await.unwrap();
+            assert!(docs.get_status("test").await.unwrap().status.is_some());
+            servers.push(server);
+        }
+        let diagnostics = state.diagnostics().await;
+        assert_eq!(diagnostics.documents.tracked, 2);
+        assert!(diagnostics.documents.failing.is_empty());
+    }
 }
@@ -1,4 +1,143 @@
-use kube::CustomResourceExt;
-fn main() {
-    print!("{}", serde_yaml::to_string(&controller::Document::crd()).unwrap())
+use anyhow::Context as _;
+use clap::{Args, Parser, Subcommand};
+use controller::crds::{self, Format};
+use std::{path::PathBuf, process::ExitCode};
+
+/// Generate the CustomResourceDefinitions of the controller
+///
+/// Prints all CRDs as yaml when no command is given.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print or write the CRDs
+    Crd(CrdArgs),
+    /// Print or write JSON Schemas of the CRDs for editor integration
+    Schema(SchemaArgs),
+    /// Compare the generated CRDs with a committed file and fail on drift
+    Check {
+        #[arg(default_value = "yaml/crd.yaml")]
+        path: PathBuf,
+    },
+}
+
+#[derive(Args, Default)]
+struct CrdArgs {
+    #[arg(short, long, value_enum, default_value_t)]
+    format: Format,
+    #[command(flatten)]
+    target: Target,
+}
+
+#[derive(Args, Default)]
+struct SchemaArgs {
+    #[command(flatten)]
+    target: Target,
+}
+
+#[derive(Args, Default)]
+struct Target {
+    /// Only emit these CRDs (by name, kind or short name) instead of all
+    #[arg(long = "crd", value_name = "NAME")]
+    names: Vec<String>,
+    /// Write to a file instead of stdout
+    #[arg(short, long, conflicts_with = "dir")]
+    output: Option<PathBuf>,
+    /// Write one file per CRD (or schema) into a directory
+    #[arg(short, long)]
+    dir: Option<PathBuf>,
+}
+
+impl Target {
+    /// Write the named outputs to the target
+    fn write(&self, outputs: Vec<(String, String)>) -> anyhow::Result<()> {
+        if let Some(dir) = &self.dir {
+            std::fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+            for (file, contents) in outputs {
+                let path = dir.join(file);
+                std::fs::write(&path, contents)
+                    .with_context(|| format!("failed to write {}", path.display()))?;
+                eprintln!("wrote {}", path.display());
+            }
+            return Ok(());
+        }
+        let contents: Vec<_> = outputs.into_iter().map(|(_, contents)| contents).collect();
+        let contents = contents.concat();
+        match &self.output {
+            Some(path) => {
+                std::fs::write(path, contents).with_context(|| format!("failed to write {}", path.display()))
+            }
+            None => {
+                print!("{contents}");
+                Ok(())
+            }
+        }
+    }
+}
+
+fn crd(args: CrdArgs) -> anyhow::Result<()> {
+    let crds = crds::select(&args.target.names)?;
+    let outputs = if args.target.dir.is_some() {
+        crds.iter()
+            .map(|crd| {
+                Ok((
+                    crds::file_name(crd, args.format),
+                    crds::render(std::slice::from_ref(crd), args.format)?,
+                ))
+            })
+            .collect::<anyhow::Result<_>>()?
+    } else {
+        vec![(String::new(), crds::render(&crds, args.format)?)]
+    };
+    args.target.write(outputs)
+}
+
+fn schema(args: SchemaArgs) -> anyhow::Result<()> {
+    let mut outputs = vec![];
+    for crd in crds::select(&args.target.names)? {
+        for (file, schema) in crds::schemas(&crd)? {
+            outputs.push((file, serde_json::to_string_pretty(&schema)? + "\n"));
+        }
+    }
+    if args.target.dir.is_none() && outputs.len() > 1 {
+        let files: Vec<_> = outputs.into_iter().map(|(file, _)| file).collect();
+        anyhow::bail!(
+            "several schemas ({}); select one with --crd or use --dir",
+            files.join(", ")
+        );
+    }
+    args.target.write(outputs)
+}
+
+fn check(path: PathBuf) -> anyhow::Result<ExitCode> {
+    let committed =
+        std::fs::read_to_string(&path).with_context(|| format!("failed to read {}", path.display()))?;
+    let generated = crds::render(&crds::all(), Format::Yaml)?;
+    let drift = crds::diff(&committed, &generated);
+    if drift.is_empty() {
+        return Ok(ExitCode::SUCCESS);
+    }
+    for line in drift {
+        println!("{line}");
+    }
+    eprintln!(
+        "{} differs from the generated CRDs; run `just generate` if the change is intended",
+        path.display()
+    );
+    Ok(ExitCode::FAILURE)
+}
+
+fn main() -> anyhow::Result<ExitCode> {
+    match Cli::parse().command {
+        None => crd(CrdArgs::default())?,
+        Some(Command::Crd(args)) => crd(args)?,
+        Some(Command::Schema(args)) => schema(args)?,
+        Some(Command::Check { path }) => return check(path),
+    }
+    Ok(ExitCode::SUCCESS)
 }
@@ -0,0 +1,183 @@
+//! Generation of the CustomResourceDefinitions and their JSON Schemas
+use crate::{Document, DocumentSource};
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+use kube::CustomResourceExt;
+use serde_json::{Value, json};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum CrdError {
+    #[error("unknown CRD {0:?} (expected one of: {1})")]
+    Unknown(String, String),
+
+    #[error("failed to serialize as yaml: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+
+    #[error("failed to serialize as json: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Output format of the CRDs
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    #[default]
+    Yaml,
+    Json,
+}
+
+impl Format {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Yaml => "yaml",
+            Format::Json => "json",
+        }
+    }
+}
+
+/// Every CRD defined by the crate
+pub fn all() -> Vec<CustomResourceDefinition> {
+    vec![Document::crd(), DocumentSource::crd()]
+}
+
+/// The CRDs matching the names (e.g. `documents.kube.rs`, `Document` or `doc`), or all when none are given
+pub fn select(names: &[String]) -> Result<Vec<CustomResourceDefinition>, CrdError> {
+    let crds = all();
+    if names.is_empty() {
+        return Ok(crds);
+    }
+    names
+        .iter()
+        .map(|name| {
+            let found = crds.iter().find(|crd| {
+                let names = &crd.spec.names;
+                crd.metadata.name.as_deref() == Some(name)
+                    || names.kind.eq_ignore_ascii_case(name)
+                    || names.plural == *name
+                    || names.short_names.iter().flatten().any(|short| short == name)
+            });
+            found.cloned().ok_or_else(|| {
+                let known: Vec<_> = crds.iter().filter_map(|crd| crd.metadata.name.clone()).collect();
+                CrdError::Unknown(name.clone(), known.join(", "))
+            })
+        })
+        .collect()
+}
+
+/// Render CRDs as a multi-document yaml stream, or as json (a `List` when there are several)
+pub fn render(crds: &[CustomResourceDefinition], format: Format) -> Result<String, CrdError> {
+    Ok(match (format, crds) {
+        (Format::Yaml, crds) => crds
+            .iter()
+            .map(serde_yaml::to_string)
+            .collect::<Result<Vec<_>, _>>()?
+            .join("---\n"),
+        (Format::Json, [crd]) => serde_json::to_string_pretty(crd)? + "\n",
+        (Format::Json, crds) => {
+            let list = json!({ "apiVersion": "v1", "kind": "List", "items": crds });
+            serde_json::to_string_pretty(&list)? + "\n"
+        }
+    })
+}
+
+/// File name of a rendered CRD, like `documents.kube.rs.yaml`
+pub fn file_name(crd: &CustomResourceDefinition, format: Format) -> String {
+    let name = crd.metadata.name.as_deref().unwrap_or(&crd.spec.names.plural);
+    format!("{name}.{}", format.extension())
+}
+
+/// JSON Schemas of every served version of a CRD, keyed by a file name like `document_v1.json`
+///
+/// The schemas describe complete objects (including `apiVersion` and `kind`), so editors
+/// can validate manifests against them, e.g. with a `# yaml-language-server: $schema=` comment.
+pub fn schemas(crd: &CustomResourceDefinition) -> Result<Vec<(String, Value)>, CrdError> {
+    let kind = &crd.spec.names.kind;
+    let mut schemas = vec![];
+    for version in crd.spec.versions.iter().filter(|v| v.served) {
+        let Some(schema) = version
+            .schema
+            .as_ref()
+            .and_then(|s| s.open_api_v3_schema.as_ref())
+        else {
+            continue;
+        };
+        let mut schema = serde_json::to_value(schema)?;
+        let api_version = format!("{}/{}", crd.spec.group, version.name);
+        schema["$schema"] = json!("http://json-schema.org/draft-07/schema#");
+        schema["title"] = json!(kind);
+        schema["properties"]["apiVersion"] = json!({ "type": "string", "enum": [api_version] });
+        schema["properties"]["kind"] = json!({ "type": "string", "enum": [kind] });
+        schema["required"] = json!(["apiVersion", "kind", "spec"]);
+        let file = format!("{}_{}.json", kind.to_lowercase(), version.name);
+        schemas.push((file, schema));
+    }
+    Ok(schemas)
+}
+
+/// Lines that differ between a committed and a generated file, prefixed with `-` and `+`
+pub fn diff(committed: &str, generated: &str) -> Vec<String> {
+    let old: Vec<_> = committed.lines().collect();
+    let new: Vec<_> = generated.lines().collect();
+    // longest common subsequence of the suffixes, walked from the front
+    let mut lcs = vec![vec![0u32; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j, mut lines) = (0, 0, vec![]);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            (i, j) = (i + 1, j + 1);
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(format!("-{}", old[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+{}", new[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Format, all, diff, render, schemas, select};
+
+    #[test]
+    fn committed_crds_are_up_to_date() {
+        let committed = include_str!("../yaml/crd.yaml");
+        let generated = render(&all(), Format::Yaml).unwrap();
+        let drift = diff(committed, &generated);
+        assert!(
+            drift.is_empty(),
+            "yaml/crd.yaml is out of date:\n{}",
+            drift.join("\n")
+        );
+    }
+
+    #[test]
+    fn crds_are_selected_and_rendered() {
+        let crds = select(&["Document".into()]).unwrap();
+        assert_eq!(select(&["doc".into()]).unwrap().len(), 1);
+        assert_eq!(crds[0].metadata.name.as_deref(), Some("documents.kube.rs"));
+        assert!(select(&["widgets.kube.rs".into()]).is_err());
+
+        let json: serde_json::Value = serde_json::from_str(&render(&crds, Format::Json).unwrap()).unwrap();
+        assert_eq!(json["kind"], "CustomResourceDefinition");
+
+        let (file, schema) = &schemas(&crds[0]).unwrap()[0];
+        assert_eq!(file, "document_v1.json");
+        assert_eq!(schema["properties"]["apiVersion"]["enum"][0], "kube.rs/v1");
+        assert!(schema["properties"]["spec"]["properties"]["title"].is_object());
+    }
+
+    #[test]
+    fn diff_lists_changed_lines() {
+        assert!(diff("a\nb\n", "a\nb\n").is_empty());
+        assert_eq!(diff("a\nb\nc\n", "a\nx\nc\nd\n"), ["-b", "+x", "+d"]);
+    }
+}
@@ -0,0 +1,188 @@
+//! Per-Document reconcile diagnostics for the web server
+use crate::{Document, Error};
+use jiff::Timestamp;
+use kube::runtime::reflector::ObjectRef;
+use opentelemetry::trace::TraceId;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Outcome of the last reconcile of a Document
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ReconcileResult {
+    Success,
+    Paused,
+    Error,
+}
+
+/// What is known about the last reconcile of a Document
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentDiagnostics {
+    pub last_reconcile: Timestamp,
+    pub duration_seconds: f64,
+    pub result: ReconcileResult,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Consecutive failed reconciles
+    pub retries: u32,
+    /// When the Document is reconciled again without changes (if scheduled)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_requeue: Option<Timestamp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+}
+
+/// Overview of the tracked Documents for `GET /`
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DocumentsSummary {
+    pub tracked: usize,
+    /// Documents whose last reconcile failed, as `namespace/name`
+    pub failing: Vec<String>,
+}
+
+/// Bounded map of `DocumentDiagnostics`, forgetting the least recently reconciled Documents first
+#[derive(Clone)]
+pub struct DocumentDiagnosticsMap {
+    capacity: usize,
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Incremented on every reconcile to order entries
+    seq: u64,
+    entries: HashMap<ObjectRef<Document>, (u64, DocumentDiagnostics)>,
+    /// Least recently reconciled first
+    order: BTreeMap<u64, ObjectRef<Document>>,
+}
+
+impl Default for DocumentDiagnosticsMap {
+    fn default() -> Self {
+        Self::new(1000)
+    }
+}
+
+fn after(delay: Option<Duration>) -> Option<Timestamp> {
+    delay.and_then(|d| Timestamp::now().checked_add(d).ok())
+}
+
+impl DocumentDiagnosticsMap {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Arc::default(),
+        }
+    }
+
+    /// Record a finished reconcile
+    ///
+    /// Failures are completed by `record_retry` from the error policy, which knows the requeue.
+    pub fn record(
+        &self,
+        oref: ObjectRef<Document>,
+        duration: Duration,
+        result: std::result::Result<ReconcileResult, &Error>,
+        next_requeue: Option<Duration>,
+        trace_id: TraceId,
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        let retries = inner.entries.get(&oref).map_or(0, |(_, d)| d.retries);
+        let diagnostics = DocumentDiagnostics {
+            last_reconcile: Timestamp::now(),
+            duration_seconds: duration.as_secs_f64(),
+            result: *result.as_ref().unwrap_or(&ReconcileResult::Error),
+            error: result.err().map(|e| e.to_string()),
+            retries: if result.is_ok() { 0 } else { retries },
+            next_requeue: after(next_requeue),
+            trace_id: (trace_id != TraceId::INVALID).then(|| trace_id.to_string()),
+        };
+        inner.seq += 1;
+        let seq = inner.seq;
+        inner.order.insert(seq, oref.clone());
+        if let Some((previous, _)) = inner.entries.insert(oref, (seq, diagnostics)) {
+            inner.order.remove(&previous);
+        }
+        if inner.entries.len() > self.capacity
+            && let Some((_, oldest)) = inner.order.pop_first()
+        {
+            inner.entries.remove(&oldest);
+        }
+    }
+
+    /// Count a failed reconcile and when it is retried
+    pub fn record_retry(&self, oref: &ObjectRef<Document>, requeue: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some((_, d)) = inner.entries.get_mut(oref) {
+            d.retries += 1;
+            d.next_requeue = after(Some(requeue));
+        }
+    }
+
+    pub fn get(&self, oref: &ObjectRef<Document>) -> Option<DocumentDiagnostics> {
+        let inner = self.inner.lock().unwrap();
+        inner.entries.get(oref).map(|(_, d)| d.clone())
+    }
+
+    /// Forget a Document once it is deleted
+    pub fn remove(&self, oref: &ObjectRef<Document>) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some((seq, _)) = inner.entries.remove(oref) {
+            inner.order.remove(&seq);
+        }
+    }
+
+    pub fn summary(&self) -> DocumentsSummary {
+        let inner = self.inner.lock().unwrap();
+        let mut failing: Vec<String> = inner
+            .entries
+            .iter()
+            .filter(|(_, (_, d))| d.result == ReconcileResult::Error)
+            .map(|(o, _)| format!("{}/{}", o.namespace.as_deref().unwrap_or_default(), o.name))
+            .collect();
+        failing.sort();
+        DocumentsSummary {
+            tracked: inner.entries.len(),
+            failing,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DocumentDiagnosticsMap, ReconcileResult};
+    use crate::Error;
+    use kube::runtime::reflector::ObjectRef;
+    use opentelemetry::trace::TraceId;
+    use std::time::Duration;
+
+    #[test]
+    fn failures_count_retries_until_a_success() {
+        let map = DocumentDiagnosticsMap::new(1);
+        let oref = ObjectRef::new("test").within("default");
+        let failure = Err(&Error::IllegalDocument);
+        for _ in 0..2 {
+            map.record(oref.clone(), Duration::ZERO, failure, None, TraceId::INVALID);
+            map.record_retry(&oref, Duration::from_secs(60));
+        }
+        let failing = map.get(&oref).unwrap();
+        assert_eq!(failing.retries, 2);
+        assert!(failing.next_requeue.is_some());
+        assert_eq!(map.summary().failing, vec!["default/test".to_string()]);
+
+        let ok = Ok(ReconcileResult::Success);
+        map.record(oref.clone(), Duration::ZERO, ok, None, TraceId::INVALID);
+        let recovered = map.get(&oref).unwrap();
+        assert_eq!((recovered.retries, recovered.error), (0, None));
+
+        // bounded to the most recently reconciled documents
+        let other = ObjectRef::new("other").within("default");
+        map.record(other.clone(), Duration::ZERO, ok, None, TraceId::INVALID);
+        assert!(map.get(&oref).is_none());
+        assert_eq!(map.summary().tracked, 1);
+    }
+}
@@ -0,0 +1,7 @@
+use clap::Parser;
+use controller::cli::Cli;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    Cli::parse().run().await
+}
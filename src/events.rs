@@ -0,0 +1,304 @@
+//! Event publishing with deduplication and per-object rate limiting
+use crate::metrics::EventMetrics;
+use k8s_openapi::api::core::v1::ObjectReference;
+use kube::runtime::events::{Event, EventType, Recorder};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+use tracing::*;
+
+/// Max length of event notes accepted by the apiserver
+const MAX_NOTE_LEN: usize = 1024;
+
+/// Truncate a note to the max length of event notes, on a char boundary
+pub fn truncate_note(mut note: String) -> String {
+    if note.len() > MAX_NOTE_LEN {
+        let end = (0..=MAX_NOTE_LEN)
+            .rev()
+            .find(|i| note.is_char_boundary(*i))
+            .unwrap_or(0);
+        note.truncate(end);
+    }
+    note
+}
+
+/// Limits for event publishing
+#[derive(Clone, Debug)]
+pub struct EventConfig {
+    /// Identical events for an object within this window are suppressed
+    pub dedup_window: Duration,
+    /// Max number of events an object can publish in a burst
+    pub burst: u32,
+    /// Time to regain capacity for one more event
+    pub refill: Duration,
+}
+
+impl Default for EventConfig {
+    fn default() -> Self {
+        Self {
+            dedup_window: Duration::from_secs(60),
+            burst: 10,
+            refill: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Identity of an object events are published for
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct ObjectKey {
+    namespace: Option<String>,
+    name: Option<String>,
+    uid: Option<String>,
+}
+
+/// Identity of an event for deduplication purposes
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct EventKey {
+    type_: EventType,
+    reason: String,
+    note: Option<String>,
+}
+
+/// Publishing history of a single object
+struct ObjectEvents {
+    tokens: f64,
+    last_refill: Instant,
+    /// When each distinct event was last published
+    published: HashMap<EventKey, Instant>,
+}
+
+/// Publishing history of all objects
+struct History {
+    objects: HashMap<ObjectKey, ObjectEvents>,
+    /// When quiet objects were last forgotten
+    pruned: Instant,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            objects: HashMap::new(),
+            pruned: Instant::now(),
+        }
+    }
+}
+
+/// Why an event was not published
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dropped {
+    Duplicate,
+    RateLimited,
+    Failed,
+}
+
+impl Dropped {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Dropped::Duplicate => "duplicate",
+            Dropped::RateLimited => "rate_limited",
+            Dropped::Failed => "failed",
+        }
+    }
+}
+
+/// Layer on top of the `Recorder` that keeps noisy reconciles from flooding namespaces with events
+///
+/// Repeats of the same event are deduplicated locally within a window, and repeats outside the window
+/// are aggregated by the `Recorder` into the series count of the original event.
+/// Publishing is best-effort; failures are logged and counted rather than failing the reconcile.
+#[derive(Clone)]
+pub struct EventPublisher {
+    recorder: Recorder,
+    config: EventConfig,
+    history: Arc<Mutex<History>>,
+    metrics: EventMetrics,
+}
+
+impl EventPublisher {
+    pub fn new(recorder: Recorder, config: EventConfig, metrics: EventMetrics) -> Self {
+        Self {
+            recorder,
+            config,
+            history: Arc::default(),
+            metrics,
+        }
+    }
+
+    /// Publish an event unless it is a duplicate or the object exceeded its rate limit
+    ///
+    /// Returns why the event was dropped (if it was).
+    pub async fn publish(&self, ev: &Event, reference: &ObjectReference) -> Option<Dropped> {
+        if let Err(dropped) = self.admit(ev, reference) {
+            debug!("dropping {} event: {:?}", ev.reason, dropped);
+            self.metrics.set_dropped(dropped.as_str());
+            return Some(dropped);
+        }
+        if let Err(e) = self.recorder.publish(ev, reference).await {
+            warn!("failed to publish {} event: {e}", ev.reason);
+            self.metrics.set_dropped(Dropped::Failed.as_str());
+            return Some(Dropped::Failed);
+        }
+        self.metrics.published.inc();
+        None
+    }
+
+    /// Decide whether an event can be published, consuming rate limit capacity if so
+    fn admit(&self, ev: &Event, reference: &ObjectReference) -> Result<(), Dropped> {
+        let now = Instant::now();
+        let (burst, window) = (f64::from(self.config.burst), self.config.dedup_window);
+        let mut history = self.history.lock().unwrap();
+        // forget objects that have been quiet for long enough to be back at full capacity,
+        // at most once per such period so that publishing stays cheap with many objects
+        let idle = window.max(self.config.refill * self.config.burst);
+        if now.duration_since(history.pruned) >= idle {
+            history
+                .objects
+                .retain(|_, h| now.duration_since(h.last_refill) < idle);
+            history.pruned = now;
+        }
+
+        let key = ObjectKey {
+            namespace: reference.namespace.clone(),
+            name: reference.name.clone(),
+            uid: reference.uid.clone(),
+        };
+        let object = history.objects.entry(key).or_insert_with(|| ObjectEvents {
+            tokens: burst,
+            last_refill: now,
+            published: HashMap::new(),
+        });
+        let refilled =
+            now.duration_since(object.last_refill).as_secs_f64() / self.config.refill.as_secs_f64();
+        object.tokens = (object.tokens + refilled).min(burst);
+        object.last_refill = now;
+
+        let event = EventKey {
+            type_: ev.type_,
+            reason: ev.reason.clone(),
+            note: ev.note.clone(),
+        };
+        object.published.retain(|_, at| now.duration_since(*at) < window);
+        if object.published.contains_key(&event) {
+            return Err(Dropped::Duplicate);
+        }
+        if object.tokens < 1.0 {
+            return Err(Dropped::RateLimited);
+        }
+        object.tokens -= 1.0;
+        object.published.insert(event, now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Dropped, EventConfig, EventPublisher};
+    use crate::{
+        Document, Metrics,
+        fixtures::{Scenario, mock_client, timeout_after_1s},
+    };
+    use kube::{
+        Resource,
+        runtime::events::{Event, EventType, Recorder},
+    };
+    use std::time::Duration;
+
+    fn event(reason: &str) -> Event {
+        Event {
+            type_: EventType::Normal,
+            reason: reason.into(),
+            note: None,
+            action: "Testing".into(),
+            secondary: None,
+        }
+    }
+
+    fn publisher(burst: u32, metrics: &Metrics) -> (EventPublisher, crate::fixtures::ApiServerVerifier) {
+        let (client, fakeserver) = mock_client();
+        let config = EventConfig {
+            dedup_window: Duration::from_secs(60),
+            burst,
+            refill: Duration::from_secs(60),
+        };
+        let recorder = Recorder::new(client, "doc-ctrl-test".into());
+        (
+            EventPublisher::new(recorder, config, metrics.events.clone()),
+            fakeserver,
+        )
+    }
+
+    #[tokio::test]
+    async fn repeated_events_are_deduplicated() {
+        let metrics = Metrics::default();
+        let (events, fakeserver) = publisher(10, &metrics);
+        let oref = Document::test().object_ref(&());
+        let mocksrv = fakeserver.run(Scenario::EventPublish("Flapping".into()));
+        assert_eq!(events.publish(&event("Flapping"), &oref).await, None);
+        assert_eq!(
+            events.publish(&event("Flapping"), &oref).await,
+            Some(Dropped::Duplicate)
+        );
+        timeout_after_1s(mocksrv).await;
+        assert_eq!(metrics.events.published.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn events_are_rate_limited_per_object() {
+        let metrics = Metrics::default();
+        let (events, fakeserver) = publisher(1, &metrics);
+        let oref = Document::test().object_ref(&());
+        let mocksrv = fakeserver.run(Scenario::EventPublish("First".into()));
+        assert_eq!(events.publish(&event("First"), &oref).await, None);
+        assert_eq!(
+            events.publish(&event("Second"), &oref).await,
+            Some(Dropped::RateLimited)
+        );
+        timeout_after_1s(mocksrv).await;
+        let dropped = metrics
+            .events
+            .dropped
+            .get_or_create(&crate::metrics::DroppedLabels {
+                reason: "rate_limited".into(),
+            });
+        assert_eq!(dropped.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn publish_failures_are_not_fatal() {
+        let metrics = Metrics::default();
+        let (events, fakeserver) = publisher(10, &metrics);
+        drop(fakeserver); // apiserver unavailable
+        let oref = Document::test().object_ref(&());
+        let res = events.publish(&event("Unheard"), &oref).await;
+        assert_eq!(res, Some(Dropped::Failed));
+    }
+
+    #[tokio::test]
+    async fn quiet_objects_are_forgotten_once_per_idle_period() {
+        let metrics = Metrics::default();
+        let (mut events, _fakeserver) = publisher(1, &metrics);
+        events.config = EventConfig {
+            dedup_window: Duration::from_millis(50),
+            burst: 1,
+            refill: Duration::from_millis(10),
+        };
+        let mut other = Document::test();
+        other.metadata.name = Some("other".into());
+        let (oref, other) = (Document::test().object_ref(&()), other.object_ref(&()));
+        assert_eq!(events.admit(&event("First"), &oref), Ok(()));
+        assert_eq!(events.admit(&event("First"), &other), Ok(()));
+        assert_eq!(events.history.lock().unwrap().objects.len(), 2);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(events.admit(&event("Second"), &other), Ok(()));
+        let history = events.history.lock().unwrap();
+        assert_eq!(
+            history.objects.len(),
+            1,
+            "only the object published again is kept"
+        );
+    }
+}
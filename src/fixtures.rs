@@ -1,9 +1,27 @@
 //! Helper methods only available for tests
-use crate::{Context, DOCUMENT_FINALIZER, Document, DocumentSpec, DocumentStatus, Result};
+use crate::{
+    ContentSource, Context, DOCUMENT_FINALIZER, Document, DocumentSpec, DocumentStatus, KeyRef, Metrics,
+    PAUSED_ANNOTATION, Result,
+    auth::Access,
+    cache::DocumentCache,
+    events::{EventConfig, EventPublisher},
+    notify::Notifier,
+    search::{self, SearchIndex},
+    shard::Sharder,
+};
 use assert_json_diff::assert_json_include;
 use http::{Request, Response};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, Time};
 use kube::{Client, Resource, ResourceExt, client::Body, runtime::events::Recorder};
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
 
 impl Document {
     /// A document that will cause the reconciler to fail
@@ -17,9 +35,17 @@ impl Document {
     pub fn test() -> Self {
         let mut d = Document::new("test", DocumentSpec::default());
         d.meta_mut().namespace = Some("default".into());
+        d.meta_mut().generation = Some(1);
         d
     }
 
+    /// Modify document to pause its reconciliation
+    pub fn paused(mut self) -> Self {
+        self.annotations_mut()
+            .insert(PAUSED_ANNOTATION.into(), "true".into());
+        self
+    }
+
     /// Modify document to be set to hide
     pub fn needs_hide(mut self) -> Self {
         self.spec.hide = true;
@@ -39,6 +65,43 @@ impl Document {
         self
     }
 
+    /// Modify a document to read its content from an external source
+    pub fn with_content_from(mut self, source: ContentSource) -> Self {
+        self.spec.content_from = Some(source);
+        self
+    }
+
+    /// Modify a document to read its content from the `content` key of a ConfigMap
+    pub fn with_content_config_map(self, name: &str) -> Self {
+        self.with_content_from(ContentSource {
+            config_map_key_ref: Some(KeyRef {
+                name: name.into(),
+                key: "content".into(),
+            }),
+            ..ContentSource::default()
+        })
+    }
+
+    /// Modify a document to have the status the reconciler would write for it
+    pub fn reconciled(self) -> Self {
+        let ready = Condition {
+            type_: "Ready".into(),
+            status: "True".into(),
+            reason: "Reconciled".into(),
+            message: String::new(),
+            last_transition_time: Time("2017-04-02T12:50:32Z".parse().unwrap()),
+            observed_generation: self.metadata.generation,
+        };
+        let hidden = self.spec.hide;
+        let observed_generation = self.metadata.generation;
+        self.with_status(DocumentStatus {
+            hidden,
+            observed_generation,
+            conditions: vec![ready],
+            ..DocumentStatus::default()
+        })
+    }
+
     /// Modify a document to have an expected status
     pub fn with_status(mut self, status: DocumentStatus) -> Self {
         self.status = Some(status);
@@ -56,12 +119,54 @@ pub enum Scenario {
     FinalizerCreation(Document),
     /// objects that do not fail and do not cause publishes will only patch
     StatusPatch(Document),
+    /// objects whose status is already up to date will not be patched
+    StatusUnchanged,
     /// finalized objects with hide set causes both an event and then a hide patch
     EventPublishThenStatusPatch(String, Document),
+    /// a single event is published
+    EventPublish(String),
+    /// failed reconciles publish a warning event then mark the document as not ready
+    FailureReported(String, Document),
     /// finalized objects "with errors" (i.e. the "illegal" object) will short circuit the apply loop
     RadioSilence,
-    /// objects with a deletion timestamp will run the cleanup loop sending event and removing the finalizer
+    /// objects with a deletion timestamp will run the cleanup loop setting the Deleting condition,
+    /// sending event and removing the finalizer
     Cleanup(String, Document),
+    /// objects whose cleanup already started only get their finalizer removed
+    CleanupRetry(Document),
+    /// objects referencing a ConfigMap for content will fail when the ConfigMap does not exist
+    ContentConfigMapMissing(String),
+    /// objects referencing a Secret for content fetch it (with the given name, key and value)
+    SecretFetch(String, String, String),
+    /// paused documents only record the Paused condition
+    PausedConditionPatch(Document),
+    /// authenticated requests review the token of the given user (if valid), then whether they are allowed access
+    AccessReview(Option<String>, Access, bool),
+    /// documents watched through their metadata are fetched in full before reconciling
+    DocumentFetch(Document),
+    /// docctl creates a document with the same spec (but hidden)
+    DocumentCreation(Document),
+    /// docctl patches the hide field of a document
+    HidePatch(Document),
+    /// docctl fetches a document and then its events (responding with an event of the given reason)
+    DocumentDescribe(Document, String),
+    /// documents are listed in the default namespace
+    DocumentList(Vec<Document>),
+    /// docctl fetches the existing document, then dry-runs applying the imported one
+    ImportDryRun(Document),
+    /// a DocumentSource sync checks its documents do not exist yet, applies them, prunes a stale one,
+    /// records the commit and publishes an event
+    SourceSync(Vec<String>, Document, String),
+    /// a DocumentSource synced at the commit before applies its documents again, without an event
+    SourceResync(Vec<String>, String),
+    /// a DocumentSource sync finds one of its documents created by someone else, and stops
+    SourceConflict(Document),
+    /// a finalizer sweep of the default namespace lists documents in all namespaces (or is forbidden to, when
+    /// `None`), then lists the default namespace and patches the finalizers of the given document
+    FinalizerSweep(Option<Vec<Document>>, Vec<Document>, Document),
+    /// a finalizer sweep lists a document being deleted, runs its cleanup, then fetches it again and patches the
+    /// finalizers of its latest version
+    FinalizerSweepCleanup(Box<[Document; 3]>),
 }
 
 pub async fn timeout_after_1s(handle: tokio::task::JoinHandle<()>) {
@@ -88,6 +193,7 @@ impl ApiServerVerifier {
             match scenario {
                 Scenario::FinalizerCreation(doc) => self.handle_finalizer_creation(doc).await,
                 Scenario::StatusPatch(doc) => self.handle_status_patch(doc).await,
+                Scenario::StatusUnchanged => Ok(self),
                 Scenario::EventPublishThenStatusPatch(reason, doc) => {
                     self.handle_event_create(reason)
                         .await
@@ -95,14 +201,128 @@ impl ApiServerVerifier {
                         .handle_status_patch(doc)
                         .await
                 }
+                Scenario::EventPublish(reason) => self.handle_event_create(reason).await,
+                Scenario::FailureReported(reason, doc) => {
+                    self.handle_event_create(reason.clone())
+                        .await
+                        .unwrap()
+                        .handle_condition_patch(("Ready", "False", reason), doc)
+                        .await
+                }
                 Scenario::RadioSilence => Ok(self),
                 Scenario::Cleanup(reason, doc) => {
-                    self.handle_event_create(reason)
+                    let condition = ("Deleting", "True", "CleaningUp".into());
+                    self.handle_condition_patch(condition, doc.clone())
+                        .await
+                        .unwrap()
+                        .handle_event_create(reason)
                         .await
                         .unwrap()
                         .handle_finalizer_removal(doc)
                         .await
                 }
+                Scenario::CleanupRetry(doc) => self.handle_finalizer_removal(doc).await,
+                Scenario::ContentConfigMapMissing(name) => self.handle_config_map_not_found(name).await,
+                Scenario::SecretFetch(name, key, value) => self.handle_secret_get(name, key, value).await,
+                Scenario::PausedConditionPatch(doc) => {
+                    let condition = ("Paused", "True", "PauseAnnotation".into());
+                    self.handle_condition_patch(condition, doc).await
+                }
+                Scenario::AccessReview(None, ..) => self.handle_token_review(None).await,
+                Scenario::AccessReview(user, access, allowed) => {
+                    self.handle_token_review(user)
+                        .await
+                        .unwrap()
+                        .handle_subject_access_review(access, allowed)
+                        .await
+                }
+                Scenario::DocumentFetch(doc) => self.handle_document_get(doc).await,
+                Scenario::DocumentCreation(doc) => self.handle_document_create(doc).await,
+                Scenario::HidePatch(doc) => self.handle_hide_patch(doc).await,
+                Scenario::DocumentList(docs) => self.handle_document_list(docs).await,
+                Scenario::FinalizerSweep(all, docs, doc) => {
+                    let verifier = match all {
+                        Some(all) => self.handle_all_documents_list(all).await,
+                        None => self.handle_all_documents_list_forbidden().await,
+                    };
+                    verifier
+                        .unwrap()
+                        .handle_document_list(docs)
+                        .await
+                        .unwrap()
+                        .handle_finalizers_patch(doc)
+                        .await
+                }
+                Scenario::FinalizerSweepCleanup(docs) => {
+                    let [listed, latest, patched] = *docs;
+                    let condition = ("Deleting", "True", "CleaningUp".into());
+                    self.handle_all_documents_list(vec![listed.clone()])
+                        .await
+                        .unwrap()
+                        .handle_condition_patch(condition, listed)
+                        .await
+                        .unwrap()
+                        .handle_event_create("DeleteRequested".into())
+                        .await
+                        .unwrap()
+                        .handle_document_get(latest)
+                        .await
+                        .unwrap()
+                        .handle_finalizers_patch(patched)
+                        .await
+                }
+                Scenario::SourceSync(names, stale, commit) => {
+                    let mut verifier = self;
+                    for name in &names {
+                        verifier = verifier.handle_document_not_found(name).await.unwrap();
+                    }
+                    for name in &names {
+                        verifier = verifier.handle_document_apply(name).await.unwrap();
+                    }
+                    verifier
+                        .handle_synced_document_list(Some(stale.clone()))
+                        .await
+                        .unwrap()
+                        .handle_document_delete(stale)
+                        .await
+                        .unwrap()
+                        .handle_source_status_patch(commit, names)
+                        .await
+                        .unwrap()
+                        .handle_event_create("Synced".into())
+                        .await
+                }
+                Scenario::SourceResync(names, commit) => {
+                    let mut verifier = self;
+                    for name in &names {
+                        verifier = verifier.handle_document_not_found(name).await.unwrap();
+                    }
+                    for name in &names {
+                        verifier = verifier.handle_document_apply(name).await.unwrap();
+                    }
+                    verifier
+                        .handle_synced_document_list(None)
+                        .await
+                        .unwrap()
+                        .handle_source_status_patch(commit, names)
+                        .await
+                }
+                Scenario::SourceConflict(doc) => self.handle_document_get(doc).await,
+                Scenario::ImportDryRun(doc) => {
+                    let name = doc.name_any();
+                    self.handle_document_get(doc)
+                        .await
+                        .unwrap()
+                        .handle_dry_run_apply(name)
+                        .await
+                }
+                Scenario::DocumentDescribe(doc, reason) => {
+                    self.handle_document_get(doc)
+                        .await
+                        .unwrap()
+                        .handle_event_list(reason)
+                        .await
+                }
             }
             .expect("scenario completed without errors");
         })
@@ -181,6 +401,375 @@ impl ApiServerVerifier {
         Ok(self)
     }
 
+    async fn handle_document_get(mut self, doc: Document) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::GET);
+        assert_eq!(
+            request.uri().to_string(),
+            format!("/apis/kube.rs/v1/namespaces/default/documents/{}", doc.name_any())
+        );
+        let response = serde_json::to_vec(&doc).unwrap();
+        send.send_response(Response::builder().body(Body::from(response)).unwrap());
+        Ok(self)
+    }
+
+    async fn handle_document_not_found(mut self, name: &str) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::GET);
+        assert_eq!(
+            request.uri().to_string(),
+            format!("/apis/kube.rs/v1/namespaces/default/documents/{name}")
+        );
+        let status = serde_json::json!({
+            "kind": "Status", "apiVersion": "v1", "metadata": {}, "status": "Failure",
+            "message": format!("documents.kube.rs \"{name}\" not found"), "reason": "NotFound", "code": 404
+        });
+        let response = serde_json::to_vec(&status).unwrap();
+        send.send_response(
+            Response::builder()
+                .status(http::StatusCode::NOT_FOUND)
+                .body(Body::from(response))
+                .unwrap(),
+        );
+        Ok(self)
+    }
+
+    async fn handle_document_create(mut self, doc: Document) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::POST);
+        assert_eq!(
+            request.uri().to_string(),
+            "/apis/kube.rs/v1/namespaces/default/documents?&fieldManager=docctl"
+        );
+        let req_body = request.into_body().collect_bytes().await.unwrap();
+        let created: Document = serde_json::from_slice(&req_body).expect("valid document from docctl");
+        assert_eq!(created.name_any(), doc.name_any());
+        assert!(created.spec.hide, "created hidden");
+        assert!(!created.spec.content.is_empty(), "content from the markdown file");
+        send.send_response(Response::builder().body(Body::from(req_body)).unwrap());
+        Ok(self)
+    }
+
+    async fn handle_hide_patch(mut self, doc: Document) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::PATCH);
+        assert_eq!(
+            request.uri().to_string(),
+            format!(
+                "/apis/kube.rs/v1/namespaces/default/documents/{}?&fieldManager=docctl",
+                doc.name_any()
+            )
+        );
+        let req_body = request.into_body().collect_bytes().await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&req_body).expect("patch is json");
+        assert_eq!(json, serde_json::json!({ "spec": { "hide": doc.spec.hide } }));
+        let response = serde_json::to_vec(&doc).unwrap();
+        send.send_response(Response::builder().body(Body::from(response)).unwrap());
+        Ok(self)
+    }
+
+    async fn handle_document_list(mut self, docs: Vec<Document>) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::GET);
+        assert_eq!(
+            request.uri().to_string(),
+            "/apis/kube.rs/v1/namespaces/default/documents?"
+        );
+        let list = serde_json::json!({
+            "apiVersion": "kube.rs/v1", "kind": "DocumentList", "metadata": {}, "items": docs
+        });
+        let response = serde_json::to_vec(&list).unwrap();
+        send.send_response(Response::builder().body(Body::from(response)).unwrap());
+        Ok(self)
+    }
+
+    async fn handle_all_documents_list(mut self, docs: Vec<Document>) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::GET);
+        assert_eq!(request.uri().to_string(), "/apis/kube.rs/v1/documents?");
+        let list = serde_json::json!({
+            "apiVersion": "kube.rs/v1", "kind": "DocumentList", "metadata": {}, "items": docs
+        });
+        let response = serde_json::to_vec(&list).unwrap();
+        send.send_response(Response::builder().body(Body::from(response)).unwrap());
+        Ok(self)
+    }
+
+    async fn handle_all_documents_list_forbidden(mut self) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::GET);
+        assert_eq!(request.uri().to_string(), "/apis/kube.rs/v1/documents?");
+        let status = serde_json::json!({
+            "kind": "Status", "apiVersion": "v1", "metadata": {}, "status": "Failure",
+            "message": "documents.kube.rs is forbidden", "reason": "Forbidden", "code": 403
+        });
+        let response = serde_json::to_vec(&status).unwrap();
+        send.send_response(
+            Response::builder()
+                .status(http::StatusCode::FORBIDDEN)
+                .body(Body::from(response))
+                .unwrap(),
+        );
+        Ok(self)
+    }
+
+    async fn handle_finalizers_patch(mut self, doc: Document) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::PATCH);
+        assert_eq!(
+            request.headers().get("Content-Type").unwrap(),
+            "application/merge-patch+json"
+        );
+        assert_eq!(
+            request.uri().to_string(),
+            format!(
+                "/apis/kube.rs/v1/namespaces/default/documents/{}?",
+                doc.name_any()
+            )
+        );
+        // the finalizers are replaced as a whole, guarded by the listed resourceVersion
+        let req_body = request.into_body().collect_bytes().await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&req_body).expect("patch is json");
+        assert_eq!(
+            json["metadata"]["resourceVersion"],
+            serde_json::json!(doc.resource_version())
+        );
+        assert_eq!(
+            json["metadata"]["finalizers"],
+            serde_json::json!(doc.finalizers())
+        );
+        let response = serde_json::to_vec(&doc).unwrap();
+        send.send_response(Response::builder().body(Body::from(response)).unwrap());
+        Ok(self)
+    }
+
+    async fn handle_dry_run_apply(mut self, name: String) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::PATCH);
+        assert_eq!(
+            request.headers().get("Content-Type").unwrap(),
+            "application/apply-patch+yaml"
+        );
+        let uri = request.uri().to_string();
+        assert!(
+            uri.starts_with(&format!("/apis/kube.rs/v1/namespaces/default/documents/{name}?")),
+            "{uri}"
+        );
+        assert!(uri.contains("dryRun=All"), "{uri}");
+        assert!(uri.contains("fieldManager=docctl"), "{uri}");
+        assert!(!uri.contains("force=true"), "{uri}");
+        // pass through the applied document as the apiserver would have merged it
+        let req_body = request.into_body().collect_bytes().await.unwrap();
+        send.send_response(Response::builder().body(Body::from(req_body)).unwrap());
+        Ok(self)
+    }
+
+    async fn handle_document_apply(mut self, name: &str) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::PATCH);
+        assert_eq!(
+            request.uri().to_string(),
+            format!("/apis/kube.rs/v1/namespaces/default/documents/{name}?&force=true&fieldManager=cntrlr")
+        );
+        let req_body = request.into_body().collect_bytes().await.unwrap();
+        let doc: Document = serde_json::from_slice(&req_body).expect("applied document");
+        assert_eq!(doc.labels()["kube.rs/source"], "guides");
+        assert_eq!(doc.owner_references().len(), 1);
+        send.send_response(Response::builder().body(Body::from(req_body)).unwrap());
+        Ok(self)
+    }
+
+    async fn handle_synced_document_list(mut self, stale: Option<Document>) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::GET);
+        assert_eq!(
+            request.uri().to_string(),
+            "/apis/kube.rs/v1/namespaces/default/documents?&labelSelector=kube.rs%2Fsource%3Dguides"
+        );
+        let list = serde_json::json!({
+            "apiVersion": "kube.rs/v1", "kind": "DocumentList", "metadata": {}, "items": Vec::from_iter(stale)
+        });
+        let response = serde_json::to_vec(&list).unwrap();
+        send.send_response(Response::builder().body(Body::from(response)).unwrap());
+        Ok(self)
+    }
+
+    async fn handle_document_delete(mut self, doc: Document) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::DELETE);
+        assert_eq!(
+            request.uri().to_string(),
+            format!(
+                "/apis/kube.rs/v1/namespaces/default/documents/{}?",
+                doc.name_any()
+            )
+        );
+        let response = serde_json::to_vec(&doc).unwrap();
+        send.send_response(Response::builder().body(Body::from(response)).unwrap());
+        Ok(self)
+    }
+
+    async fn handle_source_status_patch(mut self, commit: String, names: Vec<String>) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::PATCH);
+        assert_eq!(
+            request.uri().to_string(),
+            "/apis/kube.rs/v1/namespaces/default/documentsources/guides/status?"
+        );
+        let req_body = request.into_body().collect_bytes().await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&req_body).expect("patch is json");
+        assert_eq!(json["status"]["commit"], commit.as_str());
+        assert_eq!(json["status"]["documents"], serde_json::json!(names));
+        assert_eq!(json["status"]["conditions"][0]["reason"], "Synced");
+        let source = serde_json::json!({
+            "apiVersion": "kube.rs/v1", "kind": "DocumentSource",
+            "metadata": { "name": "guides", "namespace": "default" }, "spec": { "url": "file:///repo" }
+        });
+        let response = serde_json::to_vec(&source).unwrap();
+        send.send_response(Response::builder().body(Body::from(response)).unwrap());
+        Ok(self)
+    }
+
+    async fn handle_event_list(mut self, reason: String) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::GET);
+        let uri = request.uri().to_string();
+        assert!(
+            uri.starts_with("/apis/events.k8s.io/v1/namespaces/default/events?"),
+            "{uri}"
+        );
+        assert!(uri.contains("fieldSelector=regarding.kind%3DDocument"), "{uri}");
+        let list = serde_json::json!({
+            "apiVersion": "events.k8s.io/v1", "kind": "EventList", "metadata": {},
+            "items": [{
+                "metadata": { "name": "test.1", "namespace": "default" },
+                "eventTime": "2017-04-02T12:50:32.000000Z",
+                "reason": reason, "type": "Normal", "note": "Hiding Document",
+                "reportingController": "doc-ctrl-test", "action": "Hiding"
+            }]
+        });
+        let response = serde_json::to_vec(&list).unwrap();
+        send.send_response(Response::builder().body(Body::from(response)).unwrap());
+        Ok(self)
+    }
+
+    async fn handle_token_review(mut self, user: Option<String>) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::POST);
+        assert_eq!(
+            request.uri().to_string(),
+            "/apis/authentication.k8s.io/v1/tokenreviews?"
+        );
+        let req_body = request.into_body().collect_bytes().await.unwrap();
+        let mut review: serde_json::Value = serde_json::from_slice(&req_body).expect("review is json");
+        assert!(review["spec"]["token"].is_string());
+        review["status"] = match user {
+            Some(name) => serde_json::json!({
+                "authenticated": true, "user": { "username": name, "groups": ["system:authenticated"] }
+            }),
+            None => serde_json::json!({ "authenticated": false, "error": "invalid bearer token" }),
+        };
+        let response = serde_json::to_vec(&review).unwrap();
+        send.send_response(Response::builder().body(Body::from(response)).unwrap());
+        Ok(self)
+    }
+
+    async fn handle_subject_access_review(mut self, access: Access, allowed: bool) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::POST);
+        assert_eq!(
+            request.uri().to_string(),
+            "/apis/authorization.k8s.io/v1/subjectaccessreviews?"
+        );
+        let req_body = request.into_body().collect_bytes().await.unwrap();
+        let mut review: serde_json::Value = serde_json::from_slice(&req_body).expect("review is json");
+        assert_eq!(review["spec"]["resourceAttributes"]["resource"], "documents");
+        assert_eq!(review["spec"]["resourceAttributes"]["verb"], access.verb());
+        assert!(review["spec"]["user"].is_string());
+        review["status"] = serde_json::json!({ "allowed": allowed });
+        let response = serde_json::to_vec(&review).unwrap();
+        send.send_response(Response::builder().body(Body::from(response)).unwrap());
+        Ok(self)
+    }
+
+    async fn handle_secret_get(mut self, name: String, key: String, value: String) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::GET);
+        assert_eq!(
+            request.uri().to_string(),
+            format!("/api/v1/namespaces/default/secrets/{name}")
+        );
+        let data = std::collections::BTreeMap::from([(key, k8s_openapi::ByteString(value.into_bytes()))]);
+        let secret = k8s_openapi::api::core::v1::Secret {
+            metadata: kube::api::ObjectMeta {
+                name: Some(name),
+                namespace: Some("default".into()),
+                resource_version: Some("7".into()),
+                ..Default::default()
+            },
+            data: Some(data),
+            ..Default::default()
+        };
+        let response = serde_json::to_vec(&secret).unwrap();
+        send.send_response(Response::builder().body(Body::from(response)).unwrap());
+        Ok(self)
+    }
+
+    async fn handle_config_map_not_found(mut self, name: String) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::GET);
+        assert_eq!(
+            request.uri().to_string(),
+            format!("/api/v1/namespaces/default/configmaps/{name}")
+        );
+        let status = serde_json::json!({
+            "kind": "Status", "apiVersion": "v1", "metadata": {}, "status": "Failure",
+            "message": format!("configmaps \"{name}\" not found"), "reason": "NotFound", "code": 404
+        });
+        let response = serde_json::to_vec(&status).unwrap();
+        send.send_response(
+            Response::builder()
+                .status(http::StatusCode::NOT_FOUND)
+                .body(Body::from(response))
+                .unwrap(),
+        );
+        Ok(self)
+    }
+
+    async fn handle_condition_patch(
+        mut self,
+        (type_, status, reason): (&str, &str, String),
+        doc: Document,
+    ) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::PATCH);
+        assert_eq!(
+            request.headers().get("Content-Type").unwrap(),
+            "application/merge-patch+json"
+        );
+        assert_eq!(
+            request.uri().to_string(),
+            format!(
+                "/apis/kube.rs/v1/namespaces/default/documents/{}/status?",
+                doc.name_any()
+            )
+        );
+        let req_body = request.into_body().collect_bytes().await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&req_body).expect("patch is json");
+        let conditions = json["status"]["conditions"]
+            .as_array()
+            .expect("conditions are patched");
+        let condition = conditions
+            .iter()
+            .find(|c| c["type"] == type_)
+            .expect("condition is set");
+        assert_eq!(condition["status"], status);
+        assert_eq!(condition["reason"], reason.as_str());
+        let response = serde_json::to_vec(&doc).unwrap();
+        send.send_response(Response::builder().body(Body::from(response)).unwrap());
+        Ok(self)
+    }
+
     async fn handle_status_patch(mut self, doc: Document) -> Result<Self> {
         let (request, send) = self.0.next_request().await.expect("service not called");
         assert_eq!(request.method(), http::Method::PATCH);
@@ -196,6 +785,12 @@ impl ApiServerVerifier {
         let status_json = json.get("status").expect("status object").clone();
         let status: DocumentStatus = serde_json::from_value(status_json).expect("valid status");
         assert_eq!(status.hidden, doc.spec.hide, "status.hidden iff doc.spec.hide");
+        let ready = status
+            .conditions
+            .iter()
+            .find(|c| c.type_ == "Ready")
+            .expect("ready condition");
+        assert_eq!(ready.status, "True");
         let response = serde_json::to_vec(&doc.with_status(status)).unwrap();
         // pass through document "patch accepted"
         send.send_response(Response::builder().body(Body::from(response)).unwrap());
@@ -203,18 +798,123 @@ impl ApiServerVerifier {
     }
 }
 
+/// Create a kube client backed by a mocked apiserver
+pub fn mock_client() -> (Client, ApiServerVerifier) {
+    let (mock_service, handle) = tower_test::mock::pair::<Request<Body>, Response<Body>>();
+    (Client::new(mock_service, "default"), ApiServerVerifier(handle))
+}
+
 impl Context {
     // Create a test context with a mocked kube client, locally registered metrics and default diagnostics
     pub fn test() -> (Arc<Self>, ApiServerVerifier) {
-        let (mock_service, handle) = tower_test::mock::pair::<Request<Body>, Response<Body>>();
-        let mock_client = Client::new(mock_service, "default");
+        let (mock_client, verifier) = mock_client();
         let mock_recorder = Recorder::new(mock_client.clone(), "doc-ctrl-test".into());
+        let metrics = Arc::<Metrics>::default();
+        let events = EventPublisher::new(mock_recorder, EventConfig::default(), metrics.events.clone());
+        let notifier = Notifier::new(Default::default(), metrics.notify.clone());
+        let cache = DocumentCache::new(10, metrics.cache.clone());
+        let search = SearchIndex::new(search::DEFAULT_CAPACITY, metrics.search.clone());
         let ctx = Self {
             client: mock_client,
-            metrics: Arc::default(),
+            metrics,
             diagnostics: Arc::default(),
-            recorder: mock_recorder,
+            events,
+            notifier,
+            cache,
+            sharder: Sharder::default(),
+            paused: Arc::default(),
+            documents: Default::default(),
+            search,
+            config: Default::default(),
+            concurrency: Default::default(),
+            triggers: Default::default(),
         };
-        (Arc::new(ctx), ApiServerVerifier(handle))
+        (Arc::new(ctx), verifier)
+    }
+}
+
+/// A request received by the `MockWebhook`
+#[derive(Clone, Debug)]
+pub struct WebhookRequest {
+    /// Lowercased header names to values
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+/// Minimal local http server standing in for a webhook receiver
+///
+/// Responds with the given status codes in order (then 200s), and records every request.
+pub struct MockWebhook {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<WebhookRequest>>>,
+}
+
+impl MockWebhook {
+    pub async fn start(statuses: Vec<u16>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            let mut statuses = statuses.into_iter();
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let request = read_http_request(&mut stream).await;
+                recorded.lock().unwrap().push(request);
+                let status = statuses.next().unwrap_or(200);
+                let response =
+                    format!("HTTP/1.1 {status} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        Self { addr, requests }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}/hook", self.addr)
+    }
+
+    /// Wait (at most 1s) for a number of requests to have been received
+    pub async fn wait_for(&self, count: usize) -> Vec<WebhookRequest> {
+        let poll = async {
+            loop {
+                let requests = self.requests.lock().unwrap().clone();
+                if requests.len() >= count {
+                    return requests;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(1), poll)
+            .await
+            .expect("timeout waiting for webhook requests")
+    }
+}
+
+async fn read_http_request(stream: &mut tokio::net::TcpStream) -> WebhookRequest {
+    let mut buf = vec![];
+    let mut chunk = [0u8; 4096];
+    let header_end = loop {
+        let n = stream.read(&mut chunk).await.unwrap();
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+    let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let headers: HashMap<String, String> = head
+        .lines()
+        .skip(1)
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_lowercase(), v.trim().to_string()))
+        .collect();
+    let length: usize = headers.get("content-length").map_or(0, |l| l.parse().unwrap());
+    while buf.len() < header_end + length {
+        let n = stream.read(&mut chunk).await.unwrap();
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    WebhookRequest {
+        headers,
+        body: buf[header_end..header_end + length].to_vec(),
     }
 }
@@ -15,12 +15,119 @@ pub enum Error {
 
     #[error("IllegalDocument")]
     IllegalDocument,
+
+    #[error("MissingContentReference: {0}")]
+    MissingContentReference(String),
+
+    #[error("InvalidContentSource: {0}")]
+    InvalidContentSource(String),
+
+    #[error("ContentTooLarge: {0} bytes")]
+    ContentTooLarge(usize),
+
+    #[error("SourceSyncFailed: {0}")]
+    SourceSyncFailed(String),
+
+    #[error("InvalidDocumentSource: {0}")]
+    InvalidDocumentSource(String),
+
+    #[error("DocumentNotOwned: {0}")]
+    DocumentNotOwned(String),
 }
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// How a reconciliation error should be handled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// Transient failure that is likely to succeed when retried
+    Retryable,
+    /// Failure that needs a change to the Document or its environment
+    Permanent,
+    /// Lost a race against another writer; retry straight away
+    Conflict,
+}
+
+impl ErrorCategory {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCategory::Retryable => "retryable",
+            ErrorCategory::Permanent => "permanent",
+            ErrorCategory::Conflict => "conflict",
+        }
+    }
+}
+
 impl Error {
+    /// Stable short code identifying the kind of error (used as a metric label)
+    pub fn code(&self) -> &'static str {
+        self.classify().0
+    }
+
+    /// Whether the error is worth retrying
+    pub fn category(&self) -> ErrorCategory {
+        self.classify().1
+    }
+
     pub fn metric_label(&self) -> String {
-        format!("{self:?}").to_lowercase()
+        self.code().to_string()
+    }
+
+    fn classify(&self) -> (&'static str, ErrorCategory) {
+        use ErrorCategory::*;
+        use kube::runtime::finalizer::Error as FinalizerError;
+        match self {
+            Error::SerializationError(_) => ("serialization", Permanent),
+            Error::KubeError(e) => classify_kube(e),
+            // unwrap the boxed finalizer error so the reconciler error is classified on its own
+            Error::FinalizerError(e) => match &**e {
+                FinalizerError::ApplyFailed(e) | FinalizerError::CleanupFailed(e) => e.classify(),
+                FinalizerError::AddFinalizer(e) | FinalizerError::RemoveFinalizer(e) => classify_kube(e),
+                FinalizerError::UnnamedObject | FinalizerError::InvalidFinalizer => ("finalizer", Permanent),
+            },
+            Error::IllegalDocument => ("illegal_document", Permanent),
+            // the referenced object might be created later
+            Error::MissingContentReference(_) => ("missing_content_reference", Retryable),
+            Error::InvalidContentSource(_) => ("invalid_content_source", Permanent),
+            Error::ContentTooLarge(_) => ("content_too_large", Permanent),
+            // repositories might be unreachable for a while
+            Error::SourceSyncFailed(_) => ("source_sync_failed", Retryable),
+            Error::InvalidDocumentSource(_) => ("invalid_document_source", Permanent),
+            Error::DocumentNotOwned(_) => ("document_not_owned", Permanent),
+        }
+    }
+
+    /// PascalCase reason for the Warning event published when reconciliation fails
+    pub fn event_reason(&self) -> &'static str {
+        use kube::runtime::finalizer::Error as FinalizerError;
+        match self {
+            Error::SerializationError(_) => "SerializationFailed",
+            Error::KubeError(_) => "KubeRequestFailed",
+            Error::FinalizerError(e) => match &**e {
+                FinalizerError::ApplyFailed(e) | FinalizerError::CleanupFailed(e) => e.event_reason(),
+                _ => "FinalizerFailed",
+            },
+            Error::IllegalDocument => "IllegalDocument",
+            Error::MissingContentReference(_) => "MissingContentReference",
+            Error::InvalidContentSource(_) => "InvalidContentSource",
+            Error::ContentTooLarge(_) => "ContentTooLarge",
+            Error::SourceSyncFailed(_) => "SourceSyncFailed",
+            Error::InvalidDocumentSource(_) => "InvalidDocumentSource",
+            Error::DocumentNotOwned(_) => "DocumentNotOwned",
+        }
+    }
+}
+
+fn classify_kube(e: &kube::Error) -> (&'static str, ErrorCategory) {
+    use ErrorCategory::*;
+    match e {
+        kube::Error::Api(s) if s.is_conflict() => ("conflict", Conflict),
+        kube::Error::Api(s) if s.is_not_found() => ("not_found", Permanent),
+        kube::Error::Api(s) if s.is_forbidden() => ("forbidden", Permanent),
+        kube::Error::Api(s) if s.is_invalid() => ("invalid", Permanent),
+        kube::Error::Api(s) if s.code == 429 => ("throttled", Retryable),
+        kube::Error::Api(s) if s.code >= 500 => ("api_unavailable", Retryable),
+        kube::Error::Api(_) => ("api_rejected", Permanent),
+        _ => ("request_failed", Retryable),
     }
 }
 
@@ -28,6 +135,70 @@ impl Error {
 pub mod controller;
 pub use crate::controller::*;
 
+/// Configuration from a file, environment variables and flags
+pub mod config;
+
+/// Configuration changes at runtime
+pub mod reload;
+
+/// CustomResourceDefinitions and schemas (used by crdgen)
+pub mod crds;
+
+/// Commands of the docctl binary
+pub mod cli;
+
+/// Markdown exports of Documents
+pub mod archive;
+
+/// Documents synced from git repositories
+pub mod source;
+pub use source::DocumentSource;
+
+/// Reconciliation of several clusters
+pub mod cluster;
+
+/// Document cache for metadata-only watches
+pub mod cache;
+
+/// Sharding of Documents between replicas
+pub mod shard;
+
+/// Identity of the controller's own Pod
+pub mod pod;
+
+/// Authentication of the admin HTTP API
+pub mod auth;
+
+/// TLS with reloading certificates
+pub mod tls;
+
+/// Per-Document reconcile diagnostics
+pub mod diagnostics;
+
+/// Full-text search over Documents
+pub mod search;
+
+/// Stream of Document changes for the web server
+pub mod changes;
+
+/// Reconciles requested on demand
+pub mod trigger;
+
+/// Finalizer migration and removal
+pub mod sweep;
+
+/// Resolution of externally stored Document content
+pub mod content;
+
+/// Event publishing with deduplication and rate limiting
+pub mod events;
+
+/// Webhook notifications for Document lifecycle transitions
+pub mod notify;
+
+/// Watch event filtering
+pub mod predicates;
+
 /// Log and trace integrations
 pub mod telemetry;
 
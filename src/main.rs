@@ -1,9 +1,29 @@
 #![allow(unused_imports, unused_variables)]
 use actix_web::{
-    App, HttpRequest, HttpResponse, HttpServer, Responder, get, middleware, put, web, web::Data,
+    App, HttpRequest, HttpResponse, HttpServer, Responder,
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    get,
+    middleware::{self, Next},
+    post, put, web,
+    web::Data,
 };
-pub use controller::{self, State, telemetry};
+use clap::Parser;
+pub use controller::{
+    self, State,
+    auth::{Access, AuthMode, Authenticator, Denied},
+    cluster::ClusterError,
+    config::{Args, Config},
+    pod::PodInfo,
+    reload::Reloader,
+    telemetry,
+    tls::CertResolver,
+    trigger::Outcome,
+};
+use futures::StreamExt;
+use kube::runtime::events::Recorder;
 use serde::{Deserialize, Serialize};
+use tracing::*;
 use tracing_subscriber::EnvFilter;
 
 #[get("/metrics")]
@@ -25,6 +45,102 @@ async fn index(c: Data<State>, _req: HttpRequest) -> impl Responder {
     HttpResponse::Ok().json(&d)
 }
 
+#[derive(Deserialize)]
+struct ClusterQuery {
+    cluster: Option<String>,
+}
+
+/// The state of the cluster named by a `cluster` query parameter (needed when reconciling several clusters)
+fn cluster_state<'a>(c: &'a State, cluster: Option<&str>) -> Result<&'a State, HttpResponse> {
+    c.cluster(cluster).map_err(|e| {
+        let body = serde_json::json!({"error": e.to_string()});
+        match e {
+            ClusterError::Required => HttpResponse::BadRequest().json(body),
+            ClusterError::Unknown(_) => HttpResponse::NotFound().json(body),
+        }
+    })
+}
+
+async fn document_diagnostics(
+    c: Data<State>,
+    path: web::Path<(String, String)>,
+    query: web::Query<ClusterQuery>,
+) -> impl Responder {
+    let (namespace, name) = path.into_inner();
+    let state = match cluster_state(&c, query.cluster.as_deref()) {
+        Ok(state) => state,
+        Err(response) => return response,
+    };
+    match state.document_diagnostics(&namespace, &name) {
+        Some(d) => HttpResponse::Ok().json(d),
+        None => HttpResponse::NotFound().json(serde_json::json!({"error": "no reconciles recorded"})),
+    }
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    q: String,
+    namespace: Option<String>,
+    /// Only search one cluster when reconciling several
+    cluster: Option<String>,
+    #[serde(default = "default_search_limit")]
+    limit: usize,
+}
+
+fn default_search_limit() -> usize {
+    20
+}
+
+async fn search(c: Data<State>, query: web::Query<SearchQuery>) -> impl Responder {
+    if query.q.trim().is_empty() {
+        return HttpResponse::BadRequest().json(serde_json::json!({"error": "missing query parameter q"}));
+    }
+    let state = match query.cluster.as_deref() {
+        Some(cluster) => match cluster_state(&c, Some(cluster)) {
+            Ok(state) => state,
+            Err(response) => return response,
+        },
+        None => &c,
+    };
+    let hits = state.search(&query.q, query.namespace.as_deref(), query.limit);
+    let mut body = serde_json::json!({"query": query.q, "hits": hits});
+    // with sharding, hits only cover the Documents of the answering replica
+    if let Some(shard) = state.shard() {
+        body["shard"] = serde_json::json!(shard);
+    }
+    HttpResponse::Ok().json(body)
+}
+
+#[derive(Deserialize)]
+struct WatchQuery {
+    /// Comma separated namespaces
+    namespace: Option<String>,
+    since: Option<String>,
+}
+
+async fn watch(c: Data<State>, req: HttpRequest, query: web::Query<WatchQuery>) -> impl Responder {
+    // browsers reconnecting an EventSource send the id of the last event they received
+    let last_event_id = req.headers().get("Last-Event-ID").and_then(|id| id.to_str().ok());
+    let namespaces = query
+        .namespace
+        .iter()
+        .flat_map(|ns| ns.split(','))
+        .filter(|ns| !ns.is_empty())
+        .map(String::from)
+        .collect();
+    match c
+        .changes()
+        .stream(namespaces, last_event_id.or(query.since.as_deref()))
+    {
+        Ok(events) => HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header(("Cache-Control", "no-cache"))
+            .streaming(events.map(|e| Ok::<_, actix_web::Error>(web::Bytes::from(e)))),
+        Err(e) => HttpResponse::Gone().json(serde_json::json!({"error": e.to_string()})),
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 struct LogLevelBody {
     filter: String,
@@ -38,35 +154,297 @@ async fn log_level(
     match EnvFilter::try_new(&body.filter) {
         Ok(new_filter) => {
             handle.reload(new_filter).unwrap();
-            HttpResponse::Ok().json(&body.into_inner())
+            HttpResponse::Ok().json(body.into_inner())
         }
         Err(e) => HttpResponse::BadRequest().json(serde_json::json!({"error": e.to_string()})),
     }
 }
 
+#[derive(Deserialize, Serialize)]
+struct PausedBody {
+    paused: bool,
+}
+
+#[put("/paused")]
+async fn paused(c: Data<State>, body: web::Json<PausedBody>) -> impl Responder {
+    c.set_paused(body.paused);
+    HttpResponse::Ok().json(body.into_inner())
+}
+
+/// Require a bearer token authorized to change the controller
+async fn require_admin(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    require(Access::Admin, req, next).await
+}
+
+/// Require a bearer token authorized to read Documents
+async fn require_reader(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    require(Access::Read, req, next).await
+}
+
+async fn require(
+    access: Access,
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let auth = req
+        .app_data::<Data<Authenticator>>()
+        .expect("authenticator is configured");
+    let bearer = req
+        .headers()
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    let denied = match auth.authorize(bearer, access).await {
+        Ok(user) => {
+            info!("{access:?} request {} {} by {user}", req.method(), req.path());
+            return Ok(next.call(req).await?.map_into_left_body());
+        }
+        Err(Denied::Unauthenticated(e)) => HttpResponse::Unauthorized().json(serde_json::json!({"error": e})),
+        Err(Denied::Forbidden(e)) => HttpResponse::Forbidden().json(serde_json::json!({"error": e})),
+    };
+    Ok(req.into_response(denied).map_into_right_body())
+}
+
+#[get("/config")]
+async fn active_config(c: Data<State>) -> impl Responder {
+    HttpResponse::Ok().json(c.config().redacted())
+}
+
+#[derive(Deserialize)]
+struct ReconcileQuery {
+    /// Seconds to wait for the reconcile to finish before returning a request to poll
+    #[serde(default = "default_wait")]
+    wait: u64,
+    cluster: Option<String>,
+}
+
+fn default_wait() -> u64 {
+    10
+}
+
+#[post("/reconcile/{namespace}/{name}")]
+async fn reconcile(
+    c: Data<State>,
+    path: web::Path<(String, String)>,
+    query: web::Query<ReconcileQuery>,
+) -> impl Responder {
+    let (namespace, name) = path.into_inner();
+    let state = match cluster_state(&c, query.cluster.as_deref()) {
+        Ok(state) => state,
+        Err(response) => return response,
+    };
+    let request = state.triggers().request(&namespace, &name);
+    let wait = std::time::Duration::from_secs(query.wait.min(60));
+    match state.triggers().wait(request.id, wait).await {
+        Some(r) if r.outcome != Outcome::Pending => HttpResponse::Ok().json(r),
+        _ => HttpResponse::Accepted().json(request),
+    }
+}
+
+#[post("/reconcile/{namespace}")]
+async fn reconcile_namespace(
+    c: Data<State>,
+    namespace: web::Path<String>,
+    query: web::Query<ClusterQuery>,
+) -> impl Responder {
+    match cluster_state(&c, query.cluster.as_deref()) {
+        Ok(state) => HttpResponse::Accepted().json(state.triggers().request_namespace(&namespace)),
+        Err(response) => response,
+    }
+}
+
+#[get("/reconcile/requests/{id}")]
+async fn reconcile_request(
+    c: Data<State>,
+    id: web::Path<u64>,
+    query: web::Query<ClusterQuery>,
+) -> impl Responder {
+    let state = match cluster_state(&c, query.cluster.as_deref()) {
+        Ok(state) => state,
+        Err(response) => return response,
+    };
+    match state.triggers().get(*id) {
+        Some(r) => HttpResponse::Ok().json(r),
+        None => HttpResponse::NotFound().json(serde_json::json!({"error": "unknown request"})),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SweepQuery {
+    /// Only report what the sweep would change
+    #[serde(default = "default_dry_run")]
+    dry_run: bool,
+    /// Remove the finalizer from all Documents instead of migrating legacy names
+    #[serde(default)]
+    uninstall: bool,
+    cluster: Option<String>,
+}
+
+fn default_dry_run() -> bool {
+    true
+}
+
+#[post("/finalizers/sweep")]
+async fn sweep_finalizers(c: Data<State>, query: web::Query<SweepQuery>) -> impl Responder {
+    let state = match cluster_state(&c, query.cluster.as_deref()) {
+        Ok(state) => state,
+        Err(response) => return response,
+    };
+    // reconciles would add the finalizer back
+    if query.uninstall && !query.dry_run && !state.is_paused() {
+        let error = "pause reconciliation before removing finalizers";
+        return HttpResponse::Conflict().json(serde_json::json!({ "error": error }));
+    }
+    // the pause only holds for the replica answering the request
+    if query.uninstall
+        && !query.dry_run
+        && let Some(shard) = state.shard()
+        && shard.members.len() > 1
+    {
+        let error = format!(
+            "scale the controller down to one replica before removing finalizers ({} replicas hold shard leases)",
+            shard.members.len()
+        );
+        return HttpResponse::Conflict().json(serde_json::json!({ "error": error }));
+    }
+    let client = match state.client().await {
+        Ok(client) => client,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(serde_json::json!({"error": format!("{e:#}")}));
+        }
+    };
+    match state
+        .sweeper(client)
+        .await
+        .sweep(query.uninstall, query.dry_run)
+        .await
+    {
+        Ok(summary) => HttpResponse::Ok().json(summary),
+        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({"error": e.to_string()})),
+    }
+}
+
+/// Endpoints that change the controller, served under `/admin`
+fn admin_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(log_level)
+        .service(active_config)
+        .service(paused)
+        .service(reconcile)
+        .service(reconcile_namespace)
+        .service(reconcile_request)
+        .service(sweep_finalizers);
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let reload_handle = telemetry::init().await;
+    let args = Args::parse();
+    let config = Config::load(&args).unwrap_or_else(|e| {
+        eprintln!("error: {e}");
+        std::process::exit(2);
+    });
+    let reload_handle = telemetry::init(&config.telemetry).await;
 
     // Initiatilize Kubernetes controller state
-    let state = State::default();
+    let state = State::default().with_config(config.clone());
     let controller = controller::run(state.clone());
+    let client = kube::Client::try_default().await?;
+    let mode = config.admin.mode().map_err(anyhow::Error::msg)?;
+    let auth_client = matches!(mode, AuthMode::Kubernetes).then(|| client.clone());
+    let authenticator = Authenticator::new(mode, auth_client);
+
+    // Apply changes of the config file, with events on the controller's pod
+    if let Some(reloader) = Reloader::new(args, state.clone()) {
+        let reloader = reloader.with_log_filter(reload_handle.clone());
+        // events need the pod from the downward API
+        match PodInfo::from_env() {
+            Some(pod) => {
+                let recorder = Recorder::new(client, config.controller.reporter.as_str().into());
+                reloader.with_events(recorder, pod.reference()).spawn();
+            }
+            None => reloader.spawn(),
+        }
+    }
+
+    let listeners = &config.server;
+    let tls = match config.tls.clone() {
+        Some(config) => {
+            let resolver = CertResolver::new(config)?;
+            resolver.spawn_reloader();
+            Some(resolver.server_config()?)
+        }
+        None => None,
+    };
 
-    // Start web server
-    let server = HttpServer::new(move || {
+    // Start web servers; probes and summary diagnostics, metrics, and authenticated reads and admin
+    let (probe_state, metrics_state) = (state.clone(), state.clone());
+    let probe_server = HttpServer::new(move || {
         App::new()
-            .app_data(Data::new(state.clone()))
-            .app_data(Data::new(reload_handle.clone()))
+            .app_data(Data::new(probe_state.clone()))
             .wrap(middleware::Logger::default().exclude("/health"))
             .service(index)
             .service(health)
+    })
+    .bind(listeners.http_bind)?
+    .shutdown_timeout(5);
+    let metrics_server = HttpServer::new(move || {
+        App::new()
+            .app_data(Data::new(metrics_state.clone()))
             .service(metrics)
-            .service(log_level)
     })
-    .bind("0.0.0.0:8080")?
+    .bind(listeners.metrics_bind)?
+    .shutdown_timeout(5);
+    let admin_server = HttpServer::new(move || {
+        App::new()
+            .app_data(Data::new(state.clone()))
+            .app_data(Data::new(reload_handle.clone()))
+            .app_data(Data::new(authenticator.clone()))
+            .wrap(middleware::Logger::default())
+            .service(
+                web::resource("/documents/{namespace}/{name}/diagnostics")
+                    .wrap(middleware::from_fn(require_reader))
+                    .get(document_diagnostics),
+            )
+            .service(
+                web::resource("/search")
+                    .wrap(middleware::from_fn(require_reader))
+                    .get(search),
+            )
+            .service(
+                web::resource("/watch")
+                    .wrap(middleware::from_fn(require_reader))
+                    .get(watch),
+            )
+            .service(
+                web::scope("/admin")
+                    .wrap(middleware::from_fn(require_admin))
+                    .configure(admin_routes),
+            )
+            // deprecated: admin endpoints at their original paths, e.g. `PUT /log-level`
+            .service(
+                web::scope("")
+                    .wrap(middleware::from_fn(require_admin))
+                    .wrap(middleware::DefaultHeaders::new().add(("Deprecation", "true")))
+                    .configure(admin_routes),
+            )
+    });
+    let admin_server = match tls {
+        Some(config) => admin_server.bind_rustls_0_23(listeners.admin_bind, config)?,
+        None => admin_server.bind(listeners.admin_bind)?,
+    }
     .shutdown_timeout(5);
 
-    // Both runtimes implements graceful shutdown, so poll until both are done
-    tokio::join!(controller, server.run()).1?;
+    // All runtimes implement graceful shutdown, so poll until all are done
+    let servers = (probe_server.run(), metrics_server.run(), admin_server.run());
+    let (_, probes, scrapes, admin) = tokio::join!(controller, servers.0, servers.1, servers.2);
+    probes?;
+    scrapes?;
+    admin?;
     Ok(())
 }
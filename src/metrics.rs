@@ -1,27 +1,79 @@
-use crate::{Document, Error};
+use crate::Error;
 use kube::ResourceExt;
 use opentelemetry::trace::TraceId;
 use prometheus_client::{
     encoding::EncodeLabelSet,
-    metrics::{counter::Counter, exemplar::HistogramWithExemplars, family::Family},
+    metrics::{
+        counter::Counter, exemplar::HistogramWithExemplars, family::Family, gauge::Gauge,
+        histogram::Histogram,
+    },
     registry::{Registry, Unit},
 };
-use std::sync::Arc;
+use std::{borrow::Cow, sync::Arc};
 use tokio::time::Instant;
 
 #[derive(Clone)]
 pub struct Metrics {
     pub reconcile: ReconcileMetrics,
+    pub notify: NotifyMetrics,
+    pub events: EventMetrics,
+    pub watch: WatchMetrics,
+    pub cache: CacheMetrics,
+    pub shard: ShardMetrics,
+    pub search: SearchMetrics,
     pub registry: Arc<Registry>,
 }
 
 impl Default for Metrics {
     fn default() -> Self {
-        let mut registry = Registry::with_prefix("doc_ctrl_reconcile");
-        let reconcile = ReconcileMetrics::default().register(&mut registry);
+        let mut registry = Registry::with_prefix("doc_ctrl");
+        let metrics = Self::register(&mut registry);
         Self {
             registry: Arc::new(registry),
+            ..metrics
+        }
+    }
+}
+
+impl Metrics {
+    /// Metrics of several clusters sharing one registry, labelled with the name of their cluster
+    pub fn for_clusters(names: &[String]) -> Vec<Self> {
+        let mut registry = Registry::with_prefix("doc_ctrl");
+        let metrics: Vec<_> = names
+            .iter()
+            .map(|name| {
+                let label = (Cow::Borrowed("cluster"), Cow::Owned(name.clone()));
+                Self::register(registry.sub_registry_with_label(label))
+            })
+            .collect();
+        let registry = Arc::new(registry);
+        metrics
+            .into_iter()
+            .map(|metrics| Self {
+                registry: registry.clone(),
+                ..metrics
+            })
+            .collect()
+    }
+
+    /// Register all metrics (the returned registry is a placeholder)
+    fn register(registry: &mut Registry) -> Self {
+        let reconcile = ReconcileMetrics::default().register(registry.sub_registry_with_prefix("reconcile"));
+        let notify = NotifyMetrics::default().register(registry.sub_registry_with_prefix("notify"));
+        let events = EventMetrics::default().register(registry.sub_registry_with_prefix("events"));
+        let watch = WatchMetrics::default().register(registry.sub_registry_with_prefix("watch"));
+        let cache = CacheMetrics::default().register(registry.sub_registry_with_prefix("cache"));
+        let shard = ShardMetrics::default().register(registry.sub_registry_with_prefix("shard"));
+        let search = SearchMetrics::default().register(registry.sub_registry_with_prefix("search"));
+        Self {
+            registry: Arc::default(),
             reconcile,
+            notify,
+            events,
+            watch,
+            cache,
+            shard,
+            search,
         }
     }
 }
@@ -47,7 +99,10 @@ impl TryFrom<&TraceId> for TraceLabel {
 pub struct ReconcileMetrics {
     pub runs: Counter,
     pub failures: Family<ErrorLabels, Counter>,
+    pub status_writes: Family<StatusWriteLabels, Counter>,
     pub duration: HistogramWithExemplars<TraceLabel>,
+    pub paused: Gauge,
+    pub paused_skips: Family<PauseLabels, Counter>,
 }
 
 impl Default for ReconcileMetrics {
@@ -55,7 +110,10 @@ impl Default for ReconcileMetrics {
         Self {
             runs: Counter::default(),
             failures: Family::<ErrorLabels, Counter>::default(),
+            status_writes: Family::<StatusWriteLabels, Counter>::default(),
             duration: HistogramWithExemplars::new([0.01, 0.1, 0.25, 0.5, 1., 5., 15., 60.].into_iter()),
+            paused: Gauge::default(),
+            paused_skips: Family::<PauseLabels, Counter>::default(),
         }
     }
 }
@@ -64,6 +122,17 @@ impl Default for ReconcileMetrics {
 pub struct ErrorLabels {
     pub instance: String,
     pub error: String,
+    pub category: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct StatusWriteLabels {
+    pub result: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct PauseLabels {
+    pub scope: String,
 }
 
 impl ReconcileMetrics {
@@ -77,14 +146,44 @@ impl ReconcileMetrics {
         );
         r.register("failures", "reconciliation errors", self.failures.clone());
         r.register("runs", "reconciliations", self.runs.clone());
+        r.register(
+            "status_writes",
+            "status patches applied or skipped as unchanged",
+            self.status_writes.clone(),
+        );
+        r.register(
+            "paused",
+            "whether all reconciliation is paused",
+            self.paused.clone(),
+        );
+        r.register(
+            "paused_skips",
+            "reconciles skipped while globally or per document paused",
+            self.paused_skips.clone(),
+        );
         self
     }
 
-    pub fn set_failure(&self, doc: &Document, e: &Error) {
+    pub fn set_status_write(&self, result: &str) {
+        self.status_writes
+            .get_or_create(&StatusWriteLabels {
+                result: result.into(),
+            })
+            .inc();
+    }
+
+    pub fn set_paused_skip(&self, scope: &str) {
+        self.paused_skips
+            .get_or_create(&PauseLabels { scope: scope.into() })
+            .inc();
+    }
+
+    pub fn set_failure(&self, doc: &impl ResourceExt, e: &Error) {
         self.failures
             .get_or_create(&ErrorLabels {
                 instance: doc.name_any(),
                 error: e.metric_label(),
+                category: e.category().as_str().into(),
             })
             .inc();
     }
@@ -99,6 +198,202 @@ impl ReconcileMetrics {
     }
 }
 
+#[derive(Clone, Default)]
+pub struct NotifyMetrics {
+    pub deliveries: Family<DeliveryLabels, Counter>,
+    pub retries: Counter,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DeliveryLabels {
+    pub transition: String,
+    pub status: String,
+}
+
+impl NotifyMetrics {
+    /// Register notification metrics to start tracking them.
+    pub fn register(self, r: &mut Registry) -> Self {
+        r.register(
+            "deliveries",
+            "webhook notification deliveries by final status",
+            self.deliveries.clone(),
+        );
+        r.register("retries", "webhook notification retries", self.retries.clone());
+        self
+    }
+
+    pub fn set_delivery(&self, transition: &str, status: &str) {
+        self.deliveries
+            .get_or_create(&DeliveryLabels {
+                transition: transition.into(),
+                status: status.into(),
+            })
+            .inc();
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct EventMetrics {
+    pub published: Counter,
+    pub dropped: Family<DroppedLabels, Counter>,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DroppedLabels {
+    pub reason: String,
+}
+
+impl EventMetrics {
+    /// Register event metrics to start tracking them.
+    pub fn register(self, r: &mut Registry) -> Self {
+        r.register("published", "kubernetes events published", self.published.clone());
+        r.register("dropped", "kubernetes events not published", self.dropped.clone());
+        self
+    }
+
+    pub fn set_dropped(&self, reason: &str) {
+        self.dropped
+            .get_or_create(&DroppedLabels {
+                reason: reason.into(),
+            })
+            .inc();
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct WatchMetrics {
+    pub events: Family<WatchEventLabels, Counter>,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct WatchEventLabels {
+    pub result: String,
+}
+
+impl WatchMetrics {
+    /// Register watch metrics to start tracking them.
+    pub fn register(self, r: &mut Registry) -> Self {
+        r.register(
+            "events",
+            "watch events passed to or filtered from the reconciler",
+            self.events.clone(),
+        );
+        self
+    }
+
+    pub fn set_event(&self, result: &str) {
+        self.events
+            .get_or_create(&WatchEventLabels {
+                result: result.into(),
+            })
+            .inc();
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct CacheMetrics {
+    pub objects: Gauge,
+    pub bytes: Gauge,
+    pub hits: Counter,
+    pub misses: Counter,
+}
+
+impl CacheMetrics {
+    /// Register document cache metrics to start tracking them.
+    pub fn register(self, r: &mut Registry) -> Self {
+        r.register("objects", "documents held by the cache", self.objects.clone());
+        r.register_with_unit(
+            "size",
+            "approximate serialized size of cached documents",
+            Unit::Bytes,
+            self.bytes.clone(),
+        );
+        r.register("hits", "cache lookups served from memory", self.hits.clone());
+        r.register(
+            "misses",
+            "cache lookups fetched from the apiserver",
+            self.misses.clone(),
+        );
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct SearchMetrics {
+    pub documents: Gauge,
+    pub terms: Gauge,
+    pub query_duration: Histogram,
+}
+
+impl Default for SearchMetrics {
+    fn default() -> Self {
+        Self {
+            documents: Gauge::default(),
+            terms: Gauge::default(),
+            query_duration: Histogram::new([0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1]),
+        }
+    }
+}
+
+impl SearchMetrics {
+    /// Register search index metrics to start tracking them.
+    pub fn register(self, r: &mut Registry) -> Self {
+        r.register(
+            "documents",
+            "documents in the search index",
+            self.documents.clone(),
+        );
+        r.register("terms", "distinct terms in the search index", self.terms.clone());
+        r.register_with_unit(
+            "query_duration",
+            "search query duration",
+            Unit::Seconds,
+            self.query_duration.clone(),
+        );
+        self
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct ShardMetrics {
+    pub identity: Family<ShardLabels, Gauge>,
+    pub members: Gauge,
+    pub rebalances: Counter,
+    pub skipped: Counter,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ShardLabels {
+    pub identity: String,
+}
+
+impl ShardMetrics {
+    /// Register shard metrics to start tracking them.
+    pub fn register(self, r: &mut Registry) -> Self {
+        r.register("info", "shard identity of this replica", self.identity.clone());
+        r.register("members", "replicas sharing the Documents", self.members.clone());
+        r.register(
+            "rebalances",
+            "changes to shard membership",
+            self.rebalances.clone(),
+        );
+        r.register(
+            "skipped",
+            "reconciles skipped for Documents owned by other replicas",
+            self.skipped.clone(),
+        );
+        self
+    }
+
+    pub fn set_identity(&self, identity: &str) {
+        self.identity
+            .get_or_create(&ShardLabels {
+                identity: identity.into(),
+            })
+            .set(1);
+    }
+}
+
 /// Smart function duration measurer
 ///
 /// Relies on Drop to calculate duration and register the observation in the histogram
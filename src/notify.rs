@@ -0,0 +1,210 @@
+//! Outbound webhook notifications for Document lifecycle transitions
+use crate::{Document, config::Reloadable, metrics::NotifyMetrics};
+use hmac::{Hmac, Mac};
+use jiff::Timestamp;
+use kube::ResourceExt;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::time::Duration;
+use tracing::*;
+
+/// Header carrying the hex encoded HMAC-SHA256 signature of the request body
+pub const SIGNATURE_HEADER: &str = "X-Doc-Signature";
+
+/// Lifecycle transitions of a Document that are announced to webhooks
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transition {
+    Created,
+    Hidden,
+    Unhidden,
+    Deleted,
+}
+
+impl Transition {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Transition::Created => "created",
+            Transition::Hidden => "hidden",
+            Transition::Unhidden => "unhidden",
+            Transition::Deleted => "deleted",
+        }
+    }
+}
+
+/// Webhook notification settings
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct NotifierConfig {
+    /// Endpoints receiving a POST for every transition
+    pub endpoints: Vec<String>,
+    /// Shared secret used to sign payloads
+    pub secret: Option<String>,
+    /// Number of retries after a failed delivery
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every subsequent retry
+    #[serde(with = "crate::config::duration")]
+    pub backoff: Duration,
+}
+
+impl Default for NotifierConfig {
+    fn default() -> Self {
+        Self {
+            endpoints: vec![],
+            secret: None,
+            max_retries: 3,
+            backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+/// The json body POSTed to webhooks
+#[derive(Serialize, Debug)]
+pub struct Notification {
+    pub transition: Transition,
+    pub namespace: String,
+    pub name: String,
+    pub uid: Option<String>,
+    pub title: String,
+    pub timestamp: Timestamp,
+}
+
+/// Delivers notifications to the configured webhooks in the background
+#[derive(Clone)]
+pub struct Notifier {
+    config: Reloadable<NotifierConfig>,
+    http: reqwest::Client,
+    metrics: NotifyMetrics,
+}
+
+impl Notifier {
+    pub fn new(config: Reloadable<NotifierConfig>, metrics: NotifyMetrics) -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("failed to create http client");
+        Self {
+            config,
+            http,
+            metrics,
+        }
+    }
+
+    /// Queue a notification about a Document transition to all endpoints
+    ///
+    /// Delivery happens on a separate task so that slow webhooks do not hold up reconciliation.
+    pub fn notify(&self, doc: &Document, transition: Transition) {
+        let config = self.config.get();
+        if config.endpoints.is_empty() {
+            return;
+        }
+        let notification = Notification {
+            transition,
+            namespace: doc.namespace().unwrap_or_default(),
+            name: doc.name_any(),
+            uid: doc.uid(),
+            title: doc.spec.title.clone(),
+            timestamp: Timestamp::now(),
+        };
+        let body = serde_json::to_vec(&notification).expect("notification serializes");
+        for endpoint in &config.endpoints {
+            let (notifier, endpoint, body) = (self.clone(), endpoint.clone(), body.clone());
+            tokio::spawn(async move { notifier.deliver(&endpoint, transition, body).await });
+        }
+    }
+
+    /// Deliver a payload to an endpoint with retries, returning whether it was accepted
+    pub async fn deliver(&self, endpoint: &str, transition: Transition, body: Vec<u8>) -> bool {
+        let config = self.config.get();
+        let mut backoff = config.backoff;
+        for attempt in 0..=config.max_retries {
+            if attempt > 0 {
+                self.metrics.retries.inc();
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            let mut req = self
+                .http
+                .post(endpoint)
+                .header("Content-Type", "application/json")
+                .body(body.clone());
+            if let Some(secret) = &config.secret {
+                req = req.header(SIGNATURE_HEADER, format!("sha256={}", sign(secret, &body)));
+            }
+            match req.send().await.and_then(|r| r.error_for_status()) {
+                Ok(_) => {
+                    self.metrics.set_delivery(transition.as_str(), "delivered");
+                    return true;
+                }
+                Err(e) => warn!("notification to {endpoint} failed (attempt {}): {e}", attempt + 1),
+            }
+        }
+        self.metrics.set_delivery(transition.as_str(), "failed");
+        false
+    }
+}
+
+/// Hex encoded HMAC-SHA256 of a payload
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(body);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Notifier, NotifierConfig, SIGNATURE_HEADER, Transition, sign};
+    use crate::{Document, Metrics, fixtures::MockWebhook};
+    use std::time::Duration;
+
+    fn notifier(endpoint: String, metrics: &Metrics) -> Notifier {
+        let config = NotifierConfig {
+            endpoints: vec![endpoint],
+            secret: Some("s3cr3t".into()),
+            max_retries: 2,
+            backoff: Duration::from_millis(10),
+        };
+        Notifier::new(config.into(), metrics.notify.clone())
+    }
+
+    #[tokio::test]
+    async fn notification_is_signed_and_retried_until_delivered() {
+        let webhook = MockWebhook::start(vec![500, 200]).await;
+        let metrics = Metrics::default();
+        let notifier = notifier(webhook.url(), &metrics);
+        notifier.notify(&Document::test(), Transition::Hidden);
+
+        let requests = webhook.wait_for(2).await;
+        let last = requests.last().unwrap();
+        let payload: serde_json::Value = serde_json::from_slice(&last.body).unwrap();
+        assert_eq!(payload["transition"], "hidden");
+        assert_eq!(payload["name"], "test");
+        let signature = last.headers.get(&SIGNATURE_HEADER.to_lowercase()).unwrap();
+        assert_eq!(signature, &format!("sha256={}", sign("s3cr3t", &last.body)));
+        assert_eq!(metrics.notify.retries.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn notification_delivery_gives_up_after_max_retries() {
+        let webhook = MockWebhook::start(vec![503, 503, 503]).await;
+        let metrics = Metrics::default();
+        let notifier = notifier(webhook.url(), &metrics);
+        let delivered = notifier
+            .deliver(&webhook.url(), Transition::Deleted, b"{}".to_vec())
+            .await;
+        assert!(!delivered);
+        assert_eq!(webhook.wait_for(3).await.len(), 3);
+        let failed = metrics
+            .notify
+            .deliveries
+            .get_or_create(&crate::metrics::DeliveryLabels {
+                transition: "deleted".into(),
+                status: "failed".into(),
+            });
+        assert_eq!(failed.get(), 1);
+    }
+}
@@ -0,0 +1,71 @@
+//! Identity of the controller's own Pod, from the downward API
+use k8s_openapi::{api::core::v1::ObjectReference, apimachinery::pkg::apis::meta::v1::OwnerReference};
+
+/// Name, namespace and uid of the Pod the controller runs in
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PodInfo {
+    pub name: String,
+    pub namespace: String,
+    /// Set when `POD_UID` is exposed, needed to own objects
+    pub uid: Option<String>,
+}
+
+impl PodInfo {
+    /// Read `POD_NAME`, `POD_NAMESPACE` and `POD_UID`, or `None` when not running in a Pod
+    pub fn from_env() -> Option<Self> {
+        Self::from_vars(|key| std::env::var(key).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        let var = |key| var(key).filter(|v| !v.is_empty());
+        Some(Self {
+            name: var("POD_NAME")?,
+            namespace: var("POD_NAMESPACE")?,
+            uid: var("POD_UID"),
+        })
+    }
+
+    /// Reference to the Pod, e.g. as the target of events
+    pub fn reference(&self) -> ObjectReference {
+        ObjectReference {
+            api_version: Some("v1".into()),
+            kind: Some("Pod".into()),
+            name: Some(self.name.clone()),
+            namespace: Some(self.namespace.clone()),
+            uid: self.uid.clone(),
+            ..ObjectReference::default()
+        }
+    }
+
+    /// Owner reference to the Pod, so that objects of this replica are deleted with it
+    pub fn owner_reference(&self) -> Option<OwnerReference> {
+        Some(OwnerReference {
+            api_version: "v1".into(),
+            kind: "Pod".into(),
+            name: self.name.clone(),
+            uid: self.uid.clone()?,
+            ..OwnerReference::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PodInfo;
+    use std::collections::HashMap;
+
+    #[test]
+    fn pod_identity_comes_from_the_downward_api() {
+        let env = HashMap::from([("POD_NAME", "ctrl-0"), ("POD_NAMESPACE", "docs"), ("POD_UID", "")]);
+        let pod = PodInfo::from_vars(|key| env.get(key).map(|v| v.to_string())).unwrap();
+        assert_eq!(pod.reference().name.as_deref(), Some("ctrl-0"));
+        assert_eq!(pod.reference().namespace.as_deref(), Some("docs"));
+        assert_eq!(pod.owner_reference(), None, "objects are not owned without a uid");
+
+        let env = HashMap::from([("POD_NAME", "ctrl-0")]);
+        assert_eq!(
+            PodInfo::from_vars(|key| env.get(key).map(|v| v.to_string())),
+            None
+        );
+    }
+}
@@ -0,0 +1,205 @@
+//! Filtering of watch events that should not cause a reconcile
+use crate::metrics::WatchMetrics;
+use futures::{Stream, StreamExt, future};
+use kube::{Resource, ResourceExt, runtime::watcher};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, hash_map::DefaultHasher},
+    hash::{Hash, Hasher},
+};
+
+/// Which changes to an object enqueue a reconcile
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct PredicateConfig {
+    /// Filter watch events; when disabled every change (including status writes) reconciles
+    pub enabled: bool,
+    /// Labels with these prefixes are relevant to the reconciler
+    pub label_prefixes: Vec<String>,
+    /// Annotations with these prefixes are relevant to the reconciler
+    pub annotation_prefixes: Vec<String>,
+}
+
+impl Default for PredicateConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            label_prefixes: vec![],
+            annotation_prefixes: vec!["kube.rs/".into()],
+        }
+    }
+}
+
+impl PredicateConfig {
+    /// Hash of the parts of an object that matter to the reconciler
+    fn fingerprint<K: Resource>(&self, obj: &K) -> u64 {
+        let relevant = |map: &BTreeMap<String, String>, prefixes: &[String]| {
+            map.iter()
+                .filter(|(k, _)| prefixes.iter().any(|p| k.starts_with(p.as_str())))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect::<Vec<_>>()
+        };
+        let mut hasher = DefaultHasher::new();
+        obj.meta().generation.hash(&mut hasher);
+        relevant(obj.labels(), &self.label_prefixes).hash(&mut hasher);
+        relevant(obj.annotations(), &self.annotation_prefixes).hash(&mut hasher);
+        obj.finalizers().hash(&mut hasher);
+        obj.meta().deletion_timestamp.is_some().hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// Whether a watch event concerns an object in one of the namespaces (any namespace when empty)
+pub fn in_namespaces<K: Resource>(namespaces: Vec<String>) -> impl Fn(&watcher::Event<K>) -> bool {
+    move |event| match event {
+        watcher::Event::Apply(obj) | watcher::Event::InitApply(obj) | watcher::Event::Delete(obj) => {
+            namespaces.is_empty() || obj.namespace().is_some_and(|ns| namespaces.contains(&ns))
+        }
+        watcher::Event::Init | watcher::Event::InitDone => true,
+    }
+}
+
+/// Drop applied objects from a watch event stream unless a relevant part of them changed since last seen
+///
+/// Fingerprints are forgotten when an object is deleted, and a relist forgets every object it did not list.
+/// Errors are always passed through so the controller can handle them.
+pub fn filter_changes<K, S>(
+    stream: S,
+    config: PredicateConfig,
+    metrics: WatchMetrics,
+) -> impl Stream<Item = Result<K, watcher::Error>> + Send
+where
+    K: Resource + Send,
+    S: Stream<Item = Result<watcher::Event<K>, watcher::Error>> + Send,
+{
+    let key = |obj: &K| {
+        obj.uid()
+            .unwrap_or_else(|| format!("{:?}/{}", obj.namespace(), obj.name_any()))
+    };
+    // fingerprint of every object, with the number of the list it was last seen in
+    let mut seen: HashMap<String, (u64, u64)> = HashMap::new();
+    let mut relists = 0;
+    stream.filter_map(move |res| {
+        let obj = match res {
+            Err(e) => return future::ready(Some(Err(e))),
+            Ok(watcher::Event::Apply(obj) | watcher::Event::InitApply(obj)) => obj,
+            Ok(watcher::Event::Delete(obj)) => {
+                seen.remove(&key(&obj));
+                return future::ready(None);
+            }
+            Ok(watcher::Event::Init) => {
+                relists += 1;
+                return future::ready(None);
+            }
+            Ok(watcher::Event::InitDone) => {
+                seen.retain(|_, (_, list)| *list == relists);
+                return future::ready(None);
+            }
+        };
+        let pass = !config.enabled || {
+            let hash = config.fingerprint(&obj);
+            let previous = seen.insert(key(&obj), (hash, relists));
+            previous.map(|(h, _)| h) != Some(hash)
+        };
+        metrics.set_event(if pass { "passed" } else { "filtered" });
+        future::ready(pass.then_some(Ok(obj)))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PredicateConfig, filter_changes, in_namespaces};
+    use crate::{Document, DocumentStatus, Metrics, metrics::WatchEventLabels};
+    use futures::{StreamExt, stream};
+    use kube::{Resource, ResourceExt, runtime::watcher::Event};
+
+    #[tokio::test]
+    async fn only_relevant_changes_pass_the_filter() {
+        let doc = Document::test();
+        let mut status_change = doc.clone().with_status(DocumentStatus::default());
+        status_change.meta_mut().resource_version = Some("2".into());
+        let mut unrelated_annotation = status_change.clone();
+        unrelated_annotation
+            .annotations_mut()
+            .insert("example.com/note".into(), "x".into());
+        let mut relevant_annotation = unrelated_annotation.clone();
+        relevant_annotation
+            .annotations_mut()
+            .insert("kube.rs/paused".into(), "true".into());
+        let mut generation_bump = relevant_annotation.clone();
+        generation_bump.meta_mut().generation = Some(2);
+        let finalized = generation_bump.clone().finalized();
+
+        let events = vec![
+            doc,
+            status_change,
+            unrelated_annotation,
+            relevant_annotation,
+            generation_bump,
+            finalized,
+        ];
+        let metrics = Metrics::default();
+        let input = stream::iter(events.into_iter().map(|doc| Ok(Event::Apply(doc))));
+        let passed: Vec<_> = filter_changes(input, PredicateConfig::default(), metrics.watch.clone())
+            .collect()
+            .await;
+        assert_eq!(passed.len(), 4);
+
+        let filtered = metrics.watch.events.get_or_create(&WatchEventLabels {
+            result: "filtered".into(),
+        });
+        assert_eq!(filtered.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn disabled_filter_passes_everything() {
+        let doc = Document::test();
+        let config = PredicateConfig {
+            enabled: false,
+            ..PredicateConfig::default()
+        };
+        let input = stream::iter(vec![Ok(Event::Apply(doc.clone())), Ok(Event::Apply(doc))]);
+        let passed: Vec<_> = filter_changes(input, config, Metrics::default().watch)
+            .collect()
+            .await;
+        assert_eq!(passed.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn deleted_and_unlisted_objects_are_forgotten() {
+        let doc = Document::test();
+        let mut other = doc.clone();
+        other.meta_mut().name = Some("other".into());
+        let events = vec![
+            Event::Apply(doc.clone()),
+            Event::Apply(other.clone()),
+            Event::Delete(doc.clone()),
+            // recreated with the same name, and so the same fingerprint
+            Event::Apply(doc.clone()),
+            // a relist without `other`, which was deleted while the watch was down
+            Event::Init,
+            Event::InitApply(doc.clone()),
+            Event::InitDone,
+            Event::Apply(other.clone()),
+        ];
+        let input = stream::iter(events.into_iter().map(Ok));
+        let passed: Vec<_> = filter_changes(input, PredicateConfig::default(), Metrics::default().watch)
+            .map(|res| res.unwrap().name_any())
+            .collect()
+            .await;
+        assert_eq!(passed, ["test", "other", "test", "other"]);
+    }
+
+    #[test]
+    fn events_outside_watched_namespaces_are_dropped() {
+        let doc = Document::test();
+        let mut other = doc.clone();
+        other.meta_mut().namespace = Some("other".into());
+        let keep = in_namespaces(vec!["default".into(), "docs".into()]);
+        assert!(keep(&Event::Apply(doc.clone())));
+        assert!(!keep(&Event::Apply(other.clone())));
+        assert!(!keep(&Event::InitApply(other.clone())));
+        assert!(keep(&Event::<Document>::InitDone));
+        assert!(in_namespaces(vec![])(&Event::Delete(other)));
+    }
+}
@@ -0,0 +1,268 @@
+//! Applying changes of the configuration file without a restart
+use crate::{
+    State,
+    config::{Args, Config},
+    controller::ControllerConfig,
+    events,
+    telemetry::LogFilterHandle,
+};
+use k8s_openapi::api::core::v1::ObjectReference;
+use kube::runtime::events::{Event, EventType, Recorder};
+use serde_json::Value;
+use std::{path::PathBuf, time::Duration};
+use tracing::*;
+use tracing_subscriber::EnvFilter;
+
+/// Settings that take effect when the configuration file changes; all others need a restart
+pub const RELOADABLE: &[&str] = &[
+    "controller.concurrency",
+    "controller.paused",
+    "controller.requeueInterval",
+    "controller.retryInterval",
+    "controller.pausedRequeueInterval",
+    "controller.validation",
+    "notifications",
+    "telemetry.logFilter",
+];
+
+/// How often the configuration file is checked for changes
+///
+/// Mounted ConfigMaps are updated by the kubelet with a delay of up to a minute on top of this.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Outcome of checking the configuration file
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reload {
+    /// No settings changed
+    Unchanged,
+    /// Reloadable settings were applied, and settings needing a restart were ignored
+    Applied {
+        changed: Vec<String>,
+        ignored: Vec<String>,
+    },
+    /// The new configuration is invalid and the active one stays in place
+    Rejected(String),
+}
+
+/// Watches the configuration file for changes and applies them to the running controller
+pub struct Reloader {
+    /// Flags and environment variables, which keep overriding the file
+    args: Args,
+    path: PathBuf,
+    state: State,
+    log_filter: Option<LogFilterHandle>,
+    events: Option<(Recorder, ObjectReference)>,
+    /// File contents last seen
+    last: Option<String>,
+}
+
+impl Reloader {
+    /// Reload the file given by `--config` (if any) into the state
+    pub fn new(args: Args, state: State) -> Option<Self> {
+        let path = args.config.clone()?;
+        let last = std::fs::read_to_string(&path).ok();
+        Some(Self {
+            args,
+            path,
+            state,
+            log_filter: None,
+            events: None,
+            last,
+        })
+    }
+
+    /// Also apply changes to the log filter
+    pub fn with_log_filter(mut self, handle: LogFilterHandle) -> Self {
+        self.log_filter = Some(handle);
+        self
+    }
+
+    /// Publish an Event about every change on an object (e.g. the controller's Pod)
+    pub fn with_events(mut self, recorder: Recorder, reference: ObjectReference) -> Self {
+        self.events = Some((recorder, reference));
+        self
+    }
+
+    /// Check the file for changes in the background
+    pub fn spawn(mut self) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                self.check().await;
+            }
+        });
+    }
+
+    /// Apply the file if it changed since it was last seen
+    pub async fn check(&mut self) -> Reload {
+        // a missing file is likely being replaced; keep the active config until it is back
+        let Ok(text) = std::fs::read_to_string(&self.path) else {
+            return Reload::Unchanged;
+        };
+        if self.last.as_ref() == Some(&text) {
+            return Reload::Unchanged;
+        }
+        self.last = Some(text);
+        let outcome = match Config::load(&self.args) {
+            Ok(config) => self.apply(config),
+            Err(e) => Reload::Rejected(e.to_string()),
+        };
+        self.report(&outcome).await;
+        outcome
+    }
+
+    fn apply(&self, config: Config) -> Reload {
+        let active = self.state.config();
+        let mut changed = vec![];
+        let before = serde_json::to_value(&*active).unwrap();
+        changed_paths(&before, &serde_json::to_value(&config).unwrap(), "", &mut changed);
+        if changed.is_empty() {
+            return Reload::Unchanged;
+        }
+        let (changed, ignored) = changed.into_iter().partition(|path| is_reloadable(path));
+
+        let mut applied = (*active).clone();
+        // settings of the controller section outside `RELOADABLE` keep their active values
+        applied.controller = ControllerConfig {
+            namespaces: active.controller.namespaces.clone(),
+            reporter: active.controller.reporter.clone(),
+            content_root: active.controller.content_root.clone(),
+            ..config.controller
+        };
+        applied.notifications = config.notifications;
+        if applied.telemetry.log_filter != config.telemetry.log_filter {
+            if let Some(handle) = &self.log_filter {
+                let filter = EnvFilter::try_new(&config.telemetry.log_filter).expect("validated filter");
+                if let Err(e) = handle.reload(filter) {
+                    warn!("failed to reload log filter: {e}");
+                }
+            }
+            applied.telemetry.log_filter = config.telemetry.log_filter;
+        }
+        self.state.apply(applied);
+        Reload::Applied { changed, ignored }
+    }
+
+    async fn report(&self, outcome: &Reload) {
+        let (type_, reason, note) = match outcome {
+            Reload::Unchanged => return,
+            Reload::Applied { changed, ignored } => {
+                let mut note = format!("applied changes to {}", list(changed));
+                if !ignored.is_empty() {
+                    warn!("configuration changes to {} need a restart", list(ignored));
+                    note.push_str(&format!("; changes to {} need a restart", list(ignored)));
+                }
+                info!("reloaded configuration: {note}");
+                (EventType::Normal, "ConfigReloaded", note)
+            }
+            Reload::Rejected(error) => {
+                warn!("rejected configuration change: {error}");
+                (EventType::Warning, "ConfigRejected", error.clone())
+            }
+        };
+        let Some((recorder, reference)) = &self.events else {
+            return;
+        };
+        let ev = Event {
+            type_,
+            reason: reason.into(),
+            note: Some(events::truncate_note(note)),
+            action: "Reloading".into(),
+            secondary: None,
+        };
+        if let Err(e) = recorder.publish(&ev, reference).await {
+            warn!("failed to publish {reason} event: {e}");
+        }
+    }
+}
+
+fn list(paths: &[String]) -> String {
+    match paths {
+        [] => "nothing".into(),
+        paths => paths.join(", "),
+    }
+}
+
+fn is_reloadable(path: &str) -> bool {
+    RELOADABLE
+        .iter()
+        .any(|r| path == *r || path.strip_prefix(r).is_some_and(|rest| rest.starts_with('.')))
+}
+
+/// Dotted paths of the leaves that differ between two json values
+fn changed_paths(before: &Value, after: &Value, prefix: &str, changed: &mut Vec<String>) {
+    match (before, after) {
+        (Value::Object(before), Value::Object(after)) => {
+            let keys = before
+                .keys()
+                .chain(after.keys().filter(|k| !before.contains_key(*k)));
+            for key in keys {
+                let path = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                let before = before.get(key).unwrap_or(&Value::Null);
+                changed_paths(before, after.get(key).unwrap_or(&Value::Null), &path, changed);
+            }
+        }
+        (before, after) if before != after => changed.push(prefix.to_string()),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Reload, Reloader};
+    use crate::{
+        State,
+        config::{Args, Config},
+    };
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn reloadable_changes_are_applied_and_invalid_ones_rejected() {
+        let path = std::env::temp_dir().join(format!("doc-ctrl-reload-{}.yaml", std::process::id()));
+        std::fs::write(&path, "controller:\n  requeueInterval: 5m\n").unwrap();
+        let args = Args::parse_with_env(&["controller", "--config", path.to_str().unwrap()], []);
+        let state = State::default().with_config(Config::load(&args).unwrap());
+        let mut reloader = Reloader::new(args, state.clone()).unwrap();
+        assert_eq!(reloader.check().await, Reload::Unchanged);
+
+        let changed = format!(
+            "
+controller:
+  requeueInterval: 1m
+  paused: true
+  contentRoot: {}
+notifications:
+  endpoints: [http://hooks.example/doc]
+server:
+  httpBind: 0.0.0.0:8000
+",
+            std::env::temp_dir().display()
+        );
+        std::fs::write(&path, changed).unwrap();
+        let Reload::Applied { changed, mut ignored } = reloader.check().await else {
+            panic!("expected the change to be applied");
+        };
+        assert_eq!(changed.len(), 3, "{changed:?}");
+        ignored.sort();
+        assert_eq!(ignored, ["controller.contentRoot", "server.httpBind"]);
+        let active = state.config();
+        assert_eq!(active.controller.requeue_interval, Duration::from_secs(60));
+        assert_eq!(active.notifications.endpoints.len(), 1);
+        assert_eq!(active.server.http_bind.port(), 8080, "needs a restart");
+        assert_eq!(active.controller.content_root, None, "needs a restart");
+        assert!(state.diagnostics().await.paused);
+
+        std::fs::write(&path, "controller:\n  requeueInterval: never\n").unwrap();
+        assert!(matches!(reloader.check().await, Reload::Rejected(_)));
+        assert_eq!(
+            state.config().controller.requeue_interval,
+            Duration::from_secs(60)
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,374 @@
+//! In-memory full-text index over the titles and content of Documents
+use crate::{Document, metrics::SearchMetrics};
+use kube::{
+    Resource, ResourceExt,
+    runtime::{reflector::ObjectRef, watcher},
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, RwLock},
+};
+use tokio::time::Instant;
+
+/// Weight of a term in the title relative to one in the content
+const TITLE_BOOST: f64 = 3.0;
+/// BM25 term frequency saturation
+const K1: f64 = 1.2;
+/// BM25 length normalization
+const B: f64 = 0.75;
+
+/// Most hits returned by a query
+pub const MAX_LIMIT: usize = 100;
+
+/// Max number of Documents indexed unless configured otherwise
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+/// A Document matching a query
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Hit {
+    /// Cluster of the Document when several clusters are reconciled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cluster: Option<String>,
+    pub namespace: String,
+    pub name: String,
+    pub title: String,
+    pub score: f64,
+}
+
+struct Entry {
+    title: String,
+    /// Weighted frequency of every term in the title and content
+    terms: HashMap<String, f64>,
+    length: f64,
+    /// Tick of the last upsert, the key of the entry in `Inner::order`
+    indexed: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<ObjectRef<Document>, Entry>,
+    /// Documents containing each term
+    postings: HashMap<String, HashSet<ObjectRef<Document>>>,
+    /// Sum of all entry lengths, for the average
+    length: f64,
+    /// Least recently indexed first
+    order: BTreeMap<u64, ObjectRef<Document>>,
+    tick: u64,
+    /// Documents seen so far by a relist of the watch
+    relisted: Option<HashSet<ObjectRef<Document>>>,
+}
+
+impl Inner {
+    fn remove(&mut self, oref: &ObjectRef<Document>) {
+        let Some(entry) = self.entries.remove(oref) else {
+            return;
+        };
+        self.order.remove(&entry.indexed);
+        self.length -= entry.length;
+        for term in entry.terms.keys() {
+            if let Some(docs) = self.postings.get_mut(term) {
+                docs.remove(oref);
+                if docs.is_empty() {
+                    self.postings.remove(term);
+                }
+            }
+        }
+    }
+}
+
+/// Lowercased alphanumeric words of a text
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+fn object_ref(obj: &impl ResourceExt) -> ObjectRef<Document> {
+    ObjectRef::new(&obj.name_any()).within(&obj.namespace().unwrap_or_default())
+}
+
+/// Inverted index of non-hidden Documents, updated by the reconciler and queried by the web server
+#[derive(Clone)]
+pub struct SearchIndex {
+    inner: Arc<RwLock<Inner>>,
+    capacity: usize,
+    metrics: SearchMetrics,
+}
+
+impl Default for SearchIndex {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY, SearchMetrics::default())
+    }
+}
+
+impl SearchIndex {
+    pub fn new(capacity: usize, metrics: SearchMetrics) -> Self {
+        Self {
+            inner: Arc::default(),
+            capacity,
+            metrics,
+        }
+    }
+
+    /// Index a Document with its resolved content, replacing what was indexed before
+    ///
+    /// Hidden Documents are removed from the index instead. Beyond the capacity, the least recently indexed
+    /// Documents are dropped until their next reconcile.
+    pub fn upsert(&self, doc: &Document, content: &str) {
+        let oref = object_ref(doc);
+        let mut inner = self.inner.write().unwrap();
+        inner.remove(&oref);
+        if !doc.spec.hide {
+            let mut terms = HashMap::<String, f64>::new();
+            for term in tokenize(&doc.spec.title) {
+                *terms.entry(term).or_default() += TITLE_BOOST;
+            }
+            for term in tokenize(content) {
+                *terms.entry(term).or_default() += 1.0;
+            }
+            for term in terms.keys() {
+                inner
+                    .postings
+                    .entry(term.clone())
+                    .or_default()
+                    .insert(oref.clone());
+            }
+            let length = terms.values().sum();
+            inner.length += length;
+            inner.tick += 1;
+            let indexed = inner.tick;
+            inner.order.insert(indexed, oref.clone());
+            inner.entries.insert(oref, Entry {
+                title: doc.spec.title.clone(),
+                terms,
+                length,
+                indexed,
+            });
+            while inner.entries.len() > self.capacity {
+                let Some((_, oldest)) = inner.order.pop_first() else {
+                    break;
+                };
+                inner.remove(&oldest);
+            }
+        }
+        self.record_size(&inner);
+    }
+
+    /// Drop a Document from the index
+    pub fn remove(&self, doc: &impl ResourceExt) {
+        let mut inner = self.inner.write().unwrap();
+        inner.remove(&object_ref(doc));
+        self.record_size(&inner);
+    }
+
+    /// Drop Documents deleted according to a watch event, including those missing from a relist
+    pub fn observe<K: Resource>(&self, event: &watcher::Event<K>) {
+        let mut inner = self.inner.write().unwrap();
+        match event {
+            watcher::Event::Apply(_) => return,
+            watcher::Event::Delete(obj) => inner.remove(&object_ref(obj)),
+            watcher::Event::Init => inner.relisted = Some(HashSet::new()),
+            watcher::Event::InitApply(obj) => {
+                if let Some(relisted) = &mut inner.relisted {
+                    relisted.insert(object_ref(obj));
+                }
+            }
+            watcher::Event::InitDone => {
+                let Some(relisted) = inner.relisted.take() else {
+                    return;
+                };
+                let gone: Vec<_> = inner
+                    .entries
+                    .keys()
+                    .filter(|oref| !relisted.contains(oref))
+                    .cloned()
+                    .collect();
+                for oref in &gone {
+                    inner.remove(oref);
+                }
+            }
+        }
+        self.record_size(&inner);
+    }
+
+    fn record_size(&self, inner: &Inner) {
+        self.metrics.documents.set(inner.entries.len() as i64);
+        self.metrics.terms.set(inner.postings.len() as i64);
+    }
+
+    /// Documents matching any word of the query, best first (ranked with BM25)
+    pub fn search(&self, query: &str, namespace: Option<&str>, limit: usize) -> Vec<Hit> {
+        let start = Instant::now();
+        let inner = self.inner.read().unwrap();
+        let mut terms: Vec<_> = tokenize(query).collect();
+        terms.sort();
+        terms.dedup();
+
+        let count = inner.entries.len() as f64;
+        let average = if count > 0.0 { inner.length / count } else { 1.0 };
+        let mut scores = HashMap::<&ObjectRef<Document>, f64>::new();
+        for term in &terms {
+            let Some(docs) = inner.postings.get(term) else {
+                continue;
+            };
+            let frequency = docs.len() as f64;
+            let idf = (1.0 + (count - frequency + 0.5) / (frequency + 0.5)).ln();
+            for oref in docs {
+                if namespace.is_some_and(|ns| oref.namespace.as_deref() != Some(ns)) {
+                    continue;
+                }
+                let entry = &inner.entries[oref];
+                let tf = entry.terms[term];
+                let norm = K1 * (1.0 - B + B * entry.length / average);
+                *scores.entry(oref).or_default() += idf * tf * (K1 + 1.0) / (tf + norm);
+            }
+        }
+
+        let mut hits: Vec<_> = scores
+            .into_iter()
+            .map(|(oref, score)| Hit {
+                cluster: None,
+                namespace: oref.namespace.clone().unwrap_or_default(),
+                name: oref.name.clone(),
+                title: inner.entries[oref].title.clone(),
+                score,
+            })
+            .collect();
+        hits.sort_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then_with(|| (&a.namespace, &a.name).cmp(&(&b.namespace, &b.name)))
+        });
+        hits.truncate(limit.min(MAX_LIMIT));
+        self.metrics.query_duration.observe(start.elapsed().as_secs_f64());
+        hits
+    }
+
+    /// Number of indexed Documents
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SearchIndex, tokenize};
+    use crate::{Document, DocumentSpec, metrics::SearchMetrics};
+    use kube::runtime::watcher::Event;
+
+    fn doc(namespace: &str, name: &str, title: &str) -> Document {
+        let mut doc = Document::new(name, DocumentSpec {
+            title: title.into(),
+            ..DocumentSpec::default()
+        });
+        doc.metadata.namespace = Some(namespace.into());
+        doc
+    }
+
+    #[test]
+    fn words_are_lowercased() {
+        let words: Vec<_> = tokenize("Kube-RS: a Rust client, v3!").collect();
+        assert_eq!(words, ["kube", "rs", "a", "rust", "client", "v3"]);
+    }
+
+    #[test]
+    fn documents_are_ranked_and_filtered_by_namespace() {
+        let index = SearchIndex::default();
+        index.upsert(
+            &doc("default", "guide", "Controller guide"),
+            "how to write a rust controller",
+        );
+        index.upsert(
+            &doc("default", "notes", "Release notes"),
+            "the controller got faster",
+        );
+        index.upsert(&doc("docs", "rust", "Rust"), "rust rust rust");
+        assert_eq!(index.len(), 3);
+
+        let hits = index.search("controller", None, 10);
+        let names: Vec<_> = hits.iter().map(|h| h.name.as_str()).collect();
+        assert_eq!(names, ["guide", "notes"], "title matches rank first");
+        assert_eq!(hits[0].title, "Controller guide");
+
+        let hits = index.search("Rust", None, 10);
+        assert_eq!(hits[0].name, "rust");
+        assert_eq!(hits.len(), 2);
+        let hits = index.search("rust", Some("default"), 10);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].name, "guide");
+
+        assert_eq!(index.search("controller rust", None, 1).len(), 1);
+        assert!(index.search("missing", None, 10).is_empty());
+        assert!(index.search("", None, 10).is_empty());
+    }
+
+    #[test]
+    fn updates_replace_and_remove_documents() {
+        let index = SearchIndex::default();
+        let mut guide = doc("default", "guide", "Guide");
+        index.upsert(&guide, "old words");
+        index.upsert(&guide, "new words");
+        assert!(index.search("old", None, 10).is_empty());
+        assert_eq!(index.search("new", None, 10).len(), 1);
+
+        guide.spec.hide = true;
+        index.upsert(&guide, "new words");
+        assert!(index.is_empty());
+        assert!(index.search("new", None, 10).is_empty());
+
+        guide.spec.hide = false;
+        index.upsert(&guide, "new words");
+        index.remove(&guide);
+        assert!(index.is_empty());
+        assert!(index.inner.read().unwrap().postings.is_empty());
+    }
+
+    #[test]
+    fn deleted_and_unlisted_documents_are_dropped() {
+        let index = SearchIndex::default();
+        let (guide, notes, rust) = (
+            doc("default", "guide", "Guide"),
+            doc("default", "notes", "Notes"),
+            doc("docs", "rust", "Rust"),
+        );
+        for doc in [&guide, &notes, &rust] {
+            index.upsert(doc, "words");
+        }
+        index.observe(&Event::Delete(guide));
+        assert_eq!(index.len(), 2);
+
+        // notes was deleted while the watch was down
+        index.observe(&Event::<Document>::Init);
+        index.observe(&Event::InitApply(rust.clone()));
+        assert_eq!(index.len(), 2, "entries are kept until the relist is done");
+        index.observe(&Event::<Document>::InitDone);
+        let hits = index.search("words", None, 10);
+        let names: Vec<_> = hits.iter().map(|h| h.name.as_str()).collect();
+        assert_eq!(names, ["rust"]);
+    }
+
+    #[test]
+    fn least_recently_indexed_documents_are_evicted() {
+        let index = SearchIndex::new(2, SearchMetrics::default());
+        let (guide, notes, rust) = (
+            doc("default", "guide", "Guide"),
+            doc("default", "notes", "Notes"),
+            doc("docs", "rust", "Rust"),
+        );
+        index.upsert(&guide, "words");
+        index.upsert(&notes, "words");
+        index.upsert(&guide, "words");
+        index.upsert(&rust, "words");
+        assert_eq!(index.len(), 2);
+        let hits = index.search("words", None, 10);
+        let mut names: Vec<_> = hits.iter().map(|h| h.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["guide", "rust"]);
+        assert!(index.search("notes", None, 10).is_empty());
+    }
+}
@@ -0,0 +1,346 @@
+//! Sharding of Documents across replicas through a consistent hash ring
+use crate::{Error, FIELD_MANAGER, Result, metrics::ShardMetrics};
+use futures::channel::mpsc::UnboundedSender;
+use jiff::{SignedDuration, Timestamp};
+use k8s_openapi::{
+    api::coordination::v1::Lease,
+    apimachinery::pkg::apis::meta::v1::{MicroTime, OwnerReference},
+};
+use kube::{
+    Client, ResourceExt,
+    api::{Api, DeleteParams, ListParams, Patch, PatchParams, Preconditions},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tracing::*;
+
+/// Label grouping the membership Leases of all replicas
+pub const SHARD_GROUP_LABEL: &str = "kube.rs/shard-group";
+const SHARD_GROUP: &str = "doc-controller";
+
+/// Shard membership settings
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct ShardConfig {
+    /// Split Documents between replicas; when disabled this replica reconciles everything
+    pub enabled: bool,
+    /// Unique name of this replica (its pod name)
+    pub identity: String,
+    /// Namespace holding the membership Leases
+    pub namespace: String,
+    /// Replicas that have not renewed their Lease for this long are considered gone
+    #[serde(with = "crate::config::duration")]
+    pub lease_duration: Duration,
+    /// How often the own Lease is renewed and membership is refreshed
+    #[serde(with = "crate::config::duration")]
+    pub renew_interval: Duration,
+    /// Points on the hash ring per replica; more points spread keys more evenly
+    pub vnodes: usize,
+}
+
+impl Default for ShardConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            identity: "doc-controller".into(),
+            namespace: "default".into(),
+            lease_duration: Duration::from_secs(15),
+            renew_interval: Duration::from_secs(5),
+            vnodes: 64,
+        }
+    }
+}
+
+impl ShardConfig {
+    fn lease_name(&self) -> String {
+        format!("{SHARD_GROUP}-shard-{}", self.identity)
+    }
+}
+
+fn hash(key: &str) -> u64 {
+    let digest = Sha256::digest(key.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Consistent hash ring mapping keys to members
+///
+/// Every member owns `vnodes` points on the ring, and a key belongs to the member owning the first point
+/// at or after the hash of the key. Members joining or leaving only move the keys next to their points.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HashRing {
+    points: BTreeMap<u64, String>,
+    members: Vec<String>,
+}
+
+impl HashRing {
+    pub fn new(members: impl IntoIterator<Item = String>, vnodes: usize) -> Self {
+        let mut members: Vec<String> = members.into_iter().collect();
+        members.sort();
+        members.dedup();
+        let points = members
+            .iter()
+            .flat_map(|m| (0..vnodes).map(move |i| (hash(&format!("{m}#{i}")), m.clone())))
+            .collect();
+        Self { points, members }
+    }
+
+    /// The member owning a key (if there are any members)
+    pub fn owner(&self, key: &str) -> Option<&str> {
+        let h = hash(key);
+        self.points
+            .range(h..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, m)| m.as_str())
+    }
+
+    pub fn members(&self) -> &[String] {
+        &self.members
+    }
+}
+
+/// Shard information exposed through `Diagnostics`
+#[derive(Clone, Debug, Serialize)]
+pub struct ShardInfo {
+    pub identity: String,
+    pub members: Vec<String>,
+}
+
+/// Decides which Documents this replica reconciles
+#[derive(Clone)]
+pub struct Sharder {
+    config: Arc<ShardConfig>,
+    ring: Arc<RwLock<HashRing>>,
+    metrics: ShardMetrics,
+}
+
+impl Default for Sharder {
+    fn default() -> Self {
+        Self::new(ShardConfig::default(), ShardMetrics::default())
+    }
+}
+
+impl Sharder {
+    pub fn new(config: ShardConfig, metrics: ShardMetrics) -> Self {
+        if config.enabled {
+            metrics.set_identity(&config.identity);
+        }
+        Self {
+            config: Arc::new(config),
+            ring: Arc::default(),
+            metrics,
+        }
+    }
+
+    /// A sharder with the same configuration, for another cluster
+    pub fn with_metrics(&self, metrics: ShardMetrics) -> Self {
+        Self::new((*self.config).clone(), metrics)
+    }
+
+    /// Whether this replica owns the object
+    ///
+    /// Nothing is owned until membership is known, to avoid replicas reconciling the same objects.
+    pub fn owns(&self, obj: &impl ResourceExt) -> bool {
+        self.owns_key(&obj.namespace().unwrap_or_default(), &obj.name_any())
+    }
+
+    /// Whether this replica owns the object with a namespace and name
+    pub fn owns_key(&self, namespace: &str, name: &str) -> bool {
+        !self.config.enabled || self.owner(namespace, name).as_deref() == Some(self.config.identity.as_str())
+    }
+
+    /// The replica owning the object with a namespace and name, when sharding is enabled and membership is known
+    pub fn owner(&self, namespace: &str, name: &str) -> Option<String> {
+        if !self.config.enabled {
+            return None;
+        }
+        let ring = self.ring.read().unwrap();
+        ring.owner(&format!("{namespace}/{name}")).map(String::from)
+    }
+
+    /// Shard identity and members when sharding is enabled
+    pub fn info(&self) -> Option<ShardInfo> {
+        self.config.enabled.then(|| ShardInfo {
+            identity: self.config.identity.clone(),
+            members: self.ring.read().unwrap().members().to_vec(),
+        })
+    }
+
+    /// Rebuild the ring for a set of members, returning whether membership changed
+    pub fn set_members(&self, members: Vec<String>) -> bool {
+        let ring = HashRing::new(members, self.config.vnodes);
+        let mut current = self.ring.write().unwrap();
+        if *current == ring {
+            return false;
+        }
+        info!("shard members changed: {:?}", ring.members());
+        self.metrics.members.set(ring.members().len() as i64);
+        self.metrics.rebalances.inc();
+        *current = ring;
+        true
+    }
+
+    /// Maintain membership in the background, signalling `rebalance` whenever the ring changes
+    ///
+    /// The own Lease is owned by `owner` (the replica's Pod) when given, so that it is deleted with the Pod.
+    /// Does nothing when sharding is disabled; otherwise the returned task has to be stopped before `release`.
+    pub fn spawn(
+        self,
+        client: Client,
+        owner: Option<OwnerReference>,
+        rebalance: UnboundedSender<()>,
+    ) -> Option<tokio::task::JoinHandle<()>> {
+        if !self.config.enabled {
+            return None;
+        }
+        let leases: Api<Lease> = Api::namespaced(client, &self.config.namespace);
+        Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.config.renew_interval);
+            loop {
+                interval.tick().await;
+                match self.sync(&leases, owner.as_ref()).await {
+                    Ok(true) => {
+                        if rebalance.unbounded_send(()).is_err() {
+                            break; // controller stopped
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(e) => warn!("failed to sync shard membership: {e}"),
+                }
+            }
+        }))
+    }
+
+    /// Delete the own Lease on shutdown, so that the other replicas take over right away
+    pub async fn release(&self, client: Client) {
+        if !self.config.enabled {
+            return;
+        }
+        let leases: Api<Lease> = Api::namespaced(client, &self.config.namespace);
+        match leases
+            .delete(&self.config.lease_name(), &DeleteParams::default())
+            .await
+        {
+            Ok(_) => info!("released shard lease {}", self.config.lease_name()),
+            Err(kube::Error::Api(e)) if e.code == 404 => {}
+            Err(e) => warn!("failed to release shard lease: {e}"),
+        }
+    }
+
+    /// Renew the own Lease, delete Leases of replicas long gone, and rebuild the ring from all live Leases
+    async fn sync(&self, leases: &Api<Lease>, owner: Option<&OwnerReference>) -> Result<bool> {
+        let lease = json!({
+            "apiVersion": "coordination.k8s.io/v1",
+            "kind": "Lease",
+            "metadata": {
+                "name": self.config.lease_name(),
+                "labels": { SHARD_GROUP_LABEL: SHARD_GROUP },
+                "ownerReferences": owner.into_iter().collect::<Vec<_>>(),
+            },
+            "spec": {
+                "holderIdentity": self.config.identity,
+                "leaseDurationSeconds": self.config.lease_duration.as_secs(),
+                "renewTime": MicroTime(Timestamp::now()),
+            }
+        });
+        let pp = PatchParams::apply(FIELD_MANAGER).force();
+        leases
+            .patch(&self.config.lease_name(), &pp, &Patch::Apply(lease))
+            .await
+            .map_err(Error::KubeError)?;
+        let lp = ListParams::default().labels(&format!("{SHARD_GROUP_LABEL}={SHARD_GROUP}"));
+        let list = leases.list(&lp).await.map_err(Error::KubeError)?;
+        let now = Timestamp::now();
+        for lease in stale_leases(&list.items, now) {
+            // a replica renewing meanwhile changed the resourceVersion and keeps its Lease
+            let dp = DeleteParams {
+                preconditions: Some(Preconditions {
+                    resource_version: lease.resource_version(),
+                    uid: lease.uid(),
+                }),
+                ..DeleteParams::default()
+            };
+            match leases.delete(&lease.name_any(), &dp).await {
+                Ok(_) => info!("deleted stale shard lease {}", lease.name_any()),
+                Err(e) => debug!("failed to delete stale shard lease {}: {e}", lease.name_any()),
+            }
+        }
+        Ok(self.set_members(live_members(&list.items, now)))
+    }
+}
+
+/// Whether a Lease has been renewed within its duration, times a factor
+fn renewed_within(lease: &Lease, now: Timestamp, factor: i64) -> bool {
+    let Some(spec) = &lease.spec else {
+        return false;
+    };
+    let duration =
+        SignedDuration::from_secs(i64::from(spec.lease_duration_seconds.unwrap_or_default()) * factor);
+    spec.renew_time.as_ref().is_some_and(|t| t.0 + duration > now)
+}
+
+/// Leases not renewed for twice their duration, left behind by replicas that did not shut down cleanly
+fn stale_leases(leases: &[Lease], now: Timestamp) -> impl Iterator<Item = &Lease> {
+    leases.iter().filter(move |l| !renewed_within(l, now, 2))
+}
+
+/// Holders of Leases that have been renewed within their duration
+fn live_members(leases: &[Lease], now: Timestamp) -> Vec<String> {
+    leases
+        .iter()
+        .filter(|l| renewed_within(l, now, 1))
+        .filter_map(|l| l.spec.as_ref()?.holder_identity.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HashRing, live_members, stale_leases};
+    use jiff::{SignedDuration, Timestamp};
+    use k8s_openapi::{
+        api::coordination::v1::{Lease, LeaseSpec},
+        apimachinery::pkg::apis::meta::v1::MicroTime,
+    };
+
+    #[test]
+    fn ring_moves_few_keys_when_members_change() {
+        let keys: Vec<String> = (0..1000).map(|i| format!("default/doc-{i}")).collect();
+        let two = HashRing::new(["a".to_string(), "b".to_string()], 64);
+        let three = HashRing::new(["a".to_string(), "b".to_string(), "c".to_string()], 64);
+        let owned_by_c = keys.iter().filter(|k| three.owner(k) == Some("c")).count();
+        assert!(owned_by_c > 200 && owned_by_c < 470, "c owns {owned_by_c} keys");
+        // keys only ever move to the new member
+        for k in &keys {
+            let owner = three.owner(k).unwrap();
+            assert!(owner == "c" || Some(owner) == two.owner(k));
+        }
+        assert_eq!(HashRing::default().owner("default/doc-0"), None);
+    }
+
+    #[test]
+    fn expired_leases_are_not_members() {
+        let now = Timestamp::now();
+        let lease = |holder: &str, renewed_ago: i64| Lease {
+            spec: Some(LeaseSpec {
+                holder_identity: Some(holder.into()),
+                lease_duration_seconds: Some(15),
+                renew_time: Some(MicroTime(now - SignedDuration::from_secs(renewed_ago))),
+                ..LeaseSpec::default()
+            }),
+            ..Lease::default()
+        };
+        let leases = vec![lease("alive", 5), lease("expired", 20), lease("gone", 60)];
+        assert_eq!(live_members(&leases, now), vec!["alive".to_string()]);
+        // only leases expired for a while are deleted, in case their replica is just slow
+        let stale: Vec<_> = stale_leases(&leases, now)
+            .filter_map(|l| l.spec.as_ref()?.holder_identity.as_deref())
+            .collect();
+        assert_eq!(stale, ["gone"]);
+    }
+}
@@ -0,0 +1,778 @@
+//! Syncing Documents from markdown files in git repositories
+use crate::{
+    Context, Document, Error, FIELD_MANAGER, Result, State, archive, config,
+    controller::{HasConditions, scoped_api},
+    predicates as doc_predicates,
+};
+use futures::{StreamExt, TryStreamExt};
+use jiff::Timestamp;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, Time};
+use kube::{
+    CustomResource, Resource, ResourceExt,
+    api::{Api, DeleteParams, ListParams, Patch, PatchParams},
+    client::Client,
+    runtime::{
+        WatchStreamExt,
+        controller::{Action, Controller},
+        events::{Event, EventType},
+        predicates, reflector, watcher,
+    },
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Component, Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tracing::*;
+
+/// Label on Documents naming the DocumentSource they were synced from
+pub static SOURCE_LABEL: &str = "kube.rs/source";
+
+/// Settings of DocumentSource syncs
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct SourceConfig {
+    /// How long a git command may run before the sync fails (and is retried)
+    #[serde(with = "crate::config::duration")]
+    pub git_timeout: Duration,
+    /// Writable directory repositories are cloned into; the temporary directory when unset
+    pub checkout_dir: Option<PathBuf>,
+    /// Hosts repositories may be cloned from, with `*.` matching subdomains
+    pub allowed_hosts: Vec<String>,
+}
+
+impl Default for SourceConfig {
+    fn default() -> Self {
+        Self {
+            git_timeout: Duration::from_secs(60),
+            checkout_dir: None,
+            allowed_hosts: vec!["github.com".into(), "gitlab.com".into()],
+        }
+    }
+}
+
+/// A directory of markdown files in a git repository, synced into Documents
+///
+/// Every `.md` file below the directory becomes a Document named after the source and the
+/// file's path, and Documents of files that were removed are deleted.
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[cfg_attr(test, derive(Default))]
+#[kube(kind = "DocumentSource", group = "kube.rs", version = "v1", namespaced)]
+#[kube(status = "DocumentSourceStatus", shortname = "docsrc")]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentSourceSpec {
+    /// Url of the repository; `https://`, `ssh://` or `user@host:path` on a host the controller allows
+    pub url: String,
+    /// Branch to sync
+    #[serde(default = "default_branch")]
+    pub branch: String,
+    /// Directory in the repository holding the markdown files (the root when empty)
+    #[serde(default)]
+    pub path: String,
+    /// How often the branch is checked for new commits, like `1m` (the requeue interval when unset)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<String>,
+}
+
+fn default_branch() -> String {
+    "main".into()
+}
+
+/// The status object of `DocumentSource`
+#[derive(Deserialize, Serialize, Clone, Default, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentSourceStatus {
+    /// The commit of the last successful sync
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /// The generation of the spec last synced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
+    /// Names of the synced Documents
+    // always serialized, so that merge patches clear the list once the last file is removed
+    #[serde(default)]
+    pub documents: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_sync_time: Option<Time>,
+    /// Conditions of the DocumentSource; `Ready`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<Condition>,
+}
+
+impl DocumentSource {
+    /// How often to check for new commits
+    fn interval(&self, requeue_interval: Duration) -> Result<Duration> {
+        match &self.spec.interval {
+            None => Ok(requeue_interval),
+            Some(interval) => config::duration::parse(interval)
+                .ok()
+                .filter(|d| !d.is_zero())
+                .ok_or_else(|| invalid(format!("invalid interval {interval:?}"))),
+        }
+    }
+
+    /// The directory to sync, relative to the repository root
+    fn directory(&self) -> Result<&Path> {
+        let path = Path::new(&self.spec.path);
+        if path
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(invalid(format!(
+                "path {:?} must be relative and stay in the repository",
+                self.spec.path
+            )));
+        }
+        Ok(path)
+    }
+
+    /// Reject urls of local repositories, of other protocols, and of hosts not in the allowlist
+    fn check_url(&self, allowed_hosts: &[String]) -> Result<()> {
+        let url = &self.spec.url;
+        let host = repository_host(url).ok_or_else(|| {
+            invalid(format!(
+                "url {url:?} must be an https://, ssh:// or user@host:path url"
+            ))
+        })?;
+        let allowed = allowed_hosts
+            .iter()
+            .any(|allowed| match allowed.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+                None => host.eq_ignore_ascii_case(allowed),
+            });
+        match allowed {
+            true => Ok(()),
+            false => Err(invalid(format!("host {host} of url {url:?} is not allowed"))),
+        }
+    }
+
+    /// Whether the commit was already synced for the current spec
+    fn is_synced(&self, commit: &str) -> bool {
+        self.status.as_ref().is_some_and(|s| {
+            s.commit.as_deref() == Some(commit) && s.observed_generation == self.metadata.generation
+        })
+    }
+
+    /// The Documents for markdown files keyed by their path relative to the synced directory
+    pub fn documents(&self, files: &BTreeMap<PathBuf, String>) -> Result<Vec<Document>> {
+        let (name, ns) = (self.name_any(), self.namespace().unwrap());
+        let owner = self.controller_owner_ref(&()).unwrap();
+        let mut names = BTreeSet::new();
+        let mut docs = vec![];
+        for (path, markdown) in files {
+            let doc_name = document_name(&name, path);
+            if !is_dns_subdomain(&doc_name) {
+                return Err(invalid(format!(
+                    "{} maps to {doc_name:?}, which is not a valid Document name",
+                    path.display()
+                )));
+            }
+            if !names.insert(doc_name.clone()) {
+                return Err(invalid(format!(
+                    "several files map to the Document name {doc_name}"
+                )));
+            }
+            let mut doc = archive::from_plain_markdown(&doc_name, markdown, None);
+            doc.metadata.namespace = Some(ns.clone());
+            doc.labels_mut().insert(SOURCE_LABEL.into(), name.clone());
+            doc.metadata.owner_references = Some(vec![owner.clone()]);
+            docs.push(doc);
+        }
+        Ok(docs)
+    }
+}
+
+impl HasConditions for DocumentSource {
+    fn conditions(&self) -> &[Condition] {
+        self.status
+            .as_ref()
+            .map(|s| s.conditions.as_slice())
+            .unwrap_or_default()
+    }
+}
+
+/// Document name for a file, like `guides-intro` for `intro.md` in the `guides` source
+fn document_name(source: &str, path: &Path) -> String {
+    let path = path.with_extension("");
+    let path = path.to_string_lossy().to_lowercase();
+    let mut name = format!("{source}-");
+    for c in path.chars() {
+        let c = if c.is_ascii_alphanumeric() { c } else { '-' };
+        if !(c == '-' && name.ends_with('-')) {
+            name.push(c);
+        }
+    }
+    name.trim_end_matches('-').to_string()
+}
+
+/// Whether a name is a DNS-1123 subdomain, as required for object names
+fn is_dns_subdomain(name: &str) -> bool {
+    let alphanumeric = |c: Option<char>| c.is_some_and(|c| c.is_ascii_lowercase() || c.is_ascii_digit());
+    name.len() <= 253
+        && alphanumeric(name.chars().next())
+        && alphanumeric(name.chars().last())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.')
+}
+
+/// The host of an https or ssh repository url (including the scp-like `user@host:path`)
+fn repository_host(url: &str) -> Option<String> {
+    // `<transport>::<address>` runs git remote helpers like `ext::`
+    if url.contains("::") {
+        return None;
+    }
+    let host = match url.split_once("://") {
+        Some(("https" | "ssh", _)) => reqwest::Url::parse(url).ok()?.host_str()?.to_string(),
+        Some(_) => return None,
+        // git only reads `host:path` as ssh without a slash before the colon; anything else is a local path
+        None => {
+            let (authority, path) = url.split_once(':')?;
+            if authority.contains('/') || path.is_empty() {
+                return None;
+            }
+            authority
+                .rsplit_once('@')
+                .map_or(authority, |(_, host)| host)
+                .to_string()
+        }
+    };
+    // leading dashes would be read as options by ssh
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '.' || c == '-';
+    let ok = !host.is_empty() && !host.starts_with('-') && !url.starts_with('-') && host.chars().all(valid);
+    ok.then(|| host.to_lowercase())
+}
+
+fn invalid(msg: String) -> Error {
+    Error::InvalidDocumentSource(msg)
+}
+
+fn sync_failed(msg: String) -> Error {
+    Error::SourceSyncFailed(msg)
+}
+
+async fn git(args: &[&str], dir: Option<&Path>, timeout: Duration) -> Result<String> {
+    let mut cmd = tokio::process::Command::new("git");
+    cmd.args(args).env("GIT_TERMINAL_PROMPT", "0").kill_on_drop(true);
+    // redirects could lead to hosts outside the allowlist
+    cmd.env("GIT_CONFIG_COUNT", "1")
+        .env("GIT_CONFIG_KEY_0", "http.followRedirects")
+        .env("GIT_CONFIG_VALUE_0", "false");
+    if let Some(dir) = dir {
+        cmd.current_dir(dir);
+    }
+    // a timed out git is killed when its future is dropped
+    let output = tokio::time::timeout(timeout, cmd.output())
+        .await
+        .map_err(|_| sync_failed(format!("git {} timed out after {timeout:?}", args[0])))?
+        .map_err(|e| sync_failed(format!("failed to run git: {e}")))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(sync_failed(format!("git {} failed: {}", args[0], stderr.trim())));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The head commit of a branch and the markdown files in a directory at it
+pub async fn checkout(
+    url: &str,
+    branch: &str,
+    dir: &Path,
+    config: &SourceConfig,
+) -> Result<(String, BTreeMap<PathBuf, String>)> {
+    static CHECKOUTS: AtomicUsize = AtomicUsize::new(0);
+    let n = CHECKOUTS.fetch_add(1, Ordering::Relaxed);
+    let parent = config.checkout_dir.clone().unwrap_or_else(std::env::temp_dir);
+    let clone = parent.join(format!("doc-source-{}-{n}", std::process::id()));
+    let clone_str = clone.to_string_lossy().into_owned();
+    let res = async {
+        let args = [
+            "clone",
+            "--quiet",
+            "--depth",
+            "1",
+            "--single-branch",
+            "--branch",
+            branch,
+        ];
+        let timeout = config.git_timeout;
+        git(&[&args[..], &["--", url, &clone_str]].concat(), None, timeout).await?;
+        let commit = git(&["rev-parse", "HEAD"], Some(&clone), timeout).await?;
+        // the directory might be reached through links committed to the repository
+        let not_found = || invalid(format!("{} is not a directory in {url}", dir.display()));
+        let clone = clone.canonicalize().map_err(|_| not_found())?;
+        let root = clone.join(dir).canonicalize().map_err(|_| not_found())?;
+        if !root.starts_with(&clone) || !root.is_dir() {
+            return Err(not_found());
+        }
+        let mut files = BTreeMap::new();
+        let paths = archive::markdown_files(&root).map_err(|e| sync_failed(format!("{e:#}")))?;
+        for path in paths {
+            let markdown = std::fs::read_to_string(&path)
+                .map_err(|e| sync_failed(format!("failed to read {}: {e}", path.display())))?;
+            files.insert(path.strip_prefix(&root).unwrap().to_path_buf(), markdown);
+        }
+        Ok((commit.trim().to_string(), files))
+    }
+    .await;
+    if let Err(e) = std::fs::remove_dir_all(&clone) {
+        debug!("failed to remove checkout {clone_str}: {e}");
+    }
+    res
+}
+
+#[instrument(skip(source, ctx, sources), fields(source = %source.name_any()))]
+async fn reconcile(
+    source: Arc<DocumentSource>,
+    ctx: Arc<Context>,
+    sources: Arc<SourceConfig>,
+) -> Result<Action> {
+    let config = ctx.config.get();
+    let interval = source.interval(config.requeue_interval)?;
+    // picked up by the new owner within the interval after a rebalance
+    if !ctx.sharder.owns(&*source) {
+        return Ok(Action::requeue(interval));
+    }
+    if ctx.paused.load(Ordering::Relaxed) {
+        return Ok(Action::requeue(config.paused_requeue_interval));
+    }
+    source.check_url(&sources.allowed_hosts)?;
+    sync(&source, &ctx, &sources).await?;
+    Ok(Action::requeue(interval))
+}
+
+/// Check out the source and apply its Documents, pruning those of removed files
+async fn sync(source: &DocumentSource, ctx: &Context, config: &SourceConfig) -> Result<()> {
+    let dir = source.directory()?;
+    // applied on every interval, so that Documents changed or deleted since the last sync are restored
+    let (commit, files) = checkout(&source.spec.url, &source.spec.branch, dir, config).await?;
+    let changed = !source.is_synced(&commit);
+    let desired = source.documents(&files)?;
+    let (name, ns) = (source.name_any(), source.namespace().unwrap());
+    match changed {
+        true => info!("Syncing {} Documents from {name} at {commit}", desired.len()),
+        false => debug!("Resyncing {} Documents from {name} at {commit}", desired.len()),
+    }
+
+    let docs: Api<Document> = Api::namespaced(ctx.client.clone(), &ns);
+    let uid = source.uid();
+    let owned = |doc: &Document| {
+        doc.owner_references()
+            .iter()
+            .any(|o| Some(&o.uid) == uid.as_ref())
+    };
+    // the forced applies below would take over Documents created by users or other sources
+    let mut foreign = vec![];
+    for doc in &desired {
+        let existing = docs.get_opt(&doc.name_any()).await.map_err(Error::KubeError)?;
+        if existing.is_some_and(|d| !owned(&d)) {
+            foreign.push(doc.name_any());
+        }
+    }
+    if !foreign.is_empty() {
+        return Err(Error::DocumentNotOwned(format!(
+            "Documents not synced by {name} already exist: {}",
+            foreign.join(", ")
+        )));
+    }
+    let pp = PatchParams::apply(FIELD_MANAGER).force();
+    for doc in &desired {
+        docs.patch(&doc.name_any(), &pp, &Patch::Apply(doc))
+            .await
+            .map_err(Error::KubeError)?;
+    }
+    let keep: BTreeSet<_> = desired.iter().map(|d| d.name_any()).collect();
+    let synced = docs
+        .list(&ListParams::default().labels(&format!("{SOURCE_LABEL}={name}")))
+        .await
+        .map_err(Error::KubeError)?;
+    for doc in synced {
+        if owned(&doc) && !keep.contains(&doc.name_any()) {
+            info!("Pruning Document {} removed from {name}", doc.name_any());
+            docs.delete(&doc.name_any(), &DeleteParams::default())
+                .await
+                .map_err(Error::KubeError)?;
+        }
+    }
+
+    let short = &commit[..commit.len().min(12)];
+    let message = format!("synced {} Documents from {short}", keep.len());
+    let status = json!({
+        "status": DocumentSourceStatus {
+            commit: Some(commit.clone()),
+            observed_generation: source.metadata.generation,
+            documents: keep.into_iter().collect(),
+            last_sync_time: Some(Time(Timestamp::now())),
+            conditions: vec![source.condition("Ready", true, "Synced", message.clone())],
+        }
+    });
+    let sources: Api<DocumentSource> = Api::namespaced(ctx.client.clone(), &ns);
+    sources
+        .patch_status(&name, &PatchParams::default(), &Patch::Merge(&status))
+        .await
+        .map_err(Error::KubeError)?;
+    if !changed {
+        return Ok(());
+    }
+    let ev = Event {
+        type_: EventType::Normal,
+        reason: "Synced".into(),
+        note: Some(message),
+        action: "Syncing".into(),
+        secondary: None,
+    };
+    ctx.events.publish(&ev, &source.object_ref(&())).await;
+    Ok(())
+}
+
+fn error_policy(source: Arc<DocumentSource>, error: &Error, ctx: Arc<Context>) -> Action {
+    warn!("sync of {} failed: {error}", source.name_any());
+    let config = ctx.config.get();
+    ctx.report_failure(&*source, error, "Syncing");
+    match error.category() {
+        crate::ErrorCategory::Permanent => Action::requeue(
+            source
+                .interval(config.requeue_interval)
+                .unwrap_or(config.requeue_interval),
+        ),
+        _ => Action::requeue(config.retry_interval),
+    }
+}
+
+/// Sync DocumentSources in the watched namespaces (when the CRD is installed)
+pub async fn run(client: Client, state: State, namespaces: Vec<String>) {
+    let sources = scoped_api::<DocumentSource>(client.clone(), &namespaces);
+    if let Err(e) = sources.list(&ListParams::default().limit(1)).await {
+        warn!("DocumentSource CRD is not queryable ({e}); git sources are not synced");
+        return;
+    }
+    let config = Arc::new(state.config().sources.clone());
+    let (reader, writer) = reflector::store();
+    let in_namespaces = doc_predicates::in_namespaces(namespaces);
+    // only spec changes; new commits are found by polling
+    let stream = watcher(sources, watcher::Config::default().any_semantic())
+        .default_backoff()
+        .try_filter(move |e| futures::future::ready(in_namespaces(e)))
+        .reflect(writer)
+        .applied_objects()
+        .predicate_filter(predicates::generation, Default::default());
+    Controller::for_stream(stream, reader)
+        .shutdown_on_signal()
+        .run(
+            move |source, ctx| reconcile(source, ctx, config.clone()),
+            error_policy,
+            state.to_context(client).await,
+        )
+        .for_each(|_| futures::future::ready(()))
+        .await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        DocumentSource, DocumentSourceSpec, DocumentSourceStatus, SOURCE_LABEL, SourceConfig, checkout,
+        document_name, is_dns_subdomain, reconcile, sync,
+    };
+    use crate::{
+        Context, Document, Error,
+        fixtures::{Scenario, timeout_after_1s},
+    };
+    use kube::{Resource, ResourceExt};
+    use std::{
+        path::{Path, PathBuf},
+        process::Command,
+        sync::Arc,
+        time::Duration,
+    };
+
+    /// A git repository in a temporary directory with a commit of the given files
+    fn repository(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("doc-source-repo-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let git = |args: &[&str]| {
+            let out = Command::new("git").args(args).current_dir(&dir).output().unwrap();
+            assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        };
+        git(&["init", "--quiet", "--initial-branch", "main"]);
+        for (path, content) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        git(&["add", "."]);
+        let author = ["-c", "user.name=test", "-c", "user.email=test@kube.rs"];
+        git(&[&author[..], &["commit", "--quiet", "-m", "docs"]].concat());
+        dir
+    }
+
+    /// The commit at the head of a repository
+    fn head(repo: &Path) -> String {
+        let out = Command::new("git")
+            .args(["rev-parse", "HEAD"])
+            .current_dir(repo)
+            .output()
+            .unwrap();
+        String::from_utf8(out.stdout).unwrap().trim().to_string()
+    }
+
+    fn source(url: String, path: &str) -> DocumentSource {
+        let mut source = DocumentSource::new("guides", DocumentSourceSpec {
+            url,
+            branch: "main".into(),
+            path: path.into(),
+            interval: None,
+        });
+        source.metadata.namespace = Some("default".into());
+        source.metadata.uid = Some("5ca1ab1e".into());
+        source
+    }
+
+    #[tokio::test]
+    async fn markdown_files_are_read_from_local_repositories() {
+        let config = SourceConfig::default();
+        let files = [
+            ("docs/intro.md", "# Introduction\n\nHello\n"),
+            ("docs/how to/Install.md", "Steps\n"),
+            ("docs/image.png", "not markdown"),
+            ("README.md", "# Outside\n"),
+        ];
+        let repo = repository("read", &files);
+        let url = format!("file://{}", repo.display());
+        let commit = head(&repo);
+
+        let source = source(url.clone(), "docs");
+        let (checked_out, files) = checkout(&url, "main", Path::new("docs"), &config).await.unwrap();
+        assert_eq!(checked_out, commit);
+        assert_eq!(files.len(), 2);
+        let docs = source.documents(&files).unwrap();
+        let names: Vec<_> = docs.iter().map(|d| d.name_any()).collect();
+        assert_eq!(names, ["guides-how-to-install", "guides-intro"]);
+        assert_eq!(docs[1].spec.title, "Introduction");
+        assert_eq!(docs[1].labels()[SOURCE_LABEL], "guides");
+        assert_eq!(docs[1].owner_references()[0].uid, "5ca1ab1e");
+
+        assert!(matches!(
+            checkout(&url, "missing", Path::new("docs"), &config).await,
+            Err(Error::SourceSyncFailed(_))
+        ));
+        assert!(matches!(
+            checkout(&url, "main", Path::new("nowhere"), &config).await,
+            Err(Error::InvalidDocumentSource(_))
+        ));
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[tokio::test]
+    async fn links_in_repositories_are_not_followed() {
+        let config = SourceConfig::default();
+        let repo = repository("links", &[("docs/intro.md", "# Introduction\n")]);
+        let outside = repo.with_extension("outside");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("secret.md"), "token").unwrap();
+        std::os::unix::fs::symlink(outside.join("secret.md"), repo.join("docs/secret.md")).unwrap();
+        std::os::unix::fs::symlink(&repo, repo.join("docs/loop")).unwrap();
+        std::os::unix::fs::symlink(&outside, repo.join("linked")).unwrap();
+        let git = |args: &[&str]| {
+            let author = ["-c", "user.name=test", "-c", "user.email=test@kube.rs"];
+            let out = Command::new("git")
+                .args([&author[..], args].concat())
+                .current_dir(&repo)
+                .output()
+                .unwrap();
+            assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        };
+        git(&["add", "."]);
+        git(&["commit", "--quiet", "-m", "links"]);
+
+        let url = format!("file://{}", repo.display());
+        let (_, files) = checkout(&url, "main", Path::new("docs"), &config).await.unwrap();
+        let paths: Vec<_> = files.keys().collect();
+        assert_eq!(paths, [Path::new("intro.md")]);
+        assert!(matches!(
+            checkout(&url, "main", Path::new("linked"), &config).await,
+            Err(Error::InvalidDocumentSource(_))
+        ));
+        std::fs::remove_dir_all(&repo).unwrap();
+        std::fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[tokio::test]
+    async fn documents_of_others_are_not_taken_over() {
+        let repo = repository("foreign", &[("intro.md", "# Introduction\n")]);
+        let url = format!("file://{}", repo.display());
+        let source = source(url, "");
+        let mut existing = Document::test();
+        existing.metadata.name = Some("guides-intro".into());
+
+        let (testctx, fakeserver) = Context::test();
+        let mocksrv = fakeserver.run(Scenario::SourceConflict(existing));
+        let res = sync(&source, &testctx, &SourceConfig::default()).await;
+        timeout_after_1s(mocksrv).await;
+        assert!(matches!(res, Err(Error::DocumentNotOwned(_))), "{res:?}");
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sync_applies_documents_and_prunes_removed_ones() {
+        let repo = repository("sync", &[("intro.md", "# Introduction\n")]);
+        let url = format!("file://{}", repo.display());
+        let commit = head(&repo);
+        let source = source(url, "");
+        let mut stale = Document::test();
+        stale.metadata.name = Some("guides-removed".into());
+        stale.metadata.owner_references = Some(vec![source.controller_owner_ref(&()).unwrap()]);
+
+        let (testctx, fakeserver) = Context::test();
+        let scenario = Scenario::SourceSync(vec!["guides-intro".into()], stale, commit);
+        let mocksrv = fakeserver.run(scenario);
+        sync(&source, &testctx, &SourceConfig::default()).await.unwrap();
+        timeout_after_1s(mocksrv).await;
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[tokio::test]
+    async fn synced_commits_are_applied_again() {
+        let repo = repository("resync", &[("intro.md", "# Introduction\n")]);
+        let url = format!("file://{}", repo.display());
+        let commit = head(&repo);
+        let mut source = source(url, "");
+        source.metadata.generation = Some(1);
+        source.status = Some(DocumentSourceStatus {
+            commit: Some(commit.clone()),
+            observed_generation: Some(1),
+            documents: vec!["guides-intro".into()],
+            ..DocumentSourceStatus::default()
+        });
+
+        // the Document was deleted since the last sync and is created again
+        let (testctx, fakeserver) = Context::test();
+        let scenario = Scenario::SourceResync(vec!["guides-intro".into()], commit);
+        let mocksrv = fakeserver.run(scenario);
+        sync(&source, &testctx, &SourceConfig::default()).await.unwrap();
+        timeout_after_1s(mocksrv).await;
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[tokio::test]
+    async fn removing_the_last_file_clears_the_synced_documents() {
+        let repo = repository("emptied", &[("notes.txt", "moved elsewhere\n")]);
+        let url = format!("file://{}", repo.display());
+        let commit = head(&repo);
+        let source = source(url, "");
+        let mut stale = Document::test();
+        stale.metadata.name = Some("guides-intro".into());
+        stale.metadata.owner_references = Some(vec![source.controller_owner_ref(&()).unwrap()]);
+
+        let (testctx, fakeserver) = Context::test();
+        // the status patch sends an empty list of documents
+        let mocksrv = fakeserver.run(Scenario::SourceSync(vec![], stale, commit));
+        sync(&source, &testctx, &SourceConfig::default()).await.unwrap();
+        timeout_after_1s(mocksrv).await;
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[tokio::test]
+    async fn repositories_are_cloned_into_the_checkout_dir() {
+        let repo = repository("checkouts", &[("intro.md", "# Introduction\n")]);
+        let url = format!("file://{}", repo.display());
+        let checkouts = repo.with_extension("checkouts");
+        std::fs::create_dir_all(&checkouts).unwrap();
+        let config = SourceConfig {
+            checkout_dir: Some(checkouts.clone()),
+            ..SourceConfig::default()
+        };
+        let (_, files) = checkout(&url, "main", Path::new(""), &config).await.unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(
+            std::fs::read_dir(&checkouts).unwrap().count(),
+            0,
+            "clones are removed"
+        );
+
+        std::fs::remove_dir_all(&repo).unwrap();
+        std::fs::remove_dir_all(&checkouts).unwrap();
+    }
+
+    #[tokio::test]
+    async fn git_commands_time_out() {
+        let repo = repository("timeout", &[("intro.md", "# Introduction\n")]);
+        let url = format!("file://{}", repo.display());
+        let config = SourceConfig {
+            git_timeout: Duration::from_nanos(1),
+            ..SourceConfig::default()
+        };
+        let res = checkout(&url, "main", Path::new(""), &config).await;
+        assert!(
+            matches!(&res, Err(Error::SourceSyncFailed(e)) if e.contains("timed out")),
+            "{res:?}"
+        );
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_repositories_are_not_synced() {
+        let (testctx, _) = Context::test();
+        let source = source("file:///srv/docs.git".into(), "");
+        let res = reconcile(Arc::new(source), testctx, Arc::default()).await;
+        assert!(matches!(res, Err(Error::InvalidDocumentSource(_))), "{res:?}");
+    }
+
+    #[test]
+    fn only_remote_repositories_on_allowed_hosts_are_synced() {
+        let allowed = ["github.com".to_string(), "*.example.com".to_string()];
+        let check = |url: &str| source(url.into(), "").check_url(&allowed);
+        assert!(check("https://github.com/kube-rs/controller-rs.git").is_ok());
+        assert!(check("ssh://git@GitHub.com/kube-rs/controller-rs.git").is_ok());
+        assert!(check("git@github.com:kube-rs/controller-rs.git").is_ok());
+        assert!(check("https://git.example.com/docs.git").is_ok());
+        for url in [
+            "file:///srv/docs.git",
+            "/srv/docs.git",
+            "./docs",
+            "docs/repo:name",
+            "http://github.com/kube-rs/controller-rs.git",
+            "git://github.com/kube-rs/controller-rs.git",
+            "ext::sh -c touch% /tmp/pwned",
+            "-oProxyCommand=touch@github.com:docs.git",
+            "ssh://-oProxyCommand=touch/docs.git",
+            "https://example.com/docs.git",
+            "https://github.com.evil.io/docs.git",
+            "https://10.0.0.1/docs.git",
+        ] {
+            assert!(check(url).is_err(), "{url} is rejected");
+        }
+    }
+
+    #[test]
+    fn sources_are_validated() {
+        let mut source = source("file:///repo".into(), "../outside");
+        assert!(source.directory().is_err());
+        source.spec.path = "./docs".into();
+        assert!(source.directory().is_ok());
+        source.spec.interval = Some("1m".into());
+        assert_eq!(source.interval(Duration::ZERO).unwrap(), Duration::from_secs(60));
+        source.spec.interval = Some("soon".into());
+        assert!(source.interval(Duration::ZERO).is_err());
+        assert_eq!(document_name("guides", Path::new("A/B_c.md")), "guides-a-b-c");
+        assert!(is_dns_subdomain("guides.v1-intro"));
+        assert!(!is_dns_subdomain("guides-"));
+        assert!(!is_dns_subdomain(&"a".repeat(254)));
+
+        let long = PathBuf::from(format!("{}.md", "a".repeat(250)));
+        let files = [(long, "# Long\n".to_string())].into();
+        assert!(matches!(
+            source.documents(&files),
+            Err(Error::InvalidDocumentSource(_))
+        ));
+    }
+}
@@ -0,0 +1,398 @@
+//! Migration of legacy finalizer names and removal of finalizers nothing will remove anymore
+use crate::{Context, DOCUMENT_FINALIZER, Document, Error, Result};
+use kube::{
+    Client, Resource, ResourceExt,
+    api::{Api, ListParams, Patch, PatchParams},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{collections::BTreeMap, sync::Arc};
+use tracing::*;
+
+/// Finalizer sweep settings
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct FinalizerConfig {
+    /// Former names of the Document finalizer, replaced by the current one
+    pub legacy: Vec<String>,
+    /// Sweep the Documents of every cluster when the controller starts
+    pub sweep_on_startup: bool,
+}
+
+impl Default for FinalizerConfig {
+    fn default() -> Self {
+        Self {
+            legacy: vec![],
+            sweep_on_startup: true,
+        }
+    }
+}
+
+/// What a sweep did, or would do on a dry run, with Documents as `namespace/name`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SweepSummary {
+    pub dry_run: bool,
+    pub uninstall: bool,
+    /// Name of the cluster when several clusters are reconciled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cluster: Option<String>,
+    /// Number of Documents checked
+    pub documents: usize,
+    /// Documents with a legacy finalizer replaced by the current one (or dropped, when being deleted)
+    pub migrated: Vec<String>,
+    /// Documents with the finalizer outside the watched namespaces, where no reconcile removes it (only found with
+    /// access to all namespaces)
+    pub unmanaged: Vec<String>,
+    /// Documents with the finalizer removed (in uninstall mode)
+    pub removed: Vec<String>,
+    /// Documents that could not be patched, with the error
+    pub failed: BTreeMap<String, String>,
+}
+
+impl SweepSummary {
+    /// Whether the sweep changes any Document
+    pub fn has_changes(&self) -> bool {
+        !self.migrated.is_empty() || !self.removed.is_empty()
+    }
+
+    fn log(&self) {
+        let cluster = self.cluster.as_deref().unwrap_or_default();
+        info!(
+            cluster,
+            dry_run = self.dry_run,
+            "finalizer sweep of {} Documents: {} migrated, {} removed, {} unmanaged, {} failed",
+            self.documents,
+            self.migrated.len(),
+            self.removed.len(),
+            self.unmanaged.len(),
+            self.failed.len()
+        );
+        if !self.unmanaged.is_empty() {
+            warn!(
+                "Documents outside the watched namespaces keep their finalizer: {}",
+                self.unmanaged.join(", ")
+            );
+        }
+        for (doc, error) in &self.failed {
+            warn!("failed to update the finalizers of {doc}: {error}");
+        }
+    }
+}
+
+/// The finalizers a Document should have after a sweep, or `None` when they are fine
+///
+/// Legacy names are replaced by one current finalizer in place of the first of them. Documents being deleted cannot
+/// get new finalizers, so their legacy names are only dropped. On uninstall, the current and legacy names are all
+/// removed. Finalizers of other controllers are always kept.
+fn plan(doc: &Document, legacy: &[String], uninstall: bool) -> Option<Vec<String>> {
+    let terminating = doc.meta().deletion_timestamp.is_some();
+    let mut finalizers = vec![];
+    let mut kept_ours = false;
+    for finalizer in doc.finalizers() {
+        if finalizer != DOCUMENT_FINALIZER && !legacy.contains(finalizer) {
+            finalizers.push(finalizer.clone());
+        } else if !uninstall && !kept_ours && (!terminating || finalizer == DOCUMENT_FINALIZER) {
+            finalizers.push(DOCUMENT_FINALIZER.to_string());
+            kept_ours = true;
+        }
+    }
+    (finalizers != doc.finalizers()).then_some(finalizers)
+}
+
+/// Lists all Documents of a cluster and fixes up their finalizers
+pub struct Sweeper {
+    client: Client,
+    config: FinalizerConfig,
+    namespaces: Vec<String>,
+    cluster: Option<String>,
+    context: Option<Arc<Context>>,
+}
+
+impl Sweeper {
+    pub fn new(client: Client, config: FinalizerConfig) -> Self {
+        Self {
+            client,
+            config,
+            namespaces: vec![],
+            cluster: None,
+            context: None,
+        }
+    }
+
+    /// Context to clean up with after Documents that are being deleted and lose our finalizer
+    pub fn with_context(mut self, context: Arc<Context>) -> Self {
+        self.context = Some(context);
+        self
+    }
+
+    /// Namespaces the controller watches, and the only ones swept; Documents elsewhere are reported as unmanaged
+    pub fn with_namespaces(mut self, namespaces: Vec<String>) -> Self {
+        self.namespaces = namespaces;
+        self
+    }
+
+    /// Name of the swept cluster for the summary
+    pub fn with_cluster(mut self, cluster: Option<String>) -> Self {
+        self.cluster = cluster;
+        self
+    }
+
+    /// Migrate legacy finalizers, or remove all of ours on uninstall, unless this is a dry run
+    ///
+    /// Patches carry the listed resourceVersion, so Documents changed in the meantime fail with a conflict
+    /// (and are listed under `failed`) rather than losing a finalizer added concurrently. Documents being deleted
+    /// that lose our finalizer get the cleanup of the reconciler first, as no reconcile would run it.
+    pub async fn sweep(&self, uninstall: bool, dry_run: bool) -> Result<SweepSummary> {
+        let unmanaged = self.unmanaged().await?;
+        let docs = self.list().await?;
+        let mut summary = SweepSummary {
+            dry_run,
+            uninstall,
+            cluster: self.cluster.clone(),
+            documents: docs.len(),
+            unmanaged,
+            ..SweepSummary::default()
+        };
+        for doc in &docs {
+            let ns = doc.namespace().unwrap_or_default();
+            let key = format!("{ns}/{}", doc.name_any());
+            let Some(finalizers) = plan(doc, &self.config.legacy, uninstall) else {
+                continue;
+            };
+            if !dry_run {
+                let api: Api<Document> = Api::namespaced(self.client.clone(), &ns);
+                if let Err(e) = self.update(&api, doc.clone(), finalizers, uninstall).await {
+                    summary.failed.insert(key, e.to_string());
+                    continue;
+                }
+            }
+            match uninstall {
+                true => summary.removed.push(key),
+                false => summary.migrated.push(key),
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Documents of the watched namespaces, or of all namespaces when none are configured
+    async fn list(&self) -> Result<Vec<Document>> {
+        let apis: Vec<Api<Document>> = match self.namespaces.as_slice() {
+            [] => vec![Api::all(self.client.clone())],
+            namespaces => namespaces
+                .iter()
+                .map(|ns| Api::namespaced(self.client.clone(), ns))
+                .collect(),
+        };
+        let mut docs = vec![];
+        for api in apis {
+            let list = api.list(&ListParams::default()).await.map_err(Error::KubeError)?;
+            docs.extend(list.items);
+        }
+        Ok(docs)
+    }
+
+    /// Documents outside the watched namespaces with our finalizer, as `namespace/name`
+    ///
+    /// Finding them needs access to all namespaces, which a controller restricted to some namespaces usually lacks;
+    /// then none are reported.
+    async fn unmanaged(&self) -> Result<Vec<String>> {
+        if self.namespaces.is_empty() {
+            return Ok(vec![]);
+        }
+        let api: Api<Document> = Api::all(self.client.clone());
+        let docs = match api.list(&ListParams::default()).await {
+            Ok(list) => list.items,
+            Err(kube::Error::Api(s)) if s.is_forbidden() => {
+                debug!("not allowed to list Documents outside the watched namespaces");
+                return Ok(vec![]);
+            }
+            Err(e) => return Err(Error::KubeError(e)),
+        };
+        let legacy = &self.config.legacy;
+        let unmanaged = docs
+            .iter()
+            .filter(|doc| !self.namespaces.contains(&doc.namespace().unwrap_or_default()))
+            .filter(|doc| {
+                doc.finalizers()
+                    .iter()
+                    .any(|f| f == DOCUMENT_FINALIZER || legacy.contains(f))
+            })
+            .map(|doc| format!("{}/{}", doc.namespace().unwrap_or_default(), doc.name_any()))
+            .collect();
+        Ok(unmanaged)
+    }
+
+    /// Patch the planned finalizers, cleaning up after a Document being deleted that loses ours
+    async fn update(
+        &self,
+        api: &Api<Document>,
+        mut doc: Document,
+        mut finalizers: Vec<String>,
+        uninstall: bool,
+    ) -> Result<()> {
+        let loses_ours = !finalizers.iter().any(|f| f == DOCUMENT_FINALIZER);
+        if let Some(ctx) = &self.context
+            && doc.meta().deletion_timestamp.is_some()
+            && loses_ours
+        {
+            doc.cleanup(ctx.clone()).await?;
+            // the cleanup wrote the status, so patch the latest version
+            let Some(latest) = api.get_opt(&doc.name_any()).await.map_err(Error::KubeError)? else {
+                return Ok(());
+            };
+            let Some(planned) = plan(&latest, &self.config.legacy, uninstall) else {
+                return Ok(());
+            };
+            (doc, finalizers) = (latest, planned);
+        }
+        let patch = Patch::Merge(json!({
+            "metadata": { "resourceVersion": doc.resource_version(), "finalizers": finalizers }
+        }));
+        api.patch(&doc.name_any(), &PatchParams::default(), &patch)
+            .await
+            .map_err(Error::KubeError)?;
+        Ok(())
+    }
+
+    /// Log a dry run of the sweep first, then sweep when it changes anything
+    pub async fn run(&self, uninstall: bool) -> Result<SweepSummary> {
+        let plan = self.sweep(uninstall, true).await?;
+        plan.log();
+        if !plan.has_changes() {
+            return Ok(plan);
+        }
+        let summary = self.sweep(uninstall, false).await?;
+        summary.log();
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FinalizerConfig, Sweeper, plan};
+    use crate::{
+        Context, DOCUMENT_FINALIZER, Document,
+        fixtures::{Scenario, mock_client, timeout_after_1s},
+    };
+    use kube::{Resource, ResourceExt};
+
+    fn with_finalizers(name: &str, finalizers: &[&str]) -> Document {
+        let mut doc = Document::test();
+        doc.meta_mut().name = Some(name.into());
+        doc.meta_mut().resource_version = Some("1".into());
+        *doc.finalizers_mut() = finalizers.iter().map(|f| f.to_string()).collect();
+        doc
+    }
+
+    #[test]
+    fn legacy_finalizers_are_replaced_once() {
+        let legacy = vec!["kube.rs/documents".to_string()];
+        let current = with_finalizers("current", &[DOCUMENT_FINALIZER, "other.io/keep"]);
+        assert_eq!(plan(&current, &legacy, false), None);
+
+        let renamed = with_finalizers("renamed", &["other.io/keep", "kube.rs/documents"]);
+        let expected = vec!["other.io/keep".to_string(), DOCUMENT_FINALIZER.to_string()];
+        assert_eq!(plan(&renamed, &legacy, false), Some(expected));
+
+        let both = with_finalizers("both", &["kube.rs/documents", DOCUMENT_FINALIZER]);
+        assert_eq!(
+            plan(&both, &legacy, false),
+            Some(vec![DOCUMENT_FINALIZER.to_string()])
+        );
+
+        let unrelated = with_finalizers("unrelated", &["other.io/keep"]);
+        assert_eq!(plan(&unrelated, &legacy, false), None);
+    }
+
+    #[test]
+    fn terminating_documents_only_lose_legacy_finalizers() {
+        let legacy = vec!["kube.rs/documents".to_string()];
+        let renamed = with_finalizers("renamed", &["kube.rs/documents", "other.io/keep"]).needs_delete();
+        assert_eq!(
+            plan(&renamed, &legacy, false),
+            Some(vec!["other.io/keep".to_string()])
+        );
+        let both = with_finalizers("both", &["kube.rs/documents", DOCUMENT_FINALIZER]).needs_delete();
+        assert_eq!(
+            plan(&both, &legacy, false),
+            Some(vec![DOCUMENT_FINALIZER.to_string()])
+        );
+        let current = with_finalizers("current", &[DOCUMENT_FINALIZER]).needs_delete();
+        assert_eq!(plan(&current, &legacy, false), None);
+    }
+
+    #[test]
+    fn uninstall_removes_current_and_legacy_finalizers() {
+        let legacy = vec!["kube.rs/documents".to_string()];
+        let doc = with_finalizers("doc", &["kube.rs/documents", "other.io/keep", DOCUMENT_FINALIZER]);
+        assert_eq!(plan(&doc, &legacy, true), Some(vec!["other.io/keep".to_string()]));
+        let unrelated = with_finalizers("unrelated", &["other.io/keep"]);
+        assert_eq!(plan(&unrelated, &legacy, true), None);
+    }
+
+    #[tokio::test]
+    async fn sweep_migrates_legacy_finalizers_and_reports_unmanaged_documents() {
+        let (client, fakeserver) = mock_client();
+        let config = FinalizerConfig {
+            legacy: vec!["kube.rs/documents".into()],
+            ..FinalizerConfig::default()
+        };
+        let sweeper = Sweeper::new(client, config).with_namespaces(vec!["default".into()]);
+        let renamed = with_finalizers("renamed", &["kube.rs/documents"]);
+        let current = with_finalizers("current", &[DOCUMENT_FINALIZER]);
+        let mut elsewhere = with_finalizers("elsewhere", &["kube.rs/documents"]);
+        elsewhere.meta_mut().namespace = Some("other".into());
+        let all = vec![renamed.clone(), current.clone(), elsewhere];
+        let migrated = with_finalizers("renamed", &[DOCUMENT_FINALIZER]);
+        let scenario = Scenario::FinalizerSweep(Some(all), vec![renamed, current], migrated);
+        let mocksrv = fakeserver.run(scenario);
+        let summary = sweeper.sweep(false, false).await.expect("sweep");
+        timeout_after_1s(mocksrv).await;
+
+        assert_eq!(summary.documents, 2);
+        assert_eq!(summary.migrated, ["default/renamed"]);
+        assert_eq!(summary.unmanaged, ["other/elsewhere"]);
+        assert!(summary.removed.is_empty() && summary.failed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sweep_of_some_namespaces_needs_no_access_to_all_namespaces() {
+        let (client, fakeserver) = mock_client();
+        let sweeper =
+            Sweeper::new(client, FinalizerConfig::default()).with_namespaces(vec!["default".into()]);
+        let doc = with_finalizers("doc", &[DOCUMENT_FINALIZER]);
+        let removed = with_finalizers("doc", &[]);
+        let mocksrv = fakeserver.run(Scenario::FinalizerSweep(None, vec![doc], removed));
+        let summary = sweeper.sweep(true, false).await.expect("sweep");
+        timeout_after_1s(mocksrv).await;
+
+        assert_eq!(summary.removed, ["default/doc"]);
+        assert!(summary.unmanaged.is_empty());
+    }
+
+    #[tokio::test]
+    async fn terminating_documents_are_cleaned_up_before_losing_legacy_finalizers() {
+        let (ctx, fakeserver) = Context::test();
+        let config = FinalizerConfig {
+            legacy: vec!["kube.rs/documents".into()],
+            ..FinalizerConfig::default()
+        };
+        let sweeper = Sweeper::new(ctx.client.clone(), config).with_context(ctx.clone());
+        let listed = with_finalizers("renamed", &["kube.rs/documents", "other.io/keep"]).needs_delete();
+        ctx.search.upsert(&listed, "content");
+        // the cleanup wrote the Deleting condition
+        let mut latest = listed.clone();
+        latest.meta_mut().resource_version = Some("2".into());
+        let mut patched = latest.clone();
+        *patched.finalizers_mut() = vec!["other.io/keep".into()];
+        let scenario = Scenario::FinalizerSweepCleanup(Box::new([listed, latest, patched]));
+        let mocksrv = fakeserver.run(scenario);
+        let summary = sweeper.sweep(false, false).await.expect("sweep");
+        timeout_after_1s(mocksrv).await;
+
+        assert_eq!(summary.migrated, ["default/renamed"]);
+        assert!(summary.failed.is_empty());
+        assert!(ctx.search.is_empty());
+    }
+}
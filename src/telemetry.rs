@@ -2,8 +2,28 @@
 use opentelemetry::trace::{TraceId, TracerProvider};
 use opentelemetry_sdk::{Resource, trace as sdktrace};
 use sdktrace::{SdkTracer, SdkTracerProvider};
+use serde::{Deserialize, Serialize};
 use tracing_subscriber::{EnvFilter, Registry, prelude::*, reload};
 
+/// Logging and tracing settings
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct TelemetryConfig {
+    /// Log filter in `RUST_LOG` / `EnvFilter` syntax
+    pub log_filter: String,
+    /// OTLP gRPC collector receiving traces (with the telemetry feature)
+    pub otlp_endpoint: Option<String>,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            log_filter: "info".into(),
+            otlp_endpoint: None,
+        }
+    }
+}
+
 ///  Fetch an opentelemetry::trace::TraceId as hex through the full tracing stack
 pub fn get_trace_id() -> TraceId {
     use opentelemetry::trace::TraceContextExt as _; // opentelemetry::Context -> opentelemetry::trace::Span
@@ -25,9 +45,8 @@ fn resource() -> Resource {
 }
 
 #[cfg(feature = "telemetry")]
-fn init_tracer() -> SdkTracer {
+fn init_tracer(endpoint: &str) -> SdkTracer {
     use opentelemetry_otlp::{SpanExporter, WithExportConfig};
-    let endpoint = std::env::var("OPENTELEMETRY_ENDPOINT_URL").expect("Needs an otel collector");
     let exporter = SpanExporter::builder()
         .with_tonic()
         .with_endpoint(endpoint)
@@ -44,16 +63,17 @@ fn init_tracer() -> SdkTracer {
 
 pub type LogFilterHandle = reload::Handle<EnvFilter, Registry>;
 
-/// Initialize tracing
-pub async fn init() -> LogFilterHandle {
+/// Initialize tracing (given a validated config)
+pub async fn init(config: &TelemetryConfig) -> LogFilterHandle {
     // Setup tracing layers
     #[cfg(feature = "telemetry")]
-    let otel = tracing_opentelemetry::OpenTelemetryLayer::new(init_tracer());
+    let otel = {
+        let endpoint = config.otlp_endpoint.as_deref().expect("Needs an otel collector");
+        tracing_opentelemetry::OpenTelemetryLayer::new(init_tracer(endpoint))
+    };
 
     let logger = tracing_subscriber::fmt::layer().compact();
-    let env_filter = EnvFilter::try_from_default_env()
-        .or(EnvFilter::try_new("info"))
-        .unwrap();
+    let env_filter = EnvFilter::try_new(&config.log_filter).unwrap();
     let (env_filter, reload_handle) = reload::Layer::new(env_filter);
 
     // Decide on layers
@@ -62,6 +82,10 @@ pub async fn init() -> LogFilterHandle {
     reg.with(env_filter).with(logger).with(otel).init();
     #[cfg(not(feature = "telemetry"))]
     reg.with(env_filter).with(logger).init();
+    #[cfg(not(feature = "telemetry"))]
+    if config.otlp_endpoint.is_some() {
+        tracing::warn!("ignoring otlpEndpoint without the telemetry feature");
+    }
 
     reload_handle
 }
@@ -75,7 +99,11 @@ mod test {
     #[ignore = "requires a trace exporter"]
     async fn get_trace_id_returns_valid_traces() {
         use super::*;
-        super::init().await;
+        let config = TelemetryConfig {
+            otlp_endpoint: std::env::var("OPENTELEMETRY_ENDPOINT_URL").ok(),
+            ..TelemetryConfig::default()
+        };
+        super::init(&config).await;
         #[tracing::instrument(name = "test_span")] // need to be in an instrumented fn
         fn test_trace_id() -> TraceId {
             get_trace_id()
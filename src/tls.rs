@@ -0,0 +1,153 @@
+//! TLS for the web server with certificates reloaded from disk
+use anyhow::Context as _;
+use rustls::{
+    ServerConfig,
+    crypto::{CryptoProvider, ring},
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tracing::*;
+
+/// Certificate and key files, typically mounted from a `kubernetes.io/tls` Secret
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TlsConfig {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    /// How often the files are checked for changes
+    #[serde(default = "default_reload_interval", with = "crate::config::duration")]
+    pub reload_interval: Duration,
+}
+
+fn default_reload_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// Serves the latest certificate found on disk
+#[derive(Debug)]
+pub struct CertResolver {
+    config: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    /// The served key and the modification times of the files it was loaded from
+    current: RwLock<(Arc<CertifiedKey>, [Option<SystemTime>; 2])>,
+}
+
+impl CertResolver {
+    /// Load the certificate, failing if the files are missing or invalid
+    pub fn new(config: TlsConfig) -> anyhow::Result<Arc<Self>> {
+        let provider = Arc::new(ring::default_provider());
+        let modified = modified(&config);
+        let key = load(&config, &provider)?;
+        Ok(Arc::new(Self {
+            config,
+            provider,
+            current: RwLock::new((Arc::new(key), modified)),
+        }))
+    }
+
+    /// Reload the certificate if the files changed, returning whether it was replaced
+    ///
+    /// Invalid files (e.g. halfway through a Secret update) keep the previous certificate in use.
+    pub fn reload(&self) -> anyhow::Result<bool> {
+        let modified = modified(&self.config);
+        if self.current.read().unwrap().1 == modified {
+            return Ok(false);
+        }
+        let key = load(&self.config, &self.provider)?;
+        *self.current.write().unwrap() = (Arc::new(key), modified);
+        Ok(true)
+    }
+
+    /// Check for new certificates in the background
+    pub fn spawn_reloader(self: &Arc<Self>) {
+        let resolver = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(resolver.config.reload_interval);
+            loop {
+                interval.tick().await;
+                match resolver.reload() {
+                    Ok(true) => info!("reloaded TLS certificate {}", resolver.config.cert_file.display()),
+                    Ok(false) => {}
+                    Err(e) => warn!("failed to reload TLS certificate: {e:#}"),
+                }
+            }
+        });
+    }
+
+    /// A rustls server configuration using this resolver
+    pub fn server_config(self: &Arc<Self>) -> anyhow::Result<ServerConfig> {
+        let config = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        Ok(config)
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().0.clone())
+    }
+}
+
+fn modified(config: &TlsConfig) -> [Option<SystemTime>; 2] {
+    let mtime = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    [mtime(&config.cert_file), mtime(&config.key_file)]
+}
+
+fn load(config: &TlsConfig, provider: &CryptoProvider) -> anyhow::Result<CertifiedKey> {
+    let cert_file = config.cert_file.display();
+    let certs = CertificateDer::pem_file_iter(&config.cert_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("invalid certificate {cert_file}"))?;
+    anyhow::ensure!(!certs.is_empty(), "no certificates in {cert_file}");
+    let key = PrivateKeyDer::from_pem_file(&config.key_file)
+        .with_context(|| format!("invalid private key {}", config.key_file.display()))?;
+    let key = provider.key_provider.load_private_key(key)?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CertResolver, TlsConfig};
+    use std::{path::Path, time::Duration};
+
+    fn write_cert(dir: &Path, name: &str) {
+        let cert = rcgen::generate_simple_self_signed(vec![name.into()]).unwrap();
+        std::fs::write(dir.join("tls.crt"), cert.cert.pem()).unwrap();
+        std::fs::write(dir.join("tls.key"), cert.key_pair.serialize_pem()).unwrap();
+    }
+
+    #[test]
+    fn certificates_are_reloaded_when_files_change() {
+        let dir = std::env::temp_dir().join(format!("doc-ctrl-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        write_cert(&dir, "first.example");
+        let config = TlsConfig {
+            cert_file: dir.join("tls.crt"),
+            key_file: dir.join("tls.key"),
+            reload_interval: Duration::from_secs(30),
+        };
+        let resolver = CertResolver::new(config).unwrap();
+        assert!(!resolver.reload().unwrap(), "unchanged files are not reloaded");
+        let first = resolver.current.read().unwrap().0.clone();
+
+        // make the new files visibly newer than the previous ones
+        std::thread::sleep(Duration::from_millis(20));
+        write_cert(&dir, "second.example");
+        assert!(resolver.reload().unwrap());
+        assert_ne!(resolver.current.read().unwrap().0.cert, first.cert);
+
+        std::fs::write(dir.join("tls.crt"), "garbage").unwrap();
+        assert!(resolver.reload().is_err());
+        assert!(resolver.server_config().is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,424 @@
+//! On demand reconciles requested through the admin endpoints
+use crate::{Document, Error, shard::Sharder};
+use futures::{
+    Stream,
+    channel::mpsc::{UnboundedReceiver, UnboundedSender, unbounded},
+};
+use jiff::Timestamp;
+use kube::{
+    Resource,
+    runtime::{
+        controller::{self, Action},
+        reflector::ObjectRef,
+    },
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+use tokio::sync::watch;
+use tracing::*;
+
+/// Finished requests kept around for polling
+const MAX_FINISHED: usize = 1000;
+/// Requests without a finished reconcile for this long are failed
+const PENDING_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Result of a requested reconcile
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum Outcome {
+    Pending,
+    Succeeded,
+    /// Reconciled while reconciliation of the Document (or all reconciliation) was paused, so nothing was done
+    Paused,
+    Failed {
+        error: String,
+    },
+    /// Skipped by this replica because another replica reconciles the Document
+    NotOwned {
+        owner: Option<String>,
+    },
+}
+
+/// A reconcile requested for a Document
+#[derive(Clone, Debug, Serialize)]
+pub struct ReconcileRequest {
+    pub id: u64,
+    pub namespace: String,
+    pub name: String,
+    pub requested_at: Timestamp,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+struct Tracked {
+    request: ReconcileRequest,
+    outcome: watch::Sender<Outcome>,
+    /// A reconcile started after the request; only such a reconcile completes it
+    started: bool,
+    /// That reconcile found reconciliation paused
+    paused: bool,
+}
+
+#[derive(Default)]
+struct Requests {
+    next_id: u64,
+    by_id: BTreeMap<u64, Tracked>,
+    /// Pending request ids per Document
+    pending: HashMap<ObjectRef<Document>, Vec<u64>>,
+}
+
+type Lister = Box<dyn Fn(&str) -> Vec<ObjectRef<Document>> + Send + Sync>;
+
+/// Feeds reconcile requests into the running `Controller` and tracks their outcomes
+///
+/// A request completes with the first reconcile of its Document that starts after the request. Reconciles
+/// already running when the request is made are followed by another one, as the `Controller` queues it.
+#[derive(Clone)]
+pub struct Triggers {
+    sender: UnboundedSender<ObjectRef<Document>>,
+    receiver: Arc<Mutex<Option<UnboundedReceiver<ObjectRef<Document>>>>>,
+    requests: Arc<Mutex<Requests>>,
+    lister: Arc<OnceLock<Lister>>,
+}
+
+impl Default for Triggers {
+    fn default() -> Self {
+        let (sender, receiver) = unbounded();
+        Self {
+            sender,
+            receiver: Arc::new(Mutex::new(Some(receiver))),
+            requests: Arc::default(),
+            lister: Arc::default(),
+        }
+    }
+}
+
+impl Triggers {
+    /// The stream of requested Documents for `Controller::reconcile_on` (can only be taken once)
+    pub fn stream(&self) -> impl Stream<Item = ObjectRef<Document>> + Send + 'static {
+        self.receiver
+            .lock()
+            .unwrap()
+            .take()
+            .expect("trigger stream already taken")
+    }
+
+    /// Provide the Documents known to the controller in a namespace for bulk requests
+    pub fn set_lister(&self, lister: impl Fn(&str) -> Vec<ObjectRef<Document>> + Send + Sync + 'static) {
+        let _ = self.lister.set(Box::new(lister));
+    }
+
+    /// Request a reconcile of a Document
+    pub fn request(&self, namespace: &str, name: &str) -> ReconcileRequest {
+        let oref = ObjectRef::new(name).within(namespace);
+        let mut requests = self.requests.lock().unwrap();
+        requests.next_id += 1;
+        let request = ReconcileRequest {
+            id: requests.next_id,
+            namespace: namespace.into(),
+            name: name.into(),
+            requested_at: Timestamp::now(),
+            outcome: Outcome::Pending,
+        };
+        let (outcome, _) = watch::channel(Outcome::Pending);
+        expire(&mut requests);
+        requests.by_id.insert(request.id, Tracked {
+            request: request.clone(),
+            outcome,
+            started: false,
+            paused: false,
+        });
+        requests.pending.entry(oref.clone()).or_default().push(request.id);
+        // the receiver lives as long as the controller
+        let _ = self.sender.unbounded_send(oref);
+        request
+    }
+
+    /// Request reconciles of all Documents in a namespace known to the controller
+    pub fn request_namespace(&self, namespace: &str) -> Vec<ReconcileRequest> {
+        let docs = self.lister.get().map(|list| list(namespace)).unwrap_or_default();
+        docs.iter().map(|o| self.request(namespace, &o.name)).collect()
+    }
+
+    /// Look up a request
+    pub fn get(&self, id: u64) -> Option<ReconcileRequest> {
+        let mut requests = self.requests.lock().unwrap();
+        expire(&mut requests);
+        requests.by_id.get(&id).map(|t| t.request.clone())
+    }
+
+    /// Note that a reconcile of a Document starts, so that it completes the requests made before
+    pub fn start(&self, oref: &ObjectRef<Document>) {
+        self.mark(oref, |t| (t.started, t.paused) = (true, false));
+    }
+
+    /// Note that the running reconcile of a Document does nothing because reconciliation is paused
+    pub fn pause(&self, oref: &ObjectRef<Document>) {
+        self.mark(oref, |t| t.paused = t.started);
+    }
+
+    fn mark(&self, oref: &ObjectRef<Document>, update: impl Fn(&mut Tracked)) {
+        let oref = ObjectRef::new(&oref.name).within(oref.namespace.as_deref().unwrap_or_default());
+        let mut requests = self.requests.lock().unwrap();
+        let Some(ids) = requests.pending.get(&oref).cloned() else {
+            return;
+        };
+        for id in ids {
+            if let Some(tracked) = requests.by_id.get_mut(&id) {
+                update(tracked);
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for a request to finish, returning its latest state
+    pub async fn wait(&self, id: u64, timeout: Duration) -> Option<ReconcileRequest> {
+        let mut outcome = {
+            let requests = self.requests.lock().unwrap();
+            requests.by_id.get(&id)?.outcome.subscribe()
+        };
+        let _ = tokio::time::timeout(timeout, outcome.wait_for(|o| *o != Outcome::Pending)).await;
+        self.get(id)
+    }
+
+    /// Record the result of a reconcile reported by the `Controller`
+    ///
+    /// Generic over the watched type, so that results of metadata-only controllers are recorded too.
+    /// Reconciles of Documents owned by another replica are skipped. Errors without a Document (e.g. watch
+    /// failures) are only logged; the requested reconciles may still run, or the requests time out.
+    pub fn complete<K, QErr>(
+        &self,
+        result: &Result<(ObjectRef<K>, Action), controller::Error<Error, QErr>>,
+        sharder: &Sharder,
+    ) where
+        K: Resource,
+        QErr: std::error::Error + 'static,
+    {
+        let (name, namespace, outcome) = match result {
+            Ok((oref, _)) => {
+                let ns = oref.namespace.as_deref().unwrap_or_default();
+                let outcome = match sharder.owns_key(ns, &oref.name) {
+                    true => Outcome::Succeeded,
+                    false => Outcome::NotOwned {
+                        owner: sharder.owner(ns, &oref.name),
+                    },
+                };
+                (&oref.name, &oref.namespace, outcome)
+            }
+            Err(controller::Error::ReconcilerFailed(e, obj)) => {
+                (&obj.name, &obj.namespace, Outcome::Failed {
+                    error: e.to_string(),
+                })
+            }
+            // no reconcile starts for Documents missing from the store
+            Err(controller::Error::ObjectNotFound(obj)) => {
+                let oref = ObjectRef::new(&obj.name).within(obj.namespace.as_deref().unwrap_or_default());
+                let error = "document not found".into();
+                self.finish(&oref, Outcome::Failed { error }, false);
+                return;
+            }
+            Err(e) => {
+                warn!("controller error, pending reconcile requests are kept: {e}");
+                return;
+            }
+        };
+        let oref = ObjectRef::new(name).within(namespace.as_deref().unwrap_or_default());
+        self.finish(&oref, outcome, true);
+    }
+
+    /// Finish the pending requests of a Document, or only those a finished reconcile started for
+    fn finish(&self, oref: &ObjectRef<Document>, outcome: Outcome, started_only: bool) {
+        let mut requests = self.requests.lock().unwrap();
+        let Some(ids) = requests.pending.remove(oref) else {
+            return;
+        };
+        let mut waiting = vec![];
+        for id in ids {
+            let Some(tracked) = requests.by_id.get_mut(&id) else {
+                continue;
+            };
+            if started_only && !tracked.started {
+                waiting.push(id);
+                continue;
+            }
+            let outcome = match outcome {
+                Outcome::Succeeded if tracked.paused => Outcome::Paused,
+                _ => outcome.clone(),
+            };
+            tracked.request.outcome = outcome.clone();
+            tracked.outcome.send_replace(outcome);
+        }
+        if !waiting.is_empty() {
+            requests.pending.insert(oref.clone(), waiting);
+        }
+        // forget the oldest finished requests
+        let pending: usize = requests.pending.values().map(Vec::len).sum();
+        while requests.by_id.len() > MAX_FINISHED + pending {
+            let Some((&oldest, _)) = requests
+                .by_id
+                .iter()
+                .find(|(_, t)| t.request.outcome != Outcome::Pending)
+            else {
+                break;
+            };
+            requests.by_id.remove(&oldest);
+        }
+    }
+}
+
+/// Fail requests that no reconcile finished for within `PENDING_TIMEOUT`
+fn expire(requests: &mut Requests) {
+    let deadline = Timestamp::now() - PENDING_TIMEOUT;
+    let outcome = Outcome::Failed {
+        error: format!("no reconcile finished within {}s", PENDING_TIMEOUT.as_secs()),
+    };
+    let Requests { by_id, pending, .. } = requests;
+    pending.retain(|_, ids| {
+        ids.retain(|id| {
+            let Some(tracked) = by_id.get_mut(id) else {
+                return false;
+            };
+            if tracked.request.requested_at > deadline {
+                return true;
+            }
+            tracked.request.outcome = outcome.clone();
+            tracked.outcome.send_replace(outcome.clone());
+            false
+        });
+        !ids.is_empty()
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Outcome, Triggers};
+    use crate::{
+        Document, Error,
+        metrics::ShardMetrics,
+        shard::{ShardConfig, Sharder},
+    };
+    use futures::StreamExt;
+    use kube::runtime::{
+        controller::{self, Action},
+        reflector::ObjectRef,
+        watcher,
+    };
+    use std::time::Duration;
+
+    type Completion = Result<(ObjectRef<Document>, Action), controller::Error<Error, watcher::Error>>;
+
+    fn succeed(triggers: &Triggers, oref: ObjectRef<Document>) {
+        triggers.start(&oref);
+        let result: Completion = Ok((oref, Action::await_change()));
+        triggers.complete(&result, &Sharder::default());
+    }
+
+    #[tokio::test]
+    async fn requests_are_streamed_and_completed_by_controller_results() {
+        let triggers = Triggers::default();
+        let mut stream = triggers.stream();
+        let request = triggers.request("default", "test");
+        let oref = stream.next().await.unwrap();
+        assert_eq!(oref, ObjectRef::new("test").within("default"));
+
+        let pending = triggers
+            .wait(request.id, Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert_eq!(pending.outcome, Outcome::Pending);
+        triggers.start(&oref);
+        let failed: Completion = Err(controller::Error::ReconcilerFailed(
+            Error::IllegalDocument,
+            oref.clone().erase(),
+        ));
+        triggers.complete(&failed, &Sharder::default());
+        let done = triggers.wait(request.id, Duration::from_secs(1)).await.unwrap();
+        assert!(matches!(done.outcome, Outcome::Failed { .. }));
+
+        // later results do not change finished requests
+        succeed(&triggers, oref);
+        assert_eq!(triggers.get(request.id).unwrap().outcome, done.outcome);
+    }
+
+    #[tokio::test]
+    async fn namespace_requests_cover_listed_documents() {
+        let triggers = Triggers::default();
+        triggers.set_lister(|ns| vec![ObjectRef::new("a").within(ns), ObjectRef::new("b").within(ns)]);
+        let requests = triggers.request_namespace("docs");
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].name, "b");
+        succeed(&triggers, ObjectRef::new("a").within("docs"));
+        let a = triggers
+            .wait(requests[0].id, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(a.outcome, Outcome::Succeeded);
+        assert_eq!(triggers.get(requests[1].id).unwrap().outcome, Outcome::Pending);
+    }
+
+    #[tokio::test]
+    async fn reconciles_skipped_for_other_replicas_are_not_successes() {
+        let triggers = Triggers::default();
+        let config = ShardConfig {
+            enabled: true,
+            identity: "replica-1".into(),
+            ..ShardConfig::default()
+        };
+        let sharder = Sharder::new(config, ShardMetrics::default());
+        sharder.set_members(vec!["replica-2".into()]);
+        let request = triggers.request("default", "test");
+        let oref = ObjectRef::new("test").within("default");
+        triggers.start(&oref);
+        let skipped: Completion = Ok((oref, Action::await_change()));
+        triggers.complete(&skipped, &sharder);
+        let outcome = triggers.get(request.id).unwrap().outcome;
+        assert_eq!(outcome, Outcome::NotOwned {
+            owner: Some("replica-2".into())
+        });
+    }
+
+    #[tokio::test]
+    async fn controller_errors_keep_requests_pending() {
+        let triggers = Triggers::default();
+        let request = triggers.request("default", "test");
+        let error: Completion = Err(controller::Error::QueueError(watcher::Error::NoResourceVersion));
+        triggers.complete(&error, &Sharder::default());
+        assert_eq!(triggers.get(request.id).unwrap().outcome, Outcome::Pending);
+
+        let missing: Completion = Err(controller::Error::ObjectNotFound(
+            ObjectRef::<Document>::new("test").within("default").erase(),
+        ));
+        triggers.complete(&missing, &Sharder::default());
+        let outcome = triggers.get(request.id).unwrap().outcome;
+        assert!(matches!(outcome, Outcome::Failed { .. }), "{outcome:?}");
+    }
+
+    #[tokio::test]
+    async fn only_reconciles_started_after_the_request_complete_it() {
+        let triggers = Triggers::default();
+        let oref = ObjectRef::new("test").within("default");
+        triggers.start(&oref);
+        let request = triggers.request("default", "test");
+        let running: Completion = Ok((oref.clone(), Action::await_change()));
+        triggers.complete(&running, &Sharder::default());
+        assert_eq!(triggers.get(request.id).unwrap().outcome, Outcome::Pending);
+
+        succeed(&triggers, oref);
+        assert_eq!(triggers.get(request.id).unwrap().outcome, Outcome::Succeeded);
+    }
+
+    #[tokio::test]
+    async fn paused_reconciles_are_reported_as_paused() {
+        let triggers = Triggers::default();
+        let oref = ObjectRef::new("test").within("default");
+        let request = triggers.request("default", "test");
+        triggers.start(&oref);
+        triggers.pause(&oref);
+        let result: Completion = Ok((oref, Action::await_change()));
+        triggers.complete(&result, &Sharder::default());
+        assert_eq!(triggers.get(request.id).unwrap().outcome, Outcome::Paused);
+    }
+}